@@ -39,6 +39,9 @@ pub struct Word {
     pub rating: i8,
     pub sentence_index: usize,
     pub morph: HashMap<String, String>,
+    /// Universal part of speech tag from stanza, such as VERB or PROPN
+    #[serde(default)]
+    pub pos: Option<String>,
     pub clickable: bool,
     pub other_forms: Vec<String>,
     pub length: usize,
@@ -78,6 +81,15 @@ pub struct TimeSpentStats {
 pub enum DictFileType {
     TextSplitAt(String),
     StarDict,
+    MDict,
+}
+
+const fn default_dictionary_timeout() -> u64 {
+    10
+}
+
+const fn default_dictionary_retries() -> u32 {
+    2
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
@@ -86,26 +98,356 @@ pub struct Dictionary {
     pub run_when_not: Option<String>,
     pub specific_settings: DictionarySpecificSettings,
     pub fetch_by_default: bool,
+    /// Seconds to wait for a network or command dictionary before giving up
+    #[serde(default = "default_dictionary_timeout")]
+    pub timeout: u64,
+    /// How many times a failed request to a network dictionary is retried
+    #[serde(default = "default_dictionary_retries")]
+    pub retries: u32,
+    /// Every rule has to be met for the dictionary to be used for a word
+    #[serde(default)]
+    pub rules: Vec<DictionaryRule>,
+}
+
+/// A condition for using a dictionary for a word
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+#[serde(tag = "t", content = "c")]
+pub enum DictionaryRule {
+    /// The word has one of these universal part of speech tags, such as VERB or PROPN
+    PartOfSpeech(Vec<String>),
+    /// The word has the grammatical feature with this value, such as Tense and Past
+    Feature(String, String),
+    /// The lemma starts with a capital letter
+    Capitalized,
+    /// None of these dictionaries found a definition
+    AllFailed(Vec<String>),
+    Not(Box<DictionaryRule>),
+}
+
+impl DictionaryRule {
+    /// The dictionaries whose definitions the rule depends on
+    pub fn dictionaries(&self) -> Vec<&str> {
+        match self {
+            Self::AllFailed(dictionaries) => dictionaries.iter().map(String::as_str).collect(),
+            Self::Not(rule) => rule.dictionaries(),
+            _ => Vec::new(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
 #[serde(tag = "t", content = "c")]
 pub enum DictionarySpecificSettings {
     File(String, DictFileType),
-    Url(String, bool, String),
-    Command(String),
+    Url(UrlSettings),
+    Command(CommandSettings),
     EkalbaBendrines,
     EkalbaDabartines,
     Wiktionary(String, String),
     WordReference(String, String),
+    Kaikki(String),
+}
+
+/// A website or API to get definitions from. `{word}` is replaced in the url and the body.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+#[serde(from = "UrlSettingsFormat")]
+pub struct UrlSettings {
+    pub url: String,
+    /// Whether the page is shown in the definition instead of being fetched
+    pub embed: bool,
+    pub method: HttpMethod,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    pub extraction: ResponseExtraction,
+    /// Html for each part of the response that was found. `{match}` is replaced with the part,
+    /// and `{FIELD}` with that field of a JSON object.
+    pub template: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum HttpMethod {
+    #[default]
+    Get,
+    Post,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+#[serde(tag = "t", content = "c")]
+pub enum ResponseExtraction {
+    #[default]
+    Whole,
+    Css(String),
+    JsonPath(String),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UrlSettingsFormat {
+    /// Older versions only stored the url, whether to embed it and a css selector
+    Legacy(String, bool, String),
+    Settings {
+        url: String,
+        #[serde(default)]
+        embed: bool,
+        #[serde(default)]
+        method: HttpMethod,
+        #[serde(default)]
+        headers: Vec<(String, String)>,
+        #[serde(default)]
+        body: String,
+        #[serde(default)]
+        extraction: ResponseExtraction,
+        #[serde(default)]
+        template: String,
+    },
+}
+
+impl From<UrlSettingsFormat> for UrlSettings {
+    fn from(format: UrlSettingsFormat) -> Self {
+        match format {
+            UrlSettingsFormat::Legacy(url, embed, selector) => Self {
+                url,
+                embed,
+                extraction: if selector.is_empty() {
+                    ResponseExtraction::Whole
+                } else {
+                    ResponseExtraction::Css(selector)
+                },
+                ..Default::default()
+            },
+            UrlSettingsFormat::Settings {
+                url,
+                embed,
+                method,
+                headers,
+                body,
+                extraction,
+                template,
+            } => Self {
+                url,
+                embed,
+                method,
+                headers,
+                body,
+                extraction,
+                template,
+            },
+        }
+    }
+}
+
+/// A program run to get definitions. It is run without a shell, with `{word}` replaced in each
+/// argument. The word is also passed in the `KALBA_WORD` environment variable.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+#[serde(from = "CommandSettingsFormat")]
+pub struct CommandSettings {
+    pub args: Vec<String>,
+    /// Whether the word is written to the program's standard input
+    pub stdin: bool,
+    pub output: CommandOutput,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum CommandOutput {
+    #[default]
+    Html,
+    /// A structured definition in JSON
+    Json,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CommandSettingsFormat {
+    /// Older versions stored the command as a string for the shell
+    Shell(String),
+    Args {
+        args: Vec<String>,
+        #[serde(default)]
+        stdin: bool,
+        #[serde(default)]
+        output: CommandOutput,
+    },
+}
+
+impl From<CommandSettingsFormat> for CommandSettings {
+    fn from(format: CommandSettingsFormat) -> Self {
+        match format {
+            CommandSettingsFormat::Shell(command) => Self {
+                args: shell_args(&command),
+                ..Default::default()
+            },
+            CommandSettingsFormat::Args {
+                args,
+                stdin,
+                output,
+            } => Self {
+                args,
+                stdin,
+                output,
+            },
+        }
+    }
+}
+
+/// Converts a shell command with `{word}` in it to one that reads the word from `KALBA_WORD`, so
+/// that the word can't be interpreted by the shell. `cmd` expands variables before it handles
+/// quotes, so on Windows the command is instead split into arguments and run without a shell.
+pub fn shell_args(command: &str) -> Vec<String> {
+    if cfg!(target_os = "windows") {
+        split_command(command)
+    } else {
+        vec![
+            String::from("sh"),
+            String::from("-c"),
+            shell_word_variable(command),
+        ]
+    }
+}
+
+/// Replaces `{word}` with the `KALBA_WORD` variable, quoted so that it stays one argument. A word
+/// that is quoted on its own loses its quotes, and one in a longer single quoted string ends the
+/// quotes around the variable, as variables aren't expanded in single quotes.
+fn shell_word_variable(command: &str) -> String {
+    let mut converted = String::new();
+    let mut quote = None;
+    let mut rest = command;
+    'outer: while let Some(c) = rest.chars().next() {
+        if quote.is_none() {
+            for quoted in ["{word}", "'{word}'", "\"{word}\""] {
+                if let Some(after) = rest.strip_prefix(quoted) {
+                    converted.push_str("\"$KALBA_WORD\"");
+                    rest = after;
+                    continue 'outer;
+                }
+            }
+        } else if let Some(after) = rest.strip_prefix("{word}") {
+            converted.push_str(if quote == Some('"') {
+                "${KALBA_WORD}"
+            } else {
+                "'\"$KALBA_WORD\"'"
+            });
+            rest = after;
+            continue;
+        }
+        match (c, quote) {
+            ('\\', Some('"') | None) => {
+                // The escaped character is copied along with the backslash
+                let escaped = rest[1..].chars().next().map_or(0, char::len_utf8);
+                converted.push_str(&rest[..1 + escaped]);
+                rest = &rest[1 + escaped..];
+                continue;
+            }
+            ('\'' | '"', None) => quote = Some(c),
+            (c, Some(open)) if c == open => quote = None,
+            _ => (),
+        }
+        converted.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    converted
+}
+
+/// Splits a command into arguments at whitespace outside of double quotes, like Windows programs
+/// split their command line
+pub fn split_command(command: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut quoted = false;
+    for c in command.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_arg = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_arg {
+                    args.push(std::mem::take(&mut arg));
+                    in_arg = false;
+                }
+            }
+            c => {
+                arg.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if in_arg {
+        args.push(arg);
+    }
+    args
+}
+
+/// A command run when Kalba starts, either as a string for the shell or as a list of arguments
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+#[serde(untagged)]
+pub enum StartupCommand {
+    Shell(String),
+    Args(Vec<String>),
+}
+
+impl DictionarySpecificSettings {
+    pub fn is_network(&self) -> bool {
+        matches!(
+            self,
+            Self::Url(..)
+                | Self::EkalbaBendrines
+                | Self::EkalbaDabartines
+                | Self::Wiktionary(..)
+                | Self::WordReference(..)
+        )
+    }
+
+    /// Whether the dictionary gives structured definitions rather than html
+    pub fn is_structured(&self) -> bool {
+        match self {
+            Self::Wiktionary(..) | Self::WordReference(..) | Self::Kaikki(..) => true,
+            Self::Command(settings) => settings.output == CommandOutput::Json,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
 #[serde(tag = "t", content = "c")]
 pub enum Definition {
     Text(String),
+    Structured(StructuredDefinition),
     OnDemand(String),
     Empty,
+    Error(String),
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct StructuredDefinition {
+    #[serde(default)]
+    pub headword: String,
+    pub entries: Vec<DefinitionEntry>,
+}
+
+/// The definitions of the word as a single part of speech
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+#[serde(default)]
+pub struct DefinitionEntry {
+    pub part_of_speech: Option<String>,
+    pub gender: Option<String>,
+    pub ipa: Vec<String>,
+    pub senses: Vec<Sense>,
+    pub forms: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Sense {
+    pub gloss: String,
+    pub tags: Vec<String>,
+    pub examples: Vec<Example>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct Example {
+    pub text: String,
+    pub translation: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -170,6 +512,23 @@ impl Default for ExportStyling {
     }
 }
 
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Debug)]
+pub struct CacheSettings {
+    /// The most definitions to keep, with the oldest being removed first
+    pub max_entries: usize,
+    /// Definitions older than this are fetched again. 0 keeps them forever
+    pub max_age_days: u32,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        Self {
+            max_entries: 50_000,
+            max_age_days: 90,
+        }
+    }
+}
+
 const fn default_anki_port() -> u16 {
     8765
 }
@@ -180,7 +539,7 @@ const fn default_anki_enabled() -> bool {
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct Settings {
-    pub to_run: Option<Vec<String>>,
+    pub to_run: Option<Vec<StartupCommand>>,
     pub dark_mode: bool,
     #[serde(default)]
     pub definition_styling: DefinitionStyling,
@@ -193,6 +552,13 @@ pub struct Settings {
     pub anki_port: u16,
     #[serde(default = "default_anki_enabled")]
     pub anki_enabled: bool,
+    #[serde(default)]
+    pub offline_mode: bool,
+    /// Show the definitions of every form of a word instead of only the first one that has any
+    #[serde(default)]
+    pub show_all_candidates: bool,
+    #[serde(default)]
+    pub definition_cache: CacheSettings,
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -211,6 +577,194 @@ pub struct LanguageSettings {
     pub suggest_on_lemmas: Vec<String>,
     #[serde(default)]
     pub derived_from_template: bool,
+    #[serde(default)]
+    pub examples: ExampleSettings,
+    #[serde(default)]
+    pub pronunciation: PronunciationSettings,
+    #[serde(default)]
+    pub translation: TranslationSettings,
+    #[serde(default)]
+    pub anki_ratings: AnkiRatingRules,
+    #[serde(default)]
+    pub apkg: ApkgSettings,
+}
+
+/// How the cards of notes in Anki are turned into ratings of words
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct AnkiRatingRules {
+    /// Checked in order, so the first rule a card meets gives its rating
+    pub rules: Vec<AnkiRatingRule>,
+    /// The rating of cards that meet none of the rules
+    pub otherwise: i8,
+    /// How the ratings of the cards of a note are combined
+    pub combine: RatingCombination,
+    /// The cards of a note to use, counting from 1. Every card is used when this is empty.
+    pub cards: Vec<u32>,
+}
+
+impl Default for AnkiRatingRules {
+    fn default() -> Self {
+        let interval_at_most = |days, rating| AnkiRatingRule {
+            conditions: vec![CardCondition::AtMost(CardProperty::Interval, days)],
+            rating,
+        };
+        Self {
+            rules: vec![
+                interval_at_most(1, 1),
+                interval_at_most(9, 2),
+                interval_at_most(23, 3),
+            ],
+            otherwise: 4,
+            combine: RatingCombination::Highest,
+            cards: Vec::new(),
+        }
+    }
+}
+
+/// Cards that meet every condition get the rating
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct AnkiRatingRule {
+    pub conditions: Vec<CardCondition>,
+    pub rating: i8,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RatingCombination {
+    Highest,
+    Lowest,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "t", content = "c")]
+pub enum CardCondition {
+    AtMost(CardProperty, u32),
+    AtLeast(CardProperty, u32),
+    /// The card is in one of these states
+    State(Vec<CardState>),
+    Not(Box<CardCondition>),
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CardProperty {
+    /// Days until the card is shown again
+    Interval,
+    /// The ease of the card as a percentage, such as 250
+    Ease,
+    Lapses,
+    Reviews,
+    /// Which card of the note it is, counting from 1
+    CardNumber,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CardState {
+    New,
+    Learning,
+    Review,
+    Suspended,
+    Buried,
+}
+
+/// The deck and note type of words exported to `.apkg` files
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct ApkgSettings {
+    pub deck: String,
+    pub note_type: String,
+    /// The fields of the note type, in order. The first one is used to sort and find duplicates.
+    pub fields: Vec<String>,
+    /// Anki templates for the front and back of cards, such as `{{Front}}`
+    pub front: String,
+    pub back: String,
+    pub css: String,
+}
+
+impl Default for ApkgSettings {
+    fn default() -> Self {
+        Self {
+            deck: String::from("Kalba"),
+            note_type: String::from("Kalba"),
+            fields: vec![String::from("Front"), String::from("Back")],
+            front: String::from("{{Front}}"),
+            back: String::from("{{FrontSide}}\n\n<hr id=answer>\n\n{{Back}}"),
+            css: String::from(
+                ".card {\n    font-family: arial;\n    font-size: 20px;\n    text-align: center;\n    color: black;\n    background-color: white;\n}\n",
+            ),
+        }
+    }
+}
+
+/// How whole sentences are translated
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct TranslationSettings {
+    pub backend: Option<TranslationBackend>,
+    /// Seconds to wait for a translation before giving up
+    pub timeout: u64,
+}
+
+impl Default for TranslationSettings {
+    fn default() -> Self {
+        Self {
+            backend: None,
+            timeout: 30,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "t", content = "c")]
+pub enum TranslationBackend {
+    /// A LibreTranslate compatible server, with the codes of the languages to translate from and
+    /// to
+    LibreTranslate {
+        url: String,
+        source: String,
+        target: String,
+        api_key: String,
+    },
+    /// An Ollama compatible server running a language model. `{sentence}` in the prompt is
+    /// replaced with the sentence.
+    Ollama {
+        url: String,
+        model: String,
+        prompt: String,
+    },
+    /// A program that prints the translation. `{sentence}` in the arguments is replaced with the
+    /// sentence.
+    Command { args: Vec<String>, stdin: bool },
+}
+
+/// Where audio of words and sentences comes from
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PronunciationSettings {
+    /// Folder of recordings named after the words in them, such as Lingua Libre or Forvo
+    /// downloads
+    pub recordings: String,
+    /// Text to speech command. `{word}` is replaced with the text and `{output}` with a file to
+    /// write the audio to. Without `{output}`, the audio is read from what the command prints.
+    pub tts: Vec<String>,
+}
+
+/// How example sentences for a word are chosen
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct ExampleSettings {
+    pub count: usize,
+    pub order: ExampleOrder,
+}
+
+impl Default for ExampleSettings {
+    fn default() -> Self {
+        Self {
+            count: 5,
+            order: ExampleOrder::MostComprehensible,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExampleOrder {
+    /// The examples with the highest share of known words come first
+    MostComprehensible,
+    Shortest,
 }
 
 impl Default for Settings {
@@ -227,6 +781,9 @@ impl Default for Settings {
             stanza_enabled: false,
             anki_port: default_anki_port(),
             anki_enabled: default_anki_enabled(),
+            offline_mode: false,
+            show_all_candidates: false,
+            definition_cache: CacheSettings::default(),
         }
     }
 }
@@ -249,6 +806,11 @@ impl Default for LanguageSettings {
             suggest_on_lemmas: Vec::new(),
             run_on_lemmas: Vec::new(),
             derived_from_template: false,
+            examples: ExampleSettings::default(),
+            pronunciation: PronunciationSettings::default(),
+            translation: TranslationSettings::default(),
+            anki_ratings: AnkiRatingRules::default(),
+            apkg: ApkgSettings::default(),
         }
     }
 }
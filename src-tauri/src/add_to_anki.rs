@@ -1,73 +1,505 @@
-use chrono::Utc;
-use reqwest::Client;
-use serde_json::json;
-use shared::{Definition, ExportStyling, ToasterPayload};
-use std::{borrow::Cow, collections::HashMap};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Local, Utc};
+use reqwest::{header::CONTENT_TYPE, Client};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use shared::{
+    Definition, DefinitionStyling, ExportStyling, LanguageSettings, NoteToWordHandling,
+    ToasterPayload,
+};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs,
+    hash::{Hash, Hasher},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 use tauri::{Emitter, State, Window};
 
-use crate::{ankiconnect::AnkiResult, KalbaError, KalbaState};
+use crate::{
+    anki_queue::queue_note,
+    ankiconnect::{
+        anki_connect_command, find_notes_with_word, generic_anki_connect_action, AnkiResult,
+        ExistingNote,
+    },
+    personal_dictionary::PERSONAL_DICTIONARY,
+    pronunciation::{media_file_name, pronunciation},
+    structured::{self, escape_html, unescape_html, EXPORT_PARTS},
+    template::{first_line, strip_html, truncate, Template},
+    translation::translate,
+    KalbaError, KalbaState, ToSave,
+};
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "t", content = "c")]
+pub enum MediaContents {
+    Path(PathBuf),
+    /// Base64 encoded data
+    Data(String),
+}
+
+/// A file that a note needs in Anki's media folder
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MediaFile {
+    pub name: String,
+    pub contents: MediaContents,
+}
+
+impl MediaFile {
+    pub fn read(&self) -> Result<Vec<u8>, KalbaError> {
+        Ok(match &self.contents {
+            MediaContents::Path(path) => fs::read(path)?,
+            MediaContents::Data(data) => STANDARD
+                .decode(data)
+                .map_err(|e| KalbaError::InvalidMedia(self.name.clone(), e.to_string()))?,
+        })
+    }
+
+    /// Copies the file into Kalba's data folder, so that it is kept until the note is exported
+    pub fn persist(self) -> Result<Self, KalbaError> {
+        let folder = dirs::data_dir()
+            .ok_or_else(|| KalbaError::MissingDir(String::from("data")))?
+            .join("kalba")
+            .join("media");
+        fs::create_dir_all(&folder)?;
+        let path = folder.join(&self.name);
+        if !path.exists() {
+            fs::write(&path, self.read()?)?;
+        }
+        Ok(Self {
+            name: self.name,
+            contents: MediaContents::Path(path),
+        })
+    }
+
+    async fn store_in_anki(&self, client: &Client, port: u16) -> Result<(), KalbaError> {
+        let params = match &self.contents {
+            MediaContents::Path(path) => json!({ "filename": self.name, "path": path }),
+            MediaContents::Data(data) => json!({ "filename": self.name, "data": data }),
+        };
+        let response = generic_anki_connect_action("storeMediaFile", params, client, port).await?;
+        std::convert::Into::<Result<String, KalbaError>>::into(
+            response.json::<AnkiResult<String>>().await?,
+        )?;
+        Ok(())
+    }
+}
+
+/// Deletes the media of a note that was copied to Kalba's data folder, unless a note that is still
+/// waiting to be exported uses it
+pub fn remove_unused_media(note: &RenderedNote, to_save: &ToSave) {
+    let in_use: Vec<&str> = to_save
+        .anki_queue
+        .iter()
+        .map(|queued| &queued.note)
+        .chain(
+            to_save
+                .language_specific
+                .values()
+                .flat_map(|language| &language.apkg_queue),
+        )
+        .flat_map(|note| &note.media)
+        .map(|file| file.name.as_str())
+        .collect();
+    for file in &note.media {
+        let MediaContents::Path(path) = &file.contents else {
+            continue;
+        };
+        if in_use.contains(&file.name.as_str()) {
+            continue;
+        }
+        if let Err(e) = fs::remove_file(path) {
+            log::warn!("Unable to remove {}: {e}", path.display());
+        }
+    }
+}
+
+/// A note with its fields filled in, ready to be added to Anki
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RenderedNote {
+    pub word: String,
+    pub deck: String,
+    pub model: String,
+    pub fields: HashMap<String, String>,
+    pub media: Vec<MediaFile>,
+    pub created: DateTime<Utc>,
+}
+
+fn extension_from_mime(mime: &str) -> &str {
+    match mime {
+        "image/jpeg" => "jpg",
+        "image/svg+xml" => "svg",
+        "audio/mpeg" => "mp3",
+        "text/css" => "css",
+        _ => mime.rsplit('/').next().unwrap_or("bin"),
+    }
+}
+
+/// Anki does not keep data uris in notes, so embedded media (such as images from MDict resources)
+/// is replaced with a file name and returned so that it can be stored in Anki's media folder
+fn extract_embedded_media(field: &str) -> (String, Vec<(String, String)>) {
+    let mut media = Vec::new();
+    let mut updated_field = String::new();
+    let mut rest = field;
+    while let Some(start) = rest.find("data:") {
+        let (before, uri) = rest.split_at(start);
+        updated_field.push_str(before);
+        let end = uri
+            .find(|c: char| c == '"' || c == '\'' || c == ')' || c.is_whitespace())
+            .unwrap_or(uri.len());
+        let Some((mime, data)) = uri[5..end].split_once(";base64,") else {
+            updated_field.push_str("data:");
+            rest = &uri[5..];
+            continue;
+        };
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        let file_name = format!("kalba_{:x}.{}", hasher.finish(), extension_from_mime(mime));
+        updated_field.push_str(&file_name);
+        media.push((file_name, data.to_owned()));
+        rest = &uri[end..];
+    }
+    updated_field.push_str(rest);
+    (updated_field, media)
+}
+
+/// How long to wait for images and audio linked from the page or definitions
+const MEDIA_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Where the first audio in the definition is, from the `src` of an `<audio>` or `<source>` tag
+fn audio_source(html: &str) -> Option<&str> {
+    ["<audio", "<source"]
+        .into_iter()
+        .filter_map(|tag| {
+            let start = html.find(tag)?;
+            let end = html[start..]
+                .find('>')
+                .map_or(html.len(), |end| start + end);
+            let attributes = &html[start..end];
+            let value = &attributes[attributes.find("src=")? + 4..];
+            let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+            let value = &value[1..];
+            Some((start, &value[..value.find(quote)?]))
+        })
+        .min_by_key(|(start, _)| *start)
+        .map(|(_, source)| source)
+}
+
+/// Downloads an image or audio file so that it can be stored with the note
+async fn download_media(url: &str) -> Result<MediaFile, KalbaError> {
+    if url.starts_with("data:") {
+        let (_, mut embedded) = extract_embedded_media(url);
+        let (name, data) = embedded.pop().ok_or_else(|| {
+            KalbaError::InvalidMedia(
+                String::from("data uri"),
+                String::from("it is not base64 encoded"),
+            )
+        })?;
+        return Ok(MediaFile {
+            name,
+            contents: MediaContents::Data(data),
+        });
+    }
+    let response = Client::builder()
+        .timeout(MEDIA_DOWNLOAD_TIMEOUT)
+        .build()?
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?;
+    let mime = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|mime| mime.to_str().ok())
+        .and_then(|mime| mime.split(';').next())
+        .unwrap_or_default()
+        .trim()
+        .to_owned();
+    let data = response.bytes().await?;
+    // The extension of the address is used if it has one, as servers often send a generic type
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    let extension = path
+        .rsplit_once('.')
+        .map(|(_, extension)| extension)
+        .filter(|extension| {
+            (1..=4).contains(&extension.len()) && extension.chars().all(char::is_alphanumeric)
+        })
+        .unwrap_or_else(|| extension_from_mime(&mime));
+    let mut hasher = DefaultHasher::new();
+    url.hash(&mut hasher);
+    Ok(MediaFile {
+        name: format!("kalba_{:x}.{extension}", hasher.finish()),
+        contents: MediaContents::Data(STANDARD.encode(data)),
+    })
+}
+
+/// The media that a variable links to, which is left out if it can't be downloaded so that the
+/// rest of the note can still be exported
+async fn linked_media(url: &str, offline_mode: bool) -> Option<MediaFile> {
+    if url.is_empty() {
+        return None;
+    }
+    if offline_mode && !url.starts_with("data:") {
+        log::info!("Not downloading {url} in offline mode");
+        return None;
+    }
+    match download_media(url).await {
+        Ok(file) => Some(file),
+        Err(e) => {
+            log::warn!("Unable to download {url}: {e}");
+            None
+        }
+    }
+}
+
+/// Turns a position counted in characters into one counted in bytes, if it is in the text and
+/// not empty
+fn byte_range(text: &str, (start, end): (usize, usize)) -> Option<(usize, usize)> {
+    let byte_index = |chars: usize| {
+        text.char_indices()
+            .map(|(index, _)| index)
+            .chain([text.len()])
+            .nth(chars)
+    };
+    let range = (byte_index(start)?, byte_index(end)?);
+    (range.0 < range.1).then_some(range)
+}
+
+/// Where the word is in the sentence, in bytes. Its position is used if that is known, or where
+/// it first appears otherwise, preferring places where it is a whole word.
+fn word_range(
+    sentence: &str,
+    original_form: &str,
+    position: Option<(usize, usize)>,
+) -> Option<(usize, usize)> {
+    if original_form.is_empty() {
+        return None;
+    }
+    let at_position = position
+        .and_then(|position| byte_range(sentence, position))
+        .filter(|(start, end)| sentence[*start..*end] == *original_form)
+        .map(|(start, _)| start);
+    let on_its_own = || {
+        sentence
+            .match_indices(original_form)
+            .map(|(start, _)| start)
+            .find(|start| {
+                let before = sentence[..*start].chars().next_back();
+                let after = sentence[start + original_form.len()..].chars().next();
+                !before.is_some_and(char::is_alphanumeric)
+                    && !after.is_some_and(char::is_alphanumeric)
+            })
+    };
+    let start = at_position
+        .or_else(on_its_own)
+        .or_else(|| sentence.find(original_form))?;
+    Some((start, start + original_form.len()))
+}
+
+fn highlight_word(
+    sentence: &str,
+    original_form: &str,
+    position: Option<(usize, usize)>,
+    style: &str,
+) -> String {
+    let Some((start, end)) = word_range(sentence, original_form, position) else {
+        return escape_html(sentence);
+    };
+    format!(
+        "{}<span style=\"{}\">{}</span>{}",
+        escape_html(&sentence[..start]),
+        style,
+        escape_html(&sentence[start..end]),
+        escape_html(&sentence[end..])
+    )
+}
+
+/// Escapes text so that it can't end the cloze deletion it is in
+fn escape_cloze(text: &str) -> String {
+    escape_html(text)
+        .replace(':', "&#58;")
+        .replace('}', "&#125;")
+}
+
+/// Hides the words at the positions in one cloze deletion for Anki's cloze note types, with the
+/// hint shown in their place. The word being exported is hidden if no positions are given.
+fn cloze(
+    sentence: &str,
+    original_form: &str,
+    word_position: Option<(usize, usize)>,
+    positions: &[(usize, usize)],
+    hint: &str,
+) -> String {
+    let mut ranges: Vec<(usize, usize)> = positions
+        .iter()
+        .filter_map(|position| byte_range(sentence, *position))
+        .collect();
+    if ranges.is_empty() {
+        ranges.extend(word_range(sentence, original_form, word_position));
+    }
+    ranges.sort_unstable();
+    let hint = if hint.is_empty() {
+        String::new()
+    } else {
+        format!("::{}", escape_cloze(hint))
+    };
+    let mut cloze = String::new();
+    let mut copied = 0;
+    for (start, end) in ranges {
+        // Overlapping words are already hidden
+        if start < copied {
+            continue;
+        }
+        cloze.push_str(&escape_html(&sentence[copied..start]));
+        cloze.push_str(&format!(
+            "{{{{c1::{}{hint}}}}}",
+            escape_cloze(&sentence[start..end])
+        ));
+        copied = end;
+    }
+    cloze.push_str(&escape_html(&sentence[copied..]));
+    cloze
+}
+
+/// The most characters of a definition that are shown as a cloze hint
+const CLOZE_HINT_LENGTH: usize = 60;
+
+/// Fills in the field templates. `variables` has the variables that don't come from the export
+/// details, such as audio.
 fn get_json(
     export_details: ExportDetails<'_>,
     export_styling: &ExportStyling,
-) -> serde_json::Value {
+    definition_styling: &DefinitionStyling,
+    mut variables: HashMap<String, String>,
+) -> Result<Value, KalbaError> {
     let mut def = String::new();
     for definition in export_details.defs.values() {
         def.push('\n');
-        if let Definition::Text(t) = definition {
-            def.push_str(t);
+        match definition {
+            Definition::Text(t) => def.push_str(t),
+            Definition::Structured(s) => def.push_str(&structured::to_html(s, definition_styling)),
+            _ => (),
         }
     }
 
-    let updated_sentence = export_details.sentence.replace(
-        export_details.original_form,
-        &format!(
-            "<span style=\"{}\">{}</span>",
-            export_styling.word_in_sentence, export_details.original_form
+    variables.insert(
+        String::from("sentence"),
+        highlight_word(
+            export_details.sentence,
+            export_details.original_form,
+            export_details.word_position,
+            &export_styling.word_in_sentence,
         ),
     );
+    // The hint from the definitions is its first line as plain text, kept short enough to fit in a
+    // sentence. It is escaped again when it is put in the cloze.
+    let definition_hint = truncate(
+        unescape_html(&strip_html(first_line(&def))),
+        CLOZE_HINT_LENGTH,
+    );
+    for (variable, hint) in [
+        ("cloze", ""),
+        ("cloze:lemma", export_details.word),
+        ("cloze:def", definition_hint.as_str()),
+    ] {
+        variables.insert(
+            variable.to_owned(),
+            cloze(
+                export_details.sentence,
+                export_details.original_form,
+                export_details.word_position,
+                &export_details.cloze_positions,
+                hint,
+            ),
+        );
+    }
+    variables.insert(String::from("word"), escape_html(export_details.word));
+    variables.insert(String::from("lemma"), escape_html(export_details.word));
+    variables.insert(
+        String::from("form"),
+        escape_html(export_details.original_form),
+    );
 
-    let mut replacements = HashMap::from([
-        (String::from("{sentence}"), Cow::Owned(updated_sentence)),
-        (String::from("{word}"), Cow::Borrowed(export_details.word)),
-        (String::from("{def}"), Cow::Owned(def)),
-    ]);
+    let context = &export_details.context;
+    let mut morph: Vec<String> = context
+        .morph
+        .iter()
+        .map(|(feature, value)| format!("{feature}={value}"))
+        .collect();
+    morph.sort();
+    variables.insert(String::from("morph"), escape_html(&morph.join("|")));
+    for feature in MORPH_FEATURES {
+        variables.insert(format!("morph:{feature}"), String::new());
+    }
+    for (feature, value) in &context.morph {
+        variables.insert(format!("morph:{feature}"), escape_html(value));
+    }
+    for (variable, value) in [
+        ("pos", context.pos.as_deref().unwrap_or_default()),
+        ("previous_sentence", context.previous_sentence.as_str()),
+        ("next_sentence", context.next_sentence.as_str()),
+        ("title", context.title.as_str()),
+        ("url", context.url.as_str()),
+    ] {
+        variables.insert(variable.to_owned(), escape_html(value));
+    }
+    variables.insert(String::from("def"), def);
+    variables.insert(
+        String::from("translation"),
+        escape_html(&export_details.translation.unwrap_or_default()),
+    );
 
     for (name, value) in export_details.defs {
-        replacements.insert(
-            format!("{{def:{}}}", name).to_owned(),
+        for part in EXPORT_PARTS {
+            variables.insert(
+                format!("def:{}:{}", name, part),
+                structured::export_part(&value, part).unwrap_or_default(),
+            );
+        }
+        variables.insert(
+            format!("def:{}", name),
             match value {
-                Definition::Text(t) => Cow::Owned(t),
-                Definition::Empty => Cow::Borrowed(""),
-                Definition::OnDemand(_) => Cow::Borrowed(""),
+                Definition::Text(t) => t,
+                Definition::Structured(s) => structured::to_html(&s, definition_styling),
+                Definition::Empty => String::new(),
+                Definition::OnDemand(_) => String::new(),
+                Definition::Error(_) => String::new(),
             },
         );
     }
-    log::debug!("Possible replacements: {:?}", replacements);
+    log::debug!("Possible replacements: {:?}", variables);
 
     let mut fields = HashMap::new();
 
-    for (field_name, conts) in &export_details.fields {
-        let mut conts = conts.to_string();
-        for (orig, replacement) in &replacements {
-            conts = conts.replace(orig, replacement);
-        }
-        fields.insert(field_name, conts);
+    for (field_name, template) in &export_details.fields {
+        let contents = Template::parse(template)
+            .and_then(|template| template.render(&variables))
+            .map_err(|e| KalbaError::InvalidTemplate(field_name.to_string(), e))?;
+        fields.insert(field_name.to_string(), contents);
     }
 
+    Ok(add_note_request(
+        export_details.deck,
+        export_details.model,
+        &fields,
+    ))
+}
+
+fn add_note_request(deck: &str, model: &str, fields: &HashMap<String, String>) -> Value {
     json!({
         "action": "addNote",
         "version": 6,
         "params": {
             "note": {
-                "deckName": export_details.deck,
-                "modelName": export_details.model,
+                "deckName": deck,
+                "modelName": model,
                 "fields": fields,
                 "options": {
                     "allowDuplicate": false,
                     "duplicateScope": "deck",
                     "duplicateScopeOptions": {
-                        "deckName": export_details.deck,
+                        "deckName": deck,
                         "checkChildren": false,
                         "checkAllModels": false
                     }
@@ -77,6 +509,52 @@ fn get_json(
     })
 }
 
+/// The variables about where the word was found and the note itself, which are filled in without
+/// a dictionary
+const CONTEXT_VARIABLES: [&str; 11] = [
+    "lemma",
+    "form",
+    "pos",
+    "morph",
+    "previous_sentence",
+    "next_sentence",
+    "title",
+    "url",
+    "frequency",
+    "date",
+    "language",
+];
+
+/// The universal features from Universal Dependencies, which can be used with `{morph:Feature}`
+/// and are left empty when a word doesn't have them
+const MORPH_FEATURES: [&str; 24] = [
+    "PronType",
+    "NumType",
+    "Poss",
+    "Reflex",
+    "Foreign",
+    "Abbr",
+    "Typo",
+    "Gender",
+    "Animacy",
+    "NounClass",
+    "Number",
+    "Case",
+    "Definite",
+    "Degree",
+    "VerbForm",
+    "Mood",
+    "Tense",
+    "Aspect",
+    "Voice",
+    "Evident",
+    "Polarity",
+    "Person",
+    "Polite",
+    "Clusivity",
+];
+
+/// Lists the variables other than the builtins, such as `def:Wiktionary`, without braces
 #[tauri::command]
 pub async fn get_export_variables(
     state: State<'_, KalbaState>,
@@ -94,44 +572,498 @@ pub async fn get_export_variables(
         .dicts
         .iter()
     {
-        export_variables.push(dict.name.clone());
+        export_variables.push(format!("def:{}", dict.name));
+        if dict.specific_settings.is_structured() {
+            for part in EXPORT_PARTS {
+                export_variables.push(format!("def:{}:{}", dict.name, part));
+            }
+        }
     }
+    export_variables.push(format!("def:{PERSONAL_DICTIONARY}"));
+    export_variables.extend(CONTEXT_VARIABLES.map(String::from));
+    export_variables.extend(MORPH_FEATURES.map(|feature| format!("morph:{feature}")));
     Ok(export_variables)
 }
 
+/// Where the word was found in the reader
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ExportContext {
+    /// The universal part of speech tag, such as NOUN
+    pos: Option<String>,
+    morph: HashMap<String, String>,
+    previous_sentence: String,
+    next_sentence: String,
+    /// The title of the page or file being read
+    title: String,
+    url: String,
+    /// The address of the image closest to the sentence
+    image: String,
+}
+
 #[derive(serde::Deserialize, Debug)]
 pub struct ExportDetails<'a> {
     word: &'a str,
     original_form: &'a str,
+    /// Where the word is in the sentence, counted in characters
+    #[serde(default)]
+    word_position: Option<(usize, usize)>,
+    /// The words hidden in cloze deletions, counted in characters. Only the word being exported
+    /// is hidden when there are none.
+    #[serde(default)]
+    cloze_positions: Vec<(usize, usize)>,
     sentence: &'a str,
     deck: &'a str,
     model: &'a str,
     fields: HashMap<&'a str, &'a str>,
     defs: HashMap<String, Definition>,
+    /// The translation of the sentence, which is translated when exporting if it is needed and
+    /// not given
+    #[serde(default)]
+    translation: Option<String>,
+    #[serde(default)]
+    context: ExportContext,
 }
 
-#[tauri::command]
-pub async fn add_to_anki(
-    export_details: ExportDetails<'_>,
-    state: State<'_, KalbaState>,
-    window: Window,
-) -> Result<(), KalbaError> {
-    let mut state = state.0.lock().await;
-    log::debug!("Adding to anki using details {:?}", export_details);
-    let selected_word = export_details.word;
-    let args = get_json(export_details, &state.settings.export_styling);
-    let client = Client::new();
-    let url = format!("http://localhost:{}/", state.settings.anki_port);
+/// Where the word is in the frequency list, starting from 1
+fn frequency_rank(frequency_list: &str, word: &str) -> Option<usize> {
+    let contents = fs::read_to_string(frequency_list).ok()?;
+    contents
+        .lines()
+        .position(|line| line.trim() == word)
+        .map(|index| index + 1)
+}
+
+/// Fills in the fields of the note, along with the audio and other media it uses. The state isn't
+/// kept locked while translating, downloading or synthesizing, so the app isn't blocked by them.
+pub async fn render_note(
+    state: &KalbaState,
+    mut export_details: ExportDetails<'_>,
+) -> Result<RenderedNote, KalbaError> {
+    let templates: Vec<Template> = export_details
+        .fields
+        .values()
+        .filter_map(|template| Template::parse(template).ok())
+        .collect();
+    let uses = |variable: &str| {
+        templates
+            .iter()
+            .any(|template| template.uses(|name| name == variable))
+    };
+    if export_details.translation.is_none() && uses("translation") {
+        // The note is still exported without a translation if the sentence can't be translated
+        export_details.translation = match translate(state, export_details.sentence.trim()).await {
+            Ok(translation) => translation,
+            Err(e) => {
+                log::warn!("Unable to translate sentence: {e}");
+                None
+            }
+        };
+    }
+    let word = export_details.word;
+    let sentence = export_details.sentence;
+    let deck = export_details.deck.to_owned();
+    let model = export_details.model.to_owned();
+    let (current_language, language, offline_mode, styling, definition_styling, dict_info) = {
+        let state = state.0.lock().await;
+        let current_language = state.current_language.clone().expect("language to exist");
+        let language = state
+            .settings
+            .languages
+            .get(&current_language)
+            .expect("language to exist")
+            .clone();
+        (
+            current_language,
+            language,
+            state.settings.offline_mode,
+            state.settings.export_styling.clone(),
+            state.settings.definition_styling.clone(),
+            Arc::clone(&state.dict_info),
+        )
+    };
+
+    // Dictionaries without a definition are still known, so that they can be left empty
+    let mut variables = HashMap::new();
+    for name in language
+        .dicts
+        .iter()
+        .map(|dict| dict.name.as_str())
+        .chain([PERSONAL_DICTIONARY])
+    {
+        variables.insert(format!("def:{name}"), String::new());
+        for part in EXPORT_PARTS {
+            variables.insert(format!("def:{name}:{part}"), String::new());
+        }
+    }
+
+    // The frequency list is only read when a field uses it
+    let frequency = if uses("frequency") {
+        frequency_rank(&language.frequency_list, word)
+    } else {
+        None
+    };
+    variables.insert(
+        String::from("frequency"),
+        frequency.map(|rank| rank.to_string()).unwrap_or_default(),
+    );
+    variables.insert(
+        String::from("date"),
+        Local::now().format("%Y-%m-%d").to_string(),
+    );
+    variables.insert(String::from("language"), current_language.clone());
+
+    let mut media = Vec::new();
+    // Linked media is only downloaded when a field uses it
+    let image = if uses("image") {
+        linked_media(&export_details.context.image, offline_mode).await
+    } else {
+        None
+    };
+    let definition_audio = export_details
+        .defs
+        .values()
+        .find_map(|definition| match definition {
+            Definition::Text(text) => audio_source(text),
+            _ => None,
+        });
+    let dictionary_audio = match definition_audio {
+        Some(url) if uses("dictionary_audio") => linked_media(url, offline_mode).await,
+        _ => None,
+    };
+    variables.insert(
+        String::from("image"),
+        image
+            .as_ref()
+            .map(|file| format!("<img src=\"{}\">", file.name))
+            .unwrap_or_default(),
+    );
+    variables.insert(
+        String::from("dictionary_audio"),
+        dictionary_audio
+            .as_ref()
+            .map(|file| format!("[sound:{}]", file.name))
+            .unwrap_or_default(),
+    );
+    media.extend(image.into_iter().chain(dictionary_audio));
+
+    // Audio is only looked up or synthesized when a field uses it
+    for (variable, text) in [("audio", word), ("sentence_audio", sentence)] {
+        let sound = if uses(variable) {
+            match pronunciation(&language.pronunciation, Arc::clone(&dict_info), text).await? {
+                Some(path) => {
+                    let name = media_file_name(&path);
+                    let sound = format!("[sound:{name}]");
+                    media.push(MediaFile {
+                        name,
+                        contents: MediaContents::Path(path),
+                    });
+                    sound
+                }
+                None => String::new(),
+            }
+        } else {
+            String::new()
+        };
+        variables.insert(variable.to_owned(), sound);
+    }
+
+    let mut args = get_json(export_details, &styling, &definition_styling, variables)?;
+    let mut fields: HashMap<String, String> =
+        serde_json::from_value(args["params"]["note"]["fields"].take())
+            .expect("fields to be strings");
+
+    for value in fields.values_mut() {
+        let (contents, embedded) = extract_embedded_media(value);
+        media.extend(embedded.into_iter().map(|(name, data)| MediaFile {
+            name,
+            contents: MediaContents::Data(data),
+        }));
+        *value = contents;
+    }
+
+    Ok(RenderedNote {
+        word: word.to_owned(),
+        deck,
+        model,
+        fields,
+        media,
+        created: Utc::now(),
+    })
+}
+
+/// Adds the note and its media to Anki through AnkiConnect
+pub async fn send_note(note: &RenderedNote, client: &Client, port: u16) -> Result<(), KalbaError> {
+    for file in &note.media {
+        file.store_in_anki(client, port).await?;
+    }
+    let url = format!("http://localhost:{port}/");
     let response = client
         .post(url)
-        .json(&args)
+        .json(&add_note_request(&note.deck, &note.model, &note.fields))
         .send()
         .await
         .map_err(|_| KalbaError::AnkiNotAvailable)?;
     std::convert::Into::<Result<isize, KalbaError>>::into(
         response.json::<AnkiResult<isize>>().await?,
     )?;
-    let current_language = state.current_language.clone().expect("language to exist");
+    Ok(())
+}
+
+/// How a note that is already in Anki is changed instead of adding the word again
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "t", content = "c")]
+pub enum NoteUpdate {
+    /// Adds the new sentence to the fields with the sentence
+    AppendSentence,
+    /// Replaces the fields with definitions
+    ReplaceDefinition,
+    AddTag(String),
+}
+
+/// Where the word, sentence and definitions are in notes of the note type being exported to
+struct NoteLayout {
+    word: Option<NoteToWordHandling>,
+    sentence_fields: Vec<String>,
+    definition_fields: Vec<String>,
+}
+
+impl NoteLayout {
+    /// The word is read from the field used for word knowledge if the note type has a parser, or
+    /// from the field that only has the word otherwise
+    fn new(
+        language: &LanguageSettings,
+        deck: &str,
+        model: &str,
+        fields: &HashMap<&str, &str>,
+    ) -> Self {
+        let fields_with = |variable: fn(&str) -> bool| {
+            fields
+                .iter()
+                .filter(|(_, template)| {
+                    Template::parse(template).is_ok_and(|template| template.uses(variable))
+                })
+                .map(|(field, _)| field.to_string())
+                .collect()
+        };
+        let word = language
+            .anki_parser
+            .get(deck)
+            .and_then(|note| note.0.get(model))
+            .cloned()
+            .or_else(|| {
+                let (field, _) = fields
+                    .iter()
+                    .find(|(_, template)| template.trim() == "{word}")?;
+                Some(NoteToWordHandling {
+                    field_to_use: field.to_string(),
+                    remove_everything_in_parens: false,
+                    only_first_word_or_line: false,
+                    search_params: String::new(),
+                })
+            });
+        Self {
+            word,
+            sentence_fields: fields_with(|name| name == "sentence" || name.starts_with("cloze")),
+            definition_fields: fields_with(|name| name == "def" || name.starts_with("def:")),
+        }
+    }
+
+    /// The fields of an existing note that change with the updates
+    fn updated_fields(
+        &self,
+        existing: &ExistingNote,
+        note: &RenderedNote,
+        updates: &[NoteUpdate],
+    ) -> HashMap<String, String> {
+        let mut fields = HashMap::new();
+        for update in updates {
+            match update {
+                NoteUpdate::AppendSentence => {
+                    for field in &self.sentence_fields {
+                        let (Some(old), Some(new)) =
+                            (existing.fields.get(field), note.fields.get(field))
+                        else {
+                            continue;
+                        };
+                        if !old.contains(new.as_str()) {
+                            fields.insert(field.clone(), format!("{old}<br>{new}"));
+                        }
+                    }
+                }
+                NoteUpdate::ReplaceDefinition => {
+                    for field in &self.definition_fields {
+                        if let Some(new) = note.fields.get(field) {
+                            fields.insert(field.clone(), new.clone());
+                        }
+                    }
+                }
+                NoteUpdate::AddTag(_) => (),
+            }
+        }
+        fields
+    }
+}
+
+/// Finds the notes the word is already in. Without a field that has only the word, Anki's own
+/// duplicate check is used instead, and a duplicate is an error as there are no notes to update.
+async fn find_existing(
+    layout: &NoteLayout,
+    note: &RenderedNote,
+    client: &Client,
+    port: u16,
+) -> Result<Vec<ExistingNote>, KalbaError> {
+    if let Some(handler) = &layout.word {
+        return find_notes_with_word(&note.deck, &note.model, handler, &note.word, client, port)
+            .await;
+    }
+    let request = add_note_request(&note.deck, &note.model, &note.fields);
+    let response = generic_anki_connect_action(
+        "canAddNotes",
+        json!({ "notes": [request["params"]["note"]] }),
+        client,
+        port,
+    )
+    .await?;
+    let can_add = Into::<Result<Vec<bool>, KalbaError>>::into(
+        response.json::<AnkiResult<Vec<bool>>>().await?,
+    )?;
+    if can_add.first() == Some(&false) {
+        return Err(KalbaError::AlreadyInAnki(note.word.clone()));
+    }
+    Ok(Vec::new())
+}
+
+async fn update_notes(
+    existing: &[ExistingNote],
+    note: &RenderedNote,
+    layout: &NoteLayout,
+    updates: &[NoteUpdate],
+    client: &Client,
+    port: u16,
+) -> Result<(), KalbaError> {
+    for file in &note.media {
+        file.store_in_anki(client, port).await?;
+    }
+    for existing_note in existing {
+        let fields = layout.updated_fields(existing_note, note, updates);
+        if !fields.is_empty() {
+            anki_connect_command(
+                "updateNoteFields",
+                json!({ "note": { "id": existing_note.id, "fields": fields } }),
+                client,
+                port,
+            )
+            .await?;
+        }
+    }
+    let ids: Vec<isize> = existing.iter().map(|note| note.id).collect();
+    for update in updates {
+        if let NoteUpdate::AddTag(tag) = update {
+            anki_connect_command(
+                "addTags",
+                json!({ "notes": ids, "tags": tag }),
+                client,
+                port,
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether the word is already in a note of the note type in the deck
+#[tauri::command]
+pub async fn is_in_anki(
+    state: State<'_, KalbaState>,
+    word: String,
+    deck: String,
+    model: String,
+    fields: HashMap<String, String>,
+) -> Result<bool, KalbaError> {
+    let (layout, port) = {
+        let state = state.0.lock().await;
+        let language = state
+            .settings
+            .languages
+            .get(state.current_language.as_ref().expect("language to exist"))
+            .expect("language to exist");
+        let fields = fields
+            .iter()
+            .map(|(field, template)| (field.as_str(), template.as_str()))
+            .collect();
+        (
+            NoteLayout::new(language, &deck, &model, &fields),
+            state.settings.anki_port,
+        )
+    };
+    let Some(handler) = layout.word else {
+        return Ok(false);
+    };
+    let existing =
+        find_notes_with_word(&deck, &model, &handler, &word, &Client::new(), port).await?;
+    Ok(!existing.is_empty())
+}
+
+/// Adds the word to Anki. If it is already in Anki, the existing notes are changed with the
+/// updates instead, or an error is returned if there are none.
+#[tauri::command]
+pub async fn add_to_anki(
+    export_details: ExportDetails<'_>,
+    update: Option<Vec<NoteUpdate>>,
+    state: State<'_, KalbaState>,
+    window: Window,
+) -> Result<(), KalbaError> {
+    log::debug!("Adding to anki using details {:?}", export_details);
+    let (current_language, layout, port) = {
+        let state = state.0.lock().await;
+        let current_language = state.current_language.clone().expect("language to exist");
+        let layout = NoteLayout::new(
+            state
+                .settings
+                .languages
+                .get(&current_language)
+                .expect("language to exist"),
+            export_details.deck,
+            export_details.model,
+            &export_details.fields,
+        );
+        (current_language, layout, state.settings.anki_port)
+    };
+    let note = render_note(&state, export_details).await?;
+    let client = Client::new();
+
+    let sent = match find_existing(&layout, &note, &client, port).await {
+        Ok(existing) if existing.is_empty() => send_note(&note, &client, port).await,
+        Ok(existing) => {
+            let Some(updates) = update else {
+                return Err(KalbaError::AlreadyInAnki(note.word));
+            };
+            update_notes(&existing, &note, &layout, &updates, &client, port).await?;
+            window.emit(
+                "added_to_anki",
+                Some(ToasterPayload {
+                    message: Some(&format!("Updated {} in anki", note.word)),
+                }),
+            )?;
+            return Ok(());
+        }
+        Err(e) => Err(e),
+    };
+    let mut state = state.0.lock().await;
+    let message = match sent {
+        Ok(()) => format!("Added {} to anki", note.word),
+        Err(KalbaError::AnkiNotAvailable) => {
+            log::info!("Anki is not available, so {} was queued", note.word);
+            let message = format!(
+                "Anki is not available, so {} will be added the next time it is",
+                note.word
+            );
+            queue_note(&mut state.to_save, note.clone())?;
+            message
+        }
+        Err(e) => return Err(e),
+    };
 
     state
         .to_save
@@ -139,11 +1071,11 @@ pub async fn add_to_anki(
         .get_mut(&current_language)
         .expect("language to exist")
         .added_to_anki
-        .push((Utc::now(), selected_word.to_string()));
+        .push((Utc::now(), note.word.clone()));
     window.emit(
         "added_to_anki",
         Some(ToasterPayload {
-            message: Some(&format!("Added {} to anki", selected_word)),
+            message: Some(&message),
         }),
     )?;
     log::debug!("Added to anki");
@@ -152,21 +1084,33 @@ pub async fn add_to_anki(
 
 #[cfg(test)]
 mod test {
+    use shared::{DefinitionEntry, Example, Sense, StructuredDefinition};
+
     use super::*;
-    use serde_json::Value;
+    use crate::test_server::serve_once;
 
     #[test]
     fn default_settings_no_defs() {
         let details = ExportDetails {
             word: "word",
             original_form: "word",
+            word_position: None,
+            cloze_positions: Vec::new(),
             sentence: "mmm",
             deck: "Default",
             model: "Basic",
             defs: HashMap::new(),
+            translation: None,
+            context: ExportContext::default(),
             fields: HashMap::from([("Front", "{sentence}"), ("Back", "{word}:")]),
         };
-        let args = get_json(details, &ExportStyling::default());
+        let args = get_json(
+            details,
+            &ExportStyling::default(),
+            &DefinitionStyling::default(),
+            HashMap::new(),
+        )
+        .unwrap();
         let params = args.get("params").unwrap();
         let note = params.get("note").unwrap();
         assert_eq!(
@@ -183,11 +1127,94 @@ mod test {
         );
     }
 
+    #[test]
+    fn translation_variable() {
+        let details = ExportDetails {
+            word: "labas",
+            original_form: "labas",
+            word_position: None,
+            cloze_positions: Vec::new(),
+            sentence: "Labas rytas.",
+            deck: "Default",
+            model: "Basic",
+            defs: HashMap::new(),
+            translation: Some(String::from("Good morning.")),
+            context: ExportContext::default(),
+            fields: HashMap::from([("Back", "{translation}")]),
+        };
+        let args = get_json(
+            details,
+            &ExportStyling::default(),
+            &DefinitionStyling::default(),
+            HashMap::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            args.pointer("/params/note/fields").unwrap(),
+            &json!({"Back": "Good morning."})
+        );
+    }
+
+    #[test]
+    fn existing_notes_are_updated() {
+        let layout = NoteLayout::new(
+            &LanguageSettings::default(),
+            "Default",
+            "Basic",
+            &HashMap::from([
+                ("Word", "{word}"),
+                ("Sentence", "{sentence}"),
+                ("Definition", "{word}: {def}"),
+            ]),
+        );
+        assert_eq!(layout.word.as_ref().unwrap().field_to_use, "Word");
+        let existing = ExistingNote {
+            id: 1,
+            fields: HashMap::from([
+                (String::from("Word"), String::from("labas")),
+                (String::from("Sentence"), String::from("Labas rytas.")),
+                (String::from("Definition"), String::from("labas: hi")),
+            ]),
+        };
+        let mut note = RenderedNote {
+            word: String::from("labas"),
+            deck: String::from("Default"),
+            model: String::from("Basic"),
+            fields: HashMap::from([
+                (String::from("Word"), String::from("labas")),
+                (String::from("Sentence"), String::from("Labas vakaras.")),
+                (String::from("Definition"), String::from("labas: hello")),
+            ]),
+            media: Vec::new(),
+            created: Utc::now(),
+        };
+        let updates = [NoteUpdate::AppendSentence, NoteUpdate::ReplaceDefinition];
+        assert_eq!(
+            layout.updated_fields(&existing, &note, &updates),
+            HashMap::from([
+                (
+                    String::from("Sentence"),
+                    String::from("Labas rytas.<br>Labas vakaras.")
+                ),
+                (String::from("Definition"), String::from("labas: hello")),
+            ])
+        );
+        // A sentence that is already in the note is not added again
+        note.fields
+            .insert(String::from("Sentence"), String::from("Labas rytas."));
+        assert_eq!(
+            layout.updated_fields(&existing, &note, &[NoteUpdate::AppendSentence]),
+            HashMap::new()
+        );
+    }
+
     #[test]
     fn default_settings_defs() {
         let details = ExportDetails {
             word: "word",
             original_form: "word",
+            word_position: None,
+            cloze_positions: Vec::new(),
             sentence: "sent with word:2",
             deck: "Default",
             model: "Basic",
@@ -205,12 +1232,20 @@ mod test {
                     Definition::Text(String::from("def3")),
                 ),
             ]),
+            translation: None,
+            context: ExportContext::default(),
             fields: HashMap::from([
                 ("Front", "{sentence}"),
                 ("Back", "{word}:{def:dict1}{def:dict2}{def:dict3}"),
             ]),
         };
-        let args = get_json(details, &ExportStyling::default());
+        let args = get_json(
+            details,
+            &ExportStyling::default(),
+            &DefinitionStyling::default(),
+            HashMap::new(),
+        )
+        .unwrap();
         let params = args.get("params").unwrap();
         let note = params.get("note").unwrap();
         assert_eq!(
@@ -232,6 +1267,8 @@ mod test {
         let details = ExportDetails {
             word: "word",
             original_form: "word",
+            word_position: None,
+            cloze_positions: Vec::new(),
             sentence: "sent",
             deck: "deck",
             model: "note",
@@ -239,6 +1276,8 @@ mod test {
                 String::from("dict1"),
                 Definition::Text(String::from("def1")),
             )]),
+            translation: None,
+            context: ExportContext::default(),
             fields: HashMap::from([
                 ("sentence", "{sentence}[{word}]"),
                 ("word", "{word}"),
@@ -246,7 +1285,13 @@ mod test {
             ]),
         };
 
-        let args = get_json(details, &ExportStyling::default());
+        let args = get_json(
+            details,
+            &ExportStyling::default(),
+            &DefinitionStyling::default(),
+            HashMap::new(),
+        )
+        .unwrap();
         let params = args.get("params").unwrap();
         let note = params.get("note").unwrap();
         assert_eq!(
@@ -268,15 +1313,322 @@ mod test {
         let details = ExportDetails {
             word: "word",
             original_form: "word",
+            word_position: None,
+            cloze_positions: Vec::new(),
             sentence: "sent",
             deck: "deck",
             model: "note",
             defs: HashMap::new(),
+            translation: None,
+            context: ExportContext::default(),
             fields: HashMap::from([("sentence", "{sentence}"), ("sentence", "{sentence}")]),
         };
-        let args = get_json(details, &ExportStyling::default());
+        let args = get_json(
+            details,
+            &ExportStyling::default(),
+            &DefinitionStyling::default(),
+            HashMap::new(),
+        )
+        .unwrap();
         let params = args.get("params").unwrap();
         let note = params.get("note").unwrap();
         assert_eq!(note.get("fields").unwrap(), &json!({"sentence": "sent"}));
     }
+
+    #[test]
+    fn structured_definition_parts() {
+        let details = ExportDetails {
+            word: "geras",
+            original_form: "geras",
+            word_position: None,
+            cloze_positions: Vec::new(),
+            sentence: "sent",
+            deck: "deck",
+            model: "note",
+            defs: HashMap::from([(
+                String::from("wiktionary"),
+                Definition::Structured(StructuredDefinition {
+                    headword: String::from("geras"),
+                    entries: vec![DefinitionEntry {
+                        part_of_speech: Some(String::from("adj")),
+                        senses: vec![
+                            Sense {
+                                gloss: String::from("good"),
+                                examples: vec![Example {
+                                    text: String::from("Geras žmogus."),
+                                    translation: None,
+                                }],
+                                ..Default::default()
+                            },
+                            Sense {
+                                gloss: String::from("kind"),
+                                ..Default::default()
+                            },
+                        ],
+                        ..Default::default()
+                    }],
+                }),
+            )]),
+            translation: None,
+            context: ExportContext::default(),
+            fields: HashMap::from([
+                (
+                    "meaning",
+                    "{def:wiktionary:first_sense} ({def:wiktionary:pos})",
+                ),
+                ("example", "{def:wiktionary:examples}"),
+            ]),
+        };
+        let args = get_json(
+            details,
+            &ExportStyling::default(),
+            &DefinitionStyling::default(),
+            HashMap::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            args.pointer("/params/note/fields").unwrap(),
+            &json!({"meaning": "good (adj)", "example": "Geras žmogus."})
+        );
+    }
+
+    #[test]
+    fn context_variables() {
+        let details = ExportDetails {
+            word: "rytas",
+            original_form: "rytą",
+            word_position: None,
+            cloze_positions: Vec::new(),
+            sentence: "Labas rytą.",
+            deck: "Default",
+            model: "Basic",
+            defs: HashMap::new(),
+            translation: None,
+            context: ExportContext {
+                pos: Some(String::from("NOUN")),
+                morph: HashMap::from([
+                    (String::from("Number"), String::from("Sing")),
+                    (String::from("Case"), String::from("Acc")),
+                ]),
+                previous_sentence: String::new(),
+                next_sentence: String::from("Kaip sekasi?"),
+                title: String::from("Pokalbiai"),
+                url: String::from("https://example.com/?a=1&b=2"),
+                image: String::new(),
+            },
+            fields: HashMap::from([
+                (
+                    "Word",
+                    "{lemma} ({form}, {pos}, {morph:Case}{morph:Gender})",
+                ),
+                ("Grammar", "{morph}"),
+                (
+                    "Context",
+                    "{previous_sentence}{{#next_sentence}} / {next_sentence}{{/}}",
+                ),
+                ("Source", "<a href=\"{url}\">{title}</a>"),
+            ]),
+        };
+        let args = get_json(
+            details,
+            &ExportStyling::default(),
+            &DefinitionStyling::default(),
+            HashMap::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            args.pointer("/params/note/fields").unwrap(),
+            &json!({
+                "Word": "rytas (rytą, NOUN, Acc)",
+                "Grammar": "Case=Acc|Number=Sing",
+                "Context": " / Kaip sekasi?",
+                "Source": "<a href=\"https://example.com/?a=1&amp;b=2\">Pokalbiai</a>",
+            })
+        );
+    }
+
+    #[test]
+    fn word_is_highlighted_where_it_is() {
+        let style = "color: red";
+        assert_eq!(
+            highlight_word("This is it, is it?", "is", Some((12, 14)), style),
+            "This is it, <span style=\"color: red\">is</span> it?"
+        );
+        // Without a position, the first place where it is a whole word is used
+        assert_eq!(
+            highlight_word("This <is> it", "is", None, style),
+            "This &lt;<span style=\"color: red\">is</span>&gt; it"
+        );
+        // A position that no longer matches the sentence is ignored
+        assert_eq!(
+            highlight_word("今日は良い天気", "天気", Some((0, 2)), style),
+            "今日は良い<span style=\"color: red\">天気</span>"
+        );
+    }
+
+    #[test]
+    fn cloze_deletions() {
+        let sentence = "Labas rytas, labas vakaras.";
+        assert_eq!(
+            cloze(sentence, "labas", Some((13, 18)), &[], ""),
+            "Labas rytas, {{c1::labas}} vakaras."
+        );
+        assert_eq!(
+            cloze(
+                sentence,
+                "labas",
+                None,
+                &[(13, 18), (0, 5), (40, 45)],
+                "hi: hello"
+            ),
+            "{{c1::Labas::hi&#58; hello}} rytas, {{c1::labas::hi&#58; hello}} vakaras."
+        );
+
+        let details = ExportDetails {
+            word: "rytas",
+            original_form: "rytas",
+            word_position: None,
+            cloze_positions: Vec::new(),
+            sentence,
+            deck: "Default",
+            model: "Cloze",
+            defs: HashMap::from([(
+                String::from("dict1"),
+                Definition::Text(String::from(
+                    "<b>morning &amp; dawn</b>&#33;<br>the start of the day",
+                )),
+            )]),
+            translation: None,
+            context: ExportContext::default(),
+            fields: HashMap::from([("Text", "{cloze:def}"), ("Back Extra", "{cloze:lemma}")]),
+        };
+        let args = get_json(
+            details,
+            &ExportStyling::default(),
+            &DefinitionStyling::default(),
+            HashMap::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            args.pointer("/params/note/fields").unwrap(),
+            &json!({
+                "Text": "Labas {{c1::rytas::morning &amp; dawn!}}, labas vakaras.",
+                "Back Extra": "Labas {{c1::rytas::rytas}}, labas vakaras.",
+            })
+        );
+    }
+
+    #[test]
+    fn unknown_variables_are_reported() {
+        let details = ExportDetails {
+            word: "labas",
+            original_form: "labas",
+            word_position: None,
+            cloze_positions: Vec::new(),
+            sentence: "Labas rytas.",
+            deck: "Default",
+            model: "Basic",
+            defs: HashMap::new(),
+            translation: None,
+            context: ExportContext::default(),
+            fields: HashMap::from([("Back", "{wrod}{{#def}}{def}{{/}}")]),
+        };
+        let result = get_json(
+            details,
+            &ExportStyling::default(),
+            &DefinitionStyling::default(),
+            HashMap::new(),
+        );
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "The template of the Back field is invalid: unknown variables {wrod}"
+        );
+    }
+
+    #[test]
+    fn audio_is_found_in_definitions() {
+        assert_eq!(
+            audio_source("<p>labas</p><audio controls><source src='https://example.com/labas.ogg' type=\"audio/ogg\"></audio>"),
+            Some("https://example.com/labas.ogg")
+        );
+        assert_eq!(
+            audio_source("<audio src=\"labas.mp3\"></audio><source src=\"other.mp3\">"),
+            Some("labas.mp3")
+        );
+        assert_eq!(audio_source("<img src=\"labas.png\">"), None);
+    }
+
+    #[test]
+    fn duplicates_are_checked_by_anki_without_a_word_field() {
+        let (url, request) =
+            serve_once("application/json", r#"{"result": [false], "error": null}"#);
+        let port = url.rsplit(':').next().unwrap().parse().unwrap();
+        let layout = NoteLayout {
+            word: None,
+            sentence_fields: Vec::new(),
+            definition_fields: Vec::new(),
+        };
+        let note = RenderedNote {
+            word: String::from("labas"),
+            deck: String::from("Default"),
+            model: String::from("Basic"),
+            fields: HashMap::from([(String::from("Front"), String::from("Labas rytas"))]),
+            media: Vec::new(),
+            created: Utc::now(),
+        };
+        let existing =
+            tauri::async_runtime::block_on(find_existing(&layout, &note, &Client::new(), port));
+        assert!(matches!(existing, Err(KalbaError::AlreadyInAnki(word)) if word == "labas"));
+
+        let request = request.join().unwrap();
+        let (_, body) = request.split_once("\r\n\r\n").unwrap();
+        let body: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["action"], "canAddNotes");
+        assert_eq!(body["params"]["notes"][0]["fields"]["Front"], "Labas rytas");
+        assert_eq!(
+            body["params"]["notes"][0]["options"]["allowDuplicate"],
+            false
+        );
+    }
+
+    #[test]
+    fn linked_media_is_downloaded() {
+        let (url, _) = serve_once("image/png; charset=binary", "not really a png");
+        let file =
+            tauri::async_runtime::block_on(linked_media(&format!("{url}/cat?size=2"), false))
+                .unwrap();
+        assert!(file.name.starts_with("kalba_") && file.name.ends_with(".png"));
+        assert_eq!(file.read().unwrap(), b"not really a png");
+
+        let offline = linked_media("https://example.com/cat.png", true);
+        assert!(tauri::async_runtime::block_on(offline).is_none());
+        let embedded = linked_media("data:image/gif;base64,R0lGOD", true);
+        let file = tauri::async_runtime::block_on(embedded).unwrap();
+        assert!(file.name.ends_with(".gif"));
+    }
+
+    #[test]
+    fn embedded_media_is_extracted() {
+        let (field, media) = extract_embedded_media(
+            "hi <img src=\"data:image/png;base64,iVBORw0K\"> <audio src='data:audio/mpeg;base64,SUQz'></audio>",
+        );
+        assert_eq!(media.len(), 2);
+        assert_eq!(media[0].1, "iVBORw0K");
+        assert!(media[0].0.starts_with("kalba_") && media[0].0.ends_with(".png"));
+        assert!(media[1].0.ends_with(".mp3"));
+        assert_eq!(
+            field,
+            format!(
+                "hi <img src=\"{}\"> <audio src='{}'></audio>",
+                media[0].0, media[1].0
+            )
+        );
+    }
+
+    #[test]
+    fn text_without_embedded_media_is_unchanged() {
+        let (field, media) = extract_embedded_media("data: this is not a uri");
+        assert!(media.is_empty());
+        assert_eq!(field, "data: this is not a uri");
+    }
 }
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tauri::State;
+
+use crate::{
+    add_to_anki::{remove_unused_media, send_note, RenderedNote},
+    KalbaError, KalbaState, ToSave,
+};
+
+/// A note that could not be added because Anki was not available
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueuedNote {
+    pub note: RenderedNote,
+    /// Why the note was not added the last time it was sent, if Anki was available
+    pub error: Option<String>,
+}
+
+/// Keeps the note until Anki is available, along with its media
+pub fn queue_note(to_save: &mut ToSave, note: RenderedNote) -> Result<(), KalbaError> {
+    let mut note = note;
+    note.media = note
+        .media
+        .into_iter()
+        .map(|file| file.persist())
+        .collect::<Result<_, _>>()?;
+    to_save.anki_queue.push(QueuedNote { note, error: None });
+    Ok(())
+}
+
+/// Adds the queued notes to Anki, returning how many were added. Stops without an error if Anki is
+/// still not available, and keeps notes that Anki refused along with the reason.
+pub async fn send_queued_notes(to_save: &mut ToSave, port: u16) -> Result<usize, KalbaError> {
+    if to_save.anki_queue.is_empty() {
+        return Ok(0);
+    }
+    let client = Client::new();
+    let mut sent = 0;
+    let mut index = 0;
+    while index < to_save.anki_queue.len() {
+        match send_note(&to_save.anki_queue[index].note, &client, port).await {
+            Ok(()) => {
+                let queued = to_save.anki_queue.remove(index);
+                remove_unused_media(&queued.note, to_save);
+                sent += 1;
+            }
+            Err(KalbaError::AnkiNotAvailable) => break,
+            Err(e) => {
+                log::warn!("Unable to add queued note: {e}");
+                to_save.anki_queue[index].error = Some(e.to_string());
+                index += 1;
+            }
+        }
+    }
+    log::info!("Added {sent} queued notes to Anki");
+    Ok(sent)
+}
+
+#[tauri::command]
+pub async fn get_anki_queue(state: State<'_, KalbaState>) -> Result<Vec<QueuedNote>, KalbaError> {
+    Ok(state.0.lock().await.to_save.anki_queue.clone())
+}
+
+/// Changes where a queued note is added and the contents of its fields
+#[tauri::command]
+pub async fn edit_queued_note(
+    state: State<'_, KalbaState>,
+    index: usize,
+    deck: String,
+    model: String,
+    fields: HashMap<String, String>,
+) -> Result<(), KalbaError> {
+    let mut state = state.0.lock().await;
+    if let Some(queued) = state.to_save.anki_queue.get_mut(index) {
+        queued.note.deck = deck;
+        queued.note.model = model;
+        queued.note.fields = fields;
+        queued.error = None;
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn drop_queued_note(
+    state: State<'_, KalbaState>,
+    index: usize,
+) -> Result<(), KalbaError> {
+    let mut state = state.0.lock().await;
+    if index < state.to_save.anki_queue.len() {
+        let queued = state.to_save.anki_queue.remove(index);
+        remove_unused_media(&queued.note, &state.to_save);
+    }
+    Ok(())
+}
+
+/// Tries to add the queued notes to Anki now, returning how many were added
+#[tauri::command]
+pub async fn send_anki_queue(state: State<'_, KalbaState>) -> Result<usize, KalbaError> {
+    let mut state = state.0.lock().await;
+    let port = state.settings.anki_port;
+    let sent = send_queued_notes(&mut state.to_save, port).await?;
+    // Notes without an error are only left when Anki could not be reached
+    if sent == 0 && state.to_save.anki_queue.iter().any(|q| q.error.is_none()) {
+        return Err(KalbaError::AnkiNotAvailable);
+    }
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use chrono::Utc;
+
+    use super::*;
+    use crate::test_server::serve_once;
+
+    #[test]
+    fn notes_are_kept_until_anki_adds_them() {
+        let queued = QueuedNote {
+            note: RenderedNote {
+                word: String::from("labas"),
+                deck: String::from("Default"),
+                model: String::from("Basic"),
+                fields: HashMap::from([(String::from("Front"), String::from("labas"))]),
+                media: Vec::new(),
+                created: Utc::now(),
+            },
+            error: None,
+        };
+        let mut to_save = ToSave {
+            anki_queue: vec![queued.clone(), queued],
+            ..Default::default()
+        };
+
+        // Nothing is listening on the port once the listener is dropped
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let sent = tauri::async_runtime::block_on(send_queued_notes(&mut to_save, port));
+        assert_eq!(sent.unwrap(), 0);
+        assert!(to_save.anki_queue.iter().all(|q| q.error.is_none()));
+
+        // The first note is refused, and Anki is gone by the time the second one is sent
+        let (url, _) = serve_once(
+            "application/json",
+            r#"{"result": null, "error": "cannot create note because it is a duplicate"}"#,
+        );
+        let port = url.rsplit(':').next().unwrap().parse().unwrap();
+        let sent = tauri::async_runtime::block_on(send_queued_notes(&mut to_save, port));
+        assert_eq!(sent.unwrap(), 0);
+        assert_eq!(to_save.anki_queue.len(), 2);
+        assert_eq!(
+            to_save.anki_queue[0].error.as_deref(),
+            Some("Ankiconnect return an error: cannot create note because it is a duplicate")
+        );
+        assert!(to_save.anki_queue[1].error.is_none());
+    }
+}
@@ -1,10 +1,16 @@
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
 
 use chrono::Utc;
+use futures::StreamExt;
 use reqwest::Response;
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{json, value::Value};
-use shared::NoteToWordHandling;
+use shared::{
+    AnkiRatingRules, CardCondition, CardProperty, CardState, NoteToWordHandling, RatingCombination,
+};
 use tauri::State;
 
 use crate::{KalbaError, KalbaState, Method, WordInfo};
@@ -27,67 +33,266 @@ impl<T> From<AnkiResult<T>> for Result<T, KalbaError> {
     }
 }
 
+/// Notes and cards are requested from AnkiConnect in batches of this many ids
+const BATCH_SIZE: usize = 500;
+/// Batches that are sent together in one `multi` request
+const BATCHES_PER_REQUEST: usize = 4;
+/// Requests that are sent to AnkiConnect at the same time
+const CONCURRENT_REQUESTS: usize = 2;
+
+#[derive(Clone, Serialize)]
+pub struct AnkiSyncProgress<'a> {
+    pub deck: &'a str,
+    pub done: usize,
+    pub total: usize,
+}
+
+/// Runs the action on each batch of ids in a single request, returning how many ids there were
+/// along with the combined results
+async fn multi_request<T: DeserializeOwned>(
+    action: &str,
+    key: &str,
+    ids: &[isize],
+    client: &reqwest::Client,
+    port: u16,
+) -> Result<(usize, Vec<T>), KalbaError> {
+    let actions: Vec<Value> = ids
+        .chunks(BATCH_SIZE)
+        .map(|batch| json!({ "action": action, "version": 6, "params": { key: batch } }))
+        .collect();
+    let response =
+        generic_anki_connect_action("multi", json!({ "actions": actions }), client, port).await?;
+    let results: Vec<AnkiResult<Vec<T>>> =
+        Into::<Result<_, KalbaError>>::into(response.json::<AnkiResult<_>>().await?)?;
+    let mut values = Vec::with_capacity(ids.len());
+    for result in results {
+        values.extend(Into::<Result<Vec<T>, KalbaError>>::into(result)?);
+    }
+    Ok((ids.len(), values))
+}
+
+/// Runs an action that takes a list of ids, such as `cardsInfo`, on every id. The ids are split
+/// into batches that are sent together with `multi`. `on_progress` is called with how many ids
+/// are done after each request.
+async fn batched_action<T: DeserializeOwned>(
+    action: &str,
+    key: &str,
+    ids: &[isize],
+    client: &reqwest::Client,
+    port: u16,
+    on_progress: impl Fn(usize),
+) -> Result<Vec<T>, KalbaError> {
+    let mut requests = Vec::new();
+    for ids in ids.chunks(BATCH_SIZE * BATCHES_PER_REQUEST) {
+        requests.push(multi_request::<T>(action, key, ids, client, port));
+    }
+    let mut responses = futures::stream::iter(requests).buffered(CONCURRENT_REQUESTS);
+
+    let mut values = Vec::with_capacity(ids.len());
+    let mut done = 0;
+    while let Some(response) = responses.next().await {
+        let (count, batch) = response?;
+        done += count;
+        values.extend(batch);
+        on_progress(done);
+    }
+    Ok(values)
+}
+
+/// What has been synced from a deck, so later syncs only need to check what changed
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(from = "DeckSyncFormat")]
+pub struct DeckSync {
+    /// The latest modification time of the notes and cards that have been synced, in seconds
+    pub modified: i64,
+    /// The ids of the notes each word comes from
+    pub words: HashMap<String, Vec<isize>>,
+    /// The word of each note, so that notes can be found without going through every word
+    #[serde(skip)]
+    note_words: HashMap<isize, String>,
+}
+
+#[derive(Deserialize)]
+struct DeckSyncFormat {
+    modified: i64,
+    words: HashMap<String, Vec<isize>>,
+}
+
+impl From<DeckSyncFormat> for DeckSync {
+    fn from(format: DeckSyncFormat) -> Self {
+        let note_words = format
+            .words
+            .iter()
+            .flat_map(|(word, notes)| notes.iter().map(|note| (*note, word.clone())))
+            .collect();
+        Self {
+            modified: format.modified,
+            words: format.words,
+            note_words,
+        }
+    }
+}
+
+impl DeckSync {
+    /// Sets the word of the note, returning its old word if no other note has it anymore
+    fn set_word(&mut self, note_id: isize, word: &str) -> Option<String> {
+        let orphaned = self.remove_note(note_id);
+        self.words.entry(word.to_owned()).or_default().push(note_id);
+        self.note_words.insert(note_id, word.to_owned());
+        orphaned.filter(|orphaned| orphaned != word)
+    }
+
+    /// Removes the note, returning its word if no other note has it
+    fn remove_note(&mut self, note_id: isize) -> Option<String> {
+        let word = self.note_words.remove(&note_id)?;
+        let notes = self.words.get_mut(&word).expect("word to exist");
+        notes.retain(|note| *note != note_id);
+        if notes.is_empty() {
+            self.words.remove(&word);
+            Some(word)
+        } else {
+            None
+        }
+    }
+
+    /// Removes every note that is no longer in the deck, returning the words that were only in
+    /// those notes
+    fn remove_missing(&mut self, found: &HashSet<isize>) -> Vec<String> {
+        let missing: Vec<isize> = self
+            .note_words
+            .keys()
+            .filter(|note| !found.contains(note))
+            .copied()
+            .collect();
+        missing
+            .into_iter()
+            .filter_map(|note| self.remove_note(note))
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct NoteModTime {
+    #[serde(rename = "noteId")]
+    note_id: isize,
+    #[serde(rename = "mod")]
+    modified: i64,
+}
+
+#[derive(Deserialize, Debug)]
+struct CardModTime {
+    #[serde(rename = "cardId")]
+    card_id: isize,
+    #[serde(rename = "mod")]
+    modified: i64,
+}
+
+/// Finds the notes that were added or modified, or that have cards that were modified, since the
+/// deck was last synced
+async fn changed_notes(
+    notes: &[isize],
+    find_query: &str,
+    sync: &DeckSync,
+    client: &reqwest::Client,
+    port: u16,
+) -> Result<Vec<isize>, KalbaError> {
+    let note_times: Vec<NoteModTime> =
+        batched_action("notesModTime", "notes", notes, client, port, |_| ()).await?;
+    let mut changed: HashSet<isize> = note_times
+        .into_iter()
+        .filter(|note| note.modified >= sync.modified)
+        .map(|note| note.note_id)
+        .chain(
+            notes
+                .iter()
+                .copied()
+                .filter(|note| !sync.note_words.contains_key(note)),
+        )
+        .collect();
+
+    let cards =
+        get_card_or_note_vals("findCards", json!({ "query": find_query }), client, port).await?;
+    let card_times: Vec<CardModTime> =
+        batched_action("cardsModTime", "cards", &cards, client, port, |_| ()).await?;
+    let changed_cards: Vec<isize> = card_times
+        .into_iter()
+        .filter(|card| card.modified >= sync.modified)
+        .map(|card| card.card_id)
+        .collect();
+    let card_notes: Vec<isize> = batched_action(
+        "cardsToNotes",
+        "cards",
+        &changed_cards,
+        client,
+        port,
+        |_| (),
+    )
+    .await?;
+    changed.extend(card_notes);
+    Ok(changed.into_iter().collect())
+}
+
+/// Updates the words of the notes in the deck that changed since the last sync. Returns the words
+/// that no note in the deck has anymore, as their notes were removed or edited.
 pub async fn get_anki_card_statuses(
     deck: &str,
     note_handling: &HashMap<String, NoteToWordHandling>,
+    rating_rules: &AnkiRatingRules,
     original_words: &mut HashMap<String, WordInfo>,
-    days_passed: i64,
-    first_time_run: bool,
+    sync: &mut DeckSync,
     port: u16,
-) -> Result<(), KalbaError> {
+    on_progress: &(dyn Fn(AnkiSyncProgress) + Sync),
+) -> Result<Vec<String>, KalbaError> {
     log::info!("getting anki card statuses");
     let client = reqwest::Client::new();
-    let days_passed_query = if first_time_run {
-        String::new()
-    } else {
-        format!("rated:{days_passed}")
-    };
+    let mut found = HashSet::new();
+    let mut removed = Vec::new();
+    let mut modified = sync.modified;
     for (note_type, handler) in note_handling {
-        let find_notes_query = format!(
-            "\"deck:{deck}\" \"note:{note_type}\" {days_passed_query} {}",
+        let find_query = format!(
+            "\"deck:{deck}\" \"note:{note_type}\" {}",
             handler.search_params
         );
-        log::info!("Using query: {find_notes_query}");
-        let notes = get_card_or_note_vals(
-            "findNotes",
-            json!({ "query": find_notes_query }),
-            &client,
-            port,
-        )
-        .await?;
-
-        let notes_info_res =
-            generic_anki_connect_action("notesInfo", json!({ "notes": notes }), &client, port)
+        log::info!("Using query: {find_query}");
+        let notes =
+            get_card_or_note_vals("findNotes", json!({ "query": find_query }), &client, port)
                 .await?;
-        let notes_info = Into::<Result<Vec<NoteInfo>, KalbaError>>::into(
-            notes_info_res
-                .json::<AnkiResult<Vec<NoteInfo>>>()
-                .await
-                .expect("valid json from anki"),
-        )?;
-        for note in &notes_info {
-            let word = get_word_from_note(note, handler).await;
+        found.extend(notes.iter().copied());
+        // Every note is checked the first time the deck is synced
+        let notes = if sync.modified == 0 {
+            notes
+        } else {
+            changed_notes(&notes, &find_query, sync, &client, port).await?
+        };
 
-            let intervals = get_card_or_note_vals(
-                "getIntervals",
-                json!({ "cards": note.cards }),
-                &client,
-                port,
-            )
+        let notes_info: Vec<NoteInfo> =
+            batched_action("notesInfo", "notes", &notes, &client, port, |_| ()).await?;
+        let card_ids: Vec<isize> = notes_info
+            .iter()
+            .flat_map(|note| note.cards.iter().copied())
+            .collect();
+        let total = card_ids.len();
+        log::info!("getting {total} cards of {} notes", notes_info.len());
+        let cards_info: Vec<CardInfo> =
+            batched_action("cardsInfo", "cards", &card_ids, &client, port, |done| {
+                on_progress(AnkiSyncProgress { deck, done, total })
+            })
             .await?;
-            let selected_interval = intervals.iter().max().copied().unwrap_or_default();
-            log::trace!("found word {word} with interval {selected_interval}");
-
-            // TODO: these intervals should be configurable
-            let rating = if selected_interval <= 1 {
-                1
-            } else if selected_interval <= 9 {
-                2
-            } else if selected_interval <= 23 {
-                3
-            } else {
-                4
+        let mut cards_by_note: HashMap<isize, Vec<CardInfo>> = HashMap::new();
+        for card in cards_info {
+            modified = modified.max(card.modified);
+            cards_by_note.entry(card.note).or_default().push(card);
+        }
+
+        for note in &notes_info {
+            modified = modified.max(note.modified);
+            let word = get_word_from_note(note, handler).await;
+            removed.extend(sync.set_word(note.note_id, &word));
+            let cards = cards_by_note.remove(&note.note_id).unwrap_or_default();
+            let Some(rating) = note_rating(&cards, rating_rules) else {
+                continue;
             };
+            log::trace!("found word {word} with rating {rating}");
 
             if let Some(orig) = original_words.get_mut(&word) {
                 if orig.method != Method::Specified {
@@ -105,12 +310,18 @@ pub async fn get_anki_card_statuses(
             }
         }
     }
+    sync.modified = modified;
+    removed.extend(sync.remove_missing(&found));
 
-    Ok(())
+    Ok(removed)
 }
 
 #[derive(Deserialize, Debug)]
 struct NoteInfo {
+    #[serde(rename = "noteId")]
+    note_id: isize,
+    #[serde(rename = "mod", default)]
+    modified: i64,
     fields: HashMap<String, FieldInfo>,
     cards: Vec<isize>,
 }
@@ -120,7 +331,74 @@ struct FieldInfo {
     value: String,
 }
 
-async fn generic_anki_connect_action(
+#[derive(Deserialize, Debug)]
+struct CardInfo {
+    interval: i64,
+    factor: i64,
+    lapses: u32,
+    reps: u32,
+    #[serde(rename = "type")]
+    card_type: i8,
+    queue: i8,
+    ord: u32,
+    note: isize,
+    #[serde(rename = "mod", default)]
+    modified: i64,
+}
+
+impl CardInfo {
+    fn state(&self) -> CardState {
+        match (self.queue, self.card_type) {
+            (-1, _) => CardState::Suspended,
+            (-3 | -2, _) => CardState::Buried,
+            (_, 0) => CardState::New,
+            (_, 1 | 3) => CardState::Learning,
+            _ => CardState::Review,
+        }
+    }
+
+    fn property(&self, property: CardProperty) -> u32 {
+        match property {
+            // Cards that are being learned have negative intervals, in seconds
+            CardProperty::Interval => u32::try_from(self.interval).unwrap_or(0),
+            CardProperty::Ease => u32::try_from(self.factor / 10).unwrap_or(0),
+            CardProperty::Lapses => self.lapses,
+            CardProperty::Reviews => self.reps,
+            CardProperty::CardNumber => self.ord + 1,
+        }
+    }
+
+    fn meets(&self, condition: &CardCondition) -> bool {
+        match condition {
+            CardCondition::AtMost(property, value) => self.property(*property) <= *value,
+            CardCondition::AtLeast(property, value) => self.property(*property) >= *value,
+            CardCondition::State(states) => states.contains(&self.state()),
+            CardCondition::Not(condition) => !self.meets(condition),
+        }
+    }
+
+    fn rating(&self, rules: &AnkiRatingRules) -> i8 {
+        rules
+            .rules
+            .iter()
+            .find(|rule| rule.conditions.iter().all(|c| self.meets(c)))
+            .map_or(rules.otherwise, |rule| rule.rating)
+    }
+}
+
+/// Combines the ratings of the cards of a note. Returns `None` if none of its cards are used.
+fn note_rating(cards: &[CardInfo], rules: &AnkiRatingRules) -> Option<i8> {
+    let ratings = cards
+        .iter()
+        .filter(|card| rules.cards.is_empty() || rules.cards.contains(&(card.ord + 1)))
+        .map(|card| card.rating(rules));
+    match rules.combine {
+        RatingCombination::Highest => ratings.max(),
+        RatingCombination::Lowest => ratings.min(),
+    }
+}
+
+pub async fn generic_anki_connect_action(
     action: &str,
     data: Value,
     client: &reqwest::Client,
@@ -156,6 +434,20 @@ async fn generic_anki_connect_action(
     }
 }
 
+/// Runs an action that returns nothing, such as `updateNoteFields`
+pub async fn anki_connect_command(
+    action: &str,
+    data: Value,
+    client: &reqwest::Client,
+    port: u16,
+) -> Result<(), KalbaError> {
+    let response = generic_anki_connect_action(action, data, client, port).await?;
+    match response.json::<AnkiResult<Value>>().await?.error {
+        Some(error) => Err(KalbaError::AnkiConnectError(error)),
+        None => Ok(()),
+    }
+}
+
 async fn get_card_or_note_vals(
     action: &str,
     data: Value,
@@ -166,6 +458,60 @@ async fn get_card_or_note_vals(
     res.json::<AnkiResult<Vec<isize>>>().await.unwrap().into()
 }
 
+/// Escapes text so that Anki searches for it literally
+fn escape_search(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        if matches!(c, '\\' | '"' | '*' | '_' | ':') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// A note that is already in Anki
+#[derive(Debug)]
+pub struct ExistingNote {
+    pub id: isize,
+    pub fields: HashMap<String, String>,
+}
+
+/// Finds the notes of the note type in the deck that have the word in the field. The word is read
+/// from the field the same way as when syncing word knowledge.
+pub async fn find_notes_with_word(
+    deck: &str,
+    model: &str,
+    handler: &NoteToWordHandling,
+    word: &str,
+    client: &reqwest::Client,
+    port: u16,
+) -> Result<Vec<ExistingNote>, KalbaError> {
+    let query = format!(
+        "\"deck:{deck}\" \"note:{model}\" \"{}:*{}*\"",
+        handler.field_to_use,
+        escape_search(word)
+    );
+    let notes = get_card_or_note_vals("findNotes", json!({ "query": query }), client, port).await?;
+    let notes_info: Vec<NoteInfo> =
+        batched_action("notesInfo", "notes", &notes, client, port, |_| ()).await?;
+    let mut existing = Vec::new();
+    for note in notes_info {
+        if get_word_from_note(&note, handler).await != word {
+            continue;
+        }
+        existing.push(ExistingNote {
+            id: note.note_id,
+            fields: note
+                .fields
+                .into_iter()
+                .map(|(name, field)| (name, field.value))
+                .collect(),
+        });
+    }
+    Ok(existing)
+}
+
 async fn get_word_from_note(note: &NoteInfo, handler: &NoteToWordHandling) -> String {
     let selected_field = &note
         .fields
@@ -245,12 +591,178 @@ pub async fn get_note_field_names(
 
 #[tauri::command]
 pub async fn remove_deck(deck: String, state: State<'_, KalbaState>) -> Result<(), String> {
-    state
-        .0
-        .lock()
-        .await
-        .to_save
-        .decks_checked
-        .retain(|v| v != &deck);
+    let mut state = state.0.lock().await;
+    let language = state.current_language.clone();
+    if let Some(language) = language.and_then(|l| state.to_save.language_specific.get_mut(&l)) {
+        language.anki_decks.remove(&deck);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use shared::AnkiRatingRule;
+
+    use super::*;
+    use crate::test_server::serve_once;
+
+    fn card(interval: i64, queue: i8, ord: u32) -> CardInfo {
+        CardInfo {
+            interval,
+            factor: 2500,
+            lapses: 0,
+            reps: 5,
+            card_type: if interval == 0 { 0 } else { 2 },
+            queue,
+            ord,
+            note: 1,
+            modified: 0,
+        }
+    }
+
+    fn ratings(intervals: &[i64], rules: &AnkiRatingRules) -> Vec<i8> {
+        intervals
+            .iter()
+            .map(|interval| card(*interval, 2, 0).rating(rules))
+            .collect()
+    }
+
+    #[test]
+    fn default_rules_use_intervals() {
+        assert_eq!(
+            ratings(
+                &[-600, 0, 1, 2, 9, 10, 23, 24, 300],
+                &AnkiRatingRules::default()
+            ),
+            vec![1, 1, 1, 2, 2, 3, 3, 4, 4]
+        );
+        let cards = [card(3, 2, 0), card(40, 2, 1)];
+        assert_eq!(note_rating(&cards, &AnkiRatingRules::default()), Some(4));
+    }
+
+    #[test]
+    fn rules_are_checked_in_order() {
+        let mut rules = AnkiRatingRules::default();
+        rules.rules.insert(
+            0,
+            AnkiRatingRule {
+                conditions: vec![CardCondition::State(vec![CardState::Suspended])],
+                rating: -1,
+            },
+        );
+        rules.rules.insert(
+            1,
+            AnkiRatingRule {
+                conditions: vec![
+                    CardCondition::AtLeast(CardProperty::Lapses, 8),
+                    CardCondition::Not(Box::new(CardCondition::AtMost(CardProperty::Ease, 200))),
+                ],
+                rating: 1,
+            },
+        );
+        let leech = CardInfo {
+            lapses: 9,
+            ..card(30, 2, 0)
+        };
+        assert_eq!(card(30, -1, 0).rating(&rules), -1);
+        assert_eq!(leech.rating(&rules), 1);
+        assert_eq!(
+            CardInfo {
+                factor: 1300,
+                ..leech
+            }
+            .rating(&rules),
+            4
+        );
+    }
+
+    #[test]
+    fn cards_of_a_note_are_combined() {
+        let cards = [card(3, 2, 0), card(40, 2, 1), card(0, 0, 2)];
+        let mut rules = AnkiRatingRules {
+            combine: RatingCombination::Lowest,
+            ..Default::default()
+        };
+        assert_eq!(note_rating(&cards, &rules), Some(1));
+        rules.cards = vec![1, 2];
+        assert_eq!(note_rating(&cards, &rules), Some(2));
+        rules.cards = vec![4];
+        assert_eq!(note_rating(&cards, &rules), None);
+    }
+
+    #[test]
+    fn removed_notes_remove_their_words() {
+        let mut sync = DeckSync::default();
+        sync.set_word(1, "labas");
+        sync.set_word(2, "labas");
+        sync.set_word(3, "rytas");
+        sync.set_word(4, "vakaras");
+        // The word of a note was changed
+        assert_eq!(sync.set_word(4, "diena"), Some(String::from("vakaras")));
+        assert!(!sync.words.contains_key("vakaras"));
+
+        let mut removed = sync.remove_missing(&HashSet::from([2, 4]));
+        removed.sort();
+        assert_eq!(removed, vec![String::from("rytas")]);
+        assert_eq!(sync.words["labas"], vec![2]);
+
+        // The notes of each word are found again after loading
+        let mut sync: DeckSync = serde_json::from_value(json!(sync)).unwrap();
+        assert_eq!(sync.remove_missing(&HashSet::new()).len(), 2);
+        assert!(sync.words.is_empty());
+    }
+
+    #[test]
+    fn edited_notes_return_their_old_word() {
+        let mut sync = DeckSync::default();
+        assert_eq!(sync.set_word(1, "labas"), None);
+        assert_eq!(sync.set_word(2, "labas"), None);
+        // Syncing a note again without changing its word keeps the word
+        assert_eq!(sync.set_word(1, "labas"), None);
+        // Another note still has the old word
+        assert_eq!(sync.set_word(1, "rytas"), None);
+        assert_eq!(sync.set_word(2, "vakaras"), Some(String::from("labas")));
+        assert!(!sync.words.contains_key("labas"));
+        assert_eq!(sync.words["rytas"], vec![1]);
+        assert_eq!(sync.words["vakaras"], vec![2]);
+    }
+
+    #[test]
+    fn actions_are_batched_with_multi() {
+        let (url, request) = serve_once(
+            "application/json",
+            r#"{"result": [{"result": [{"interval": 3, "factor": 2500, "lapses": 0, "reps": 2, "type": 2, "queue": 2, "ord": 0, "note": 7}], "error": null}], "error": null}"#,
+        );
+        let port = url.rsplit(':').next().unwrap().parse().unwrap();
+        let progress = RefCell::new(Vec::new());
+        let cards: Vec<CardInfo> = tauri::async_runtime::block_on(batched_action(
+            "cardsInfo",
+            "cards",
+            &[11],
+            &reqwest::Client::new(),
+            port,
+            |done| progress.borrow_mut().push(done),
+        ))
+        .unwrap();
+        assert_eq!(cards.len(), 1);
+        assert_eq!(cards[0].note, 7);
+        assert_eq!(progress.into_inner(), vec![1]);
+
+        let request = request.join().unwrap();
+        let (_, body) = request.split_once("\r\n\r\n").unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(body).unwrap(),
+            json!({
+                "action": "multi",
+                "version": 6,
+                "params": {
+                    "actions": [
+                        { "action": "cardsInfo", "version": 6, "params": { "cards": [11] } }
+                    ]
+                }
+            })
+        );
+    }
+}
@@ -0,0 +1,467 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::Write,
+    path::Path,
+};
+
+use chrono::Utc;
+use rusqlite::{params, Connection};
+use serde_json::json;
+use sha1::{Digest, Sha1};
+use shared::{ApkgSettings, ToasterPayload};
+use tauri::{Emitter, State, Window};
+use zip::{write::SimpleFileOptions, ZipWriter};
+
+use crate::{
+    add_to_anki::{remove_unused_media, render_note, ExportDetails, RenderedNote},
+    template::strip_html,
+    KalbaError, KalbaState,
+};
+
+/// The tables of an Anki collection, in the format that every Anki version can import
+const SCHEMA: &str = "
+CREATE TABLE col (
+    id integer primary key, crt integer not null, mod integer not null,
+    scm integer not null, ver integer not null, dty integer not null,
+    usn integer not null, ls integer not null, conf text not null,
+    models text not null, decks text not null, dconf text not null, tags text not null
+);
+CREATE TABLE notes (
+    id integer primary key, guid text not null, mid integer not null,
+    mod integer not null, usn integer not null, tags text not null,
+    flds text not null, sfld integer not null, csum integer not null,
+    flags integer not null, data text not null
+);
+CREATE TABLE cards (
+    id integer primary key, nid integer not null, did integer not null,
+    ord integer not null, mod integer not null, usn integer not null,
+    type integer not null, queue integer not null, due integer not null,
+    ivl integer not null, factor integer not null, reps integer not null,
+    lapses integer not null, left integer not null, odue integer not null,
+    odid integer not null, flags integer not null, data text not null
+);
+CREATE TABLE revlog (
+    id integer primary key, cid integer not null, usn integer not null,
+    ease integer not null, ivl integer not null, lastIvl integer not null,
+    factor integer not null, time integer not null, type integer not null
+);
+CREATE TABLE graves (usn integer not null, oid integer not null, type integer not null);
+CREATE INDEX ix_notes_usn on notes (usn);
+CREATE INDEX ix_cards_usn on cards (usn);
+CREATE INDEX ix_revlog_usn on revlog (usn);
+CREATE INDEX ix_cards_nid on cards (nid);
+CREATE INDEX ix_cards_sched on cards (did, queue, due);
+CREATE INDEX ix_revlog_cid on revlog (cid);
+CREATE INDEX ix_notes_csum on notes (csum);
+";
+
+/// The first 32 bits of the SHA-1 hash of the text, which is what Anki uses to find duplicates
+fn checksum(text: &str) -> u32 {
+    let hash = Sha1::digest(text.as_bytes());
+    u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]])
+}
+
+/// Ids of decks and note types are based on their names, so that exporting to the same deck again
+/// doesn't create a new one
+fn id_from_name(name: &str) -> i64 {
+    (1 << 30) + i64::from(checksum(name)) % (1 << 30)
+}
+
+/// The fields of the note type, which are the fields from the settings followed by any other
+/// fields the notes use
+fn note_type_fields(settings: &ApkgSettings, notes: &[RenderedNote]) -> Vec<String> {
+    let mut extra: Vec<&String> = notes
+        .iter()
+        .flat_map(|note| note.fields.keys())
+        .filter(|field| !settings.fields.contains(field))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    extra.sort();
+    settings.fields.iter().chain(extra).cloned().collect()
+}
+
+fn write_collection(
+    file: &Path,
+    settings: &ApkgSettings,
+    notes: &[RenderedNote],
+) -> Result<(), KalbaError> {
+    let now = Utc::now();
+    let modified = now.timestamp();
+    let deck_id = id_from_name(&settings.deck);
+    let model_id = id_from_name(&settings.note_type);
+    let fields = note_type_fields(settings, notes);
+
+    let model = json!({
+        "id": model_id,
+        "name": settings.note_type,
+        "type": 0,
+        "mod": modified,
+        "usn": -1,
+        "sortf": 0,
+        "did": deck_id,
+        "tmpls": [{
+            "name": "Card 1",
+            "ord": 0,
+            "qfmt": settings.front,
+            "afmt": settings.back,
+            "did": null,
+            "bqfmt": "",
+            "bafmt": "",
+        }],
+        "flds": fields.iter().enumerate().map(|(ord, name)| json!({
+            "name": name,
+            "ord": ord,
+            "sticky": false,
+            "rtl": false,
+            "font": "Arial",
+            "size": 20,
+            "media": [],
+        })).collect::<Vec<_>>(),
+        "css": settings.css,
+        "latexPre": "\\documentclass[12pt]{article}\n\\special{papersize=3in,5in}\n\\usepackage[utf8]{inputenc}\n\\usepackage{amssymb,amsmath}\n\\pagestyle{empty}\n\\setlength{\\parindent}{0in}\n\\begin{document}\n",
+        "latexPost": "\\end{document}",
+        "latexsvg": false,
+        "req": [[0, "any", (0..fields.len()).collect::<Vec<_>>()]],
+        "tags": [],
+        "vers": [],
+    });
+    let deck = |id: i64, name: &str| {
+        json!({
+            "id": id,
+            "name": name,
+            "desc": "",
+            "mod": modified,
+            "usn": -1,
+            "collapsed": false,
+            "browserCollapsed": false,
+            "conf": 1,
+            "dyn": 0,
+            "extendNew": 0,
+            "extendRev": 0,
+            "newToday": [0, 0],
+            "revToday": [0, 0],
+            "lrnToday": [0, 0],
+            "timeToday": [0, 0],
+        })
+    };
+    let deck_options = json!({
+        "id": 1,
+        "name": "Default",
+        "mod": 0,
+        "usn": 0,
+        "maxTaken": 60,
+        "autoplay": true,
+        "timer": 0,
+        "replayq": true,
+        "dyn": false,
+        "new": {
+            "bury": true,
+            "delays": [1.0, 10.0],
+            "initialFactor": 2500,
+            "ints": [1, 4, 7],
+            "order": 1,
+            "perDay": 20,
+            "separate": true,
+        },
+        "lapse": {
+            "delays": [10.0],
+            "leechAction": 0,
+            "leechFails": 8,
+            "minInt": 1,
+            "mult": 0.0,
+        },
+        "rev": {
+            "bury": true,
+            "ease4": 1.3,
+            "fuzz": 0.05,
+            "ivlFct": 1.0,
+            "maxIvl": 36500,
+            "minSpace": 1,
+            "perDay": 100,
+        },
+    });
+    let conf = json!({
+        "activeDecks": [1],
+        "curDeck": 1,
+        "newSpread": 0,
+        "collapseTime": 1200,
+        "timeLim": 0,
+        "estTimes": true,
+        "dueCounts": true,
+        "curModel": null,
+        "nextPos": notes.len() + 1,
+        "sortType": "noteFld",
+        "sortBackwards": false,
+        "addToCur": true,
+    });
+
+    let connection = Connection::open(file)?;
+    connection.execute_batch(SCHEMA)?;
+    connection.execute(
+        "INSERT INTO col VALUES (1, ?1, ?2, ?3, 11, 0, 0, 0, ?4, ?5, ?6, ?7, '{}')",
+        params![
+            modified,
+            now.timestamp_millis(),
+            now.timestamp_millis(),
+            conf.to_string(),
+            json!({ model_id.to_string(): model }).to_string(),
+            json!({
+                "1": deck(1, "Default"),
+                deck_id.to_string(): deck(deck_id, &settings.deck),
+            })
+            .to_string(),
+            json!({ "1": deck_options }).to_string(),
+        ],
+    )?;
+
+    let first_id = now.timestamp_millis();
+    for (i, note) in notes.iter().enumerate() {
+        let values: Vec<&str> = fields
+            .iter()
+            .map(|field| note.fields.get(field).map_or("", String::as_str))
+            .collect();
+        let sort_field = strip_html(values.first().copied().unwrap_or_default());
+        let id = first_id + i as i64;
+        connection.execute(
+            "INSERT INTO notes VALUES (?1, ?2, ?3, ?4, -1, ' kalba ', ?5, ?6, ?7, 0, '')",
+            params![
+                id,
+                // Notes with the same guid replace each other when importing, so exporting a
+                // word again updates its note
+                format!(
+                    "{:x}",
+                    checksum(&format!("{}\u{1f}{sort_field}", settings.note_type))
+                ),
+                model_id,
+                modified,
+                values.join("\u{1f}"),
+                sort_field,
+                checksum(&sort_field),
+            ],
+        )?;
+        connection.execute(
+            "INSERT INTO cards VALUES (?1, ?2, ?3, 0, ?4, -1, 0, 0, ?5, 0, 0, 0, 0, 0, 0, 0, 0, '')",
+            params![id, id, deck_id, modified, i + 1],
+        )?;
+    }
+    connection.close().map_err(|(_, e)| e)?;
+    Ok(())
+}
+
+/// Writes the notes to an Anki package, which can be imported into any version of Anki
+pub fn write_apkg(
+    path: &Path,
+    settings: &ApkgSettings,
+    notes: &[RenderedNote],
+) -> Result<(), KalbaError> {
+    let collection = std::env::temp_dir().join(format!(
+        "kalba_{}.anki2",
+        Utc::now().timestamp_nanos_opt().unwrap_or_default()
+    ));
+    let written = write_collection(&collection, settings, notes);
+    let contents = written.and_then(|_| Ok(fs::read(&collection)?));
+    let _ = fs::remove_file(&collection);
+    let contents = contents?;
+
+    let mut zip = ZipWriter::new(File::create(path)?);
+    let options = SimpleFileOptions::default();
+    zip.start_file("collection.anki2", options)?;
+    zip.write_all(&contents)?;
+
+    // Media files are numbered, with a map from their numbers to their names
+    let mut media_names = HashMap::new();
+    for file in notes.iter().flat_map(|note| &note.media) {
+        if media_names.values().any(|name| name == &file.name) {
+            continue;
+        }
+        let number = media_names.len().to_string();
+        zip.start_file(number.as_str(), options)?;
+        zip.write_all(&file.read()?)?;
+        media_names.insert(number, file.name.clone());
+    }
+    zip.start_file("media", options)?;
+    zip.write_all(json!(media_names).to_string().as_bytes())?;
+    zip.finish()?;
+    Ok(())
+}
+
+/// Adds the word to the words waiting to be exported to an Anki package
+#[tauri::command]
+pub async fn queue_for_apkg(
+    export_details: ExportDetails<'_>,
+    state: State<'_, KalbaState>,
+    window: Window,
+) -> Result<(), KalbaError> {
+    let mut note = render_note(&state, export_details).await?;
+    note.media = note
+        .media
+        .into_iter()
+        .map(|file| file.persist())
+        .collect::<Result<_, _>>()?;
+    let word = note.word.clone();
+    let mut state = state.0.lock().await;
+    let current_language = state.current_language.clone().expect("language to exist");
+    let language = state
+        .to_save
+        .language_specific
+        .get_mut(&current_language)
+        .expect("language to exist");
+    language.apkg_queue.push(note);
+    language.added_to_anki.push((Utc::now(), word.clone()));
+    window.emit(
+        "added_to_anki",
+        Some(ToasterPayload {
+            message: Some(&format!("Added {word} to the words to export")),
+        }),
+    )?;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_apkg_queue(
+    state: State<'_, KalbaState>,
+    language: String,
+) -> Result<Vec<RenderedNote>, KalbaError> {
+    Ok(state
+        .0
+        .lock()
+        .await
+        .to_save
+        .language_specific
+        .get(&language)
+        .map(|language| language.apkg_queue.clone())
+        .unwrap_or_default())
+}
+
+/// Removes a note from the words waiting to be exported, along with its media
+#[tauri::command]
+pub async fn remove_from_apkg_queue(
+    state: State<'_, KalbaState>,
+    language: String,
+    index: usize,
+) -> Result<(), KalbaError> {
+    let mut state = state.0.lock().await;
+    let Some(queue) = state
+        .to_save
+        .language_specific
+        .get_mut(&language)
+        .map(|language| &mut language.apkg_queue)
+    else {
+        return Ok(());
+    };
+    if index < queue.len() {
+        let note = queue.remove(index);
+        remove_unused_media(&note, &state.to_save);
+    }
+    Ok(())
+}
+
+/// Exports every word waiting to be exported to an Anki package, returning how many there were
+#[tauri::command]
+pub async fn export_apkg(
+    state: State<'_, KalbaState>,
+    language: String,
+    file_path: String,
+) -> Result<usize, KalbaError> {
+    let mut state = state.0.lock().await;
+    let settings = state
+        .settings
+        .languages
+        .get(&language)
+        .expect("language to exist")
+        .apkg
+        .clone();
+    let Some(language) = state.to_save.language_specific.get_mut(&language) else {
+        return Err(KalbaError::NothingToExport);
+    };
+    if language.apkg_queue.is_empty() {
+        return Err(KalbaError::NothingToExport);
+    }
+    write_apkg(Path::new(&file_path), &settings, &language.apkg_queue)?;
+    let exported = std::mem::take(&mut language.apkg_queue);
+    for note in &exported {
+        remove_unused_media(note, &state.to_save);
+    }
+    Ok(exported.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::add_to_anki::{MediaContents, MediaFile};
+
+    #[test]
+    fn package_contents() {
+        let note = |word: &str, media: Vec<MediaFile>| RenderedNote {
+            word: word.to_owned(),
+            deck: String::new(),
+            model: String::new(),
+            fields: HashMap::from([
+                (String::from("Front"), format!("<b>{word}</b>")),
+                (String::from("Back"), String::from("hello")),
+                (String::from("Audio"), String::from("[sound:labas.mp3]")),
+            ]),
+            media,
+            created: Utc::now(),
+        };
+        let sound = MediaFile {
+            name: String::from("labas.mp3"),
+            contents: MediaContents::Data(String::from("SUQz")),
+        };
+        let notes = [
+            note("labas", vec![sound.clone()]),
+            note("rytas", vec![sound]),
+        ];
+        let settings = ApkgSettings::default();
+        let package = std::env::temp_dir().join("kalba_test.apkg");
+        write_apkg(&package, &settings, &notes).unwrap();
+
+        let mut zip = zip::ZipArchive::new(File::open(&package).unwrap()).unwrap();
+        let media: HashMap<String, String> =
+            serde_json::from_reader(zip.by_name("media").unwrap()).unwrap();
+        assert_eq!(
+            media,
+            HashMap::from([(String::from("0"), String::from("labas.mp3"))])
+        );
+        let collection = std::env::temp_dir().join("kalba_test.anki2");
+        std::io::copy(
+            &mut zip.by_name("collection.anki2").unwrap(),
+            &mut File::create(&collection).unwrap(),
+        )
+        .unwrap();
+        fs::remove_file(package).unwrap();
+
+        let connection = Connection::open(&collection).unwrap();
+        let mut statement = connection
+            .prepare("SELECT flds, sfld, csum FROM notes ORDER BY id")
+            .unwrap();
+        let rows: Vec<(String, String, u32)> = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            rows[0],
+            (
+                String::from("<b>labas</b>\u{1f}hello\u{1f}[sound:labas.mp3]"),
+                String::from("labas"),
+                // The first 8 hex digits of the SHA-1 hash of "labas"
+                0x6cc9a313,
+            )
+        );
+        assert_eq!(rows.len(), 2);
+        let cards: i64 = connection
+            .query_row(
+                "SELECT count(*) FROM cards WHERE did = ?1",
+                [id_from_name("Kalba")],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(cards, 2);
+        drop(statement);
+        connection.close().unwrap();
+        fs::remove_file(collection).unwrap();
+    }
+}
@@ -0,0 +1,510 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, Read, Write},
+    path::PathBuf,
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use shared::{CacheSettings, Definition};
+use tauri::State;
+
+use crate::{KalbaError, KalbaState};
+
+const SNAPSHOT_FILE: &str = "definitions";
+const LOG_FILE: &str = "definitions.log";
+/// The cache file used before definitions were stored per dictionary
+const LEGACY_FILE: &str = "dictionaries";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct CachedDefinition {
+    definition: Definition,
+    fetched: DateTime<Utc>,
+}
+
+/// language -> dictionary -> lemma
+type CachedLanguages = HashMap<String, HashMap<String, HashMap<String, CachedDefinition>>>;
+
+#[derive(Deserialize)]
+struct LegacyCachedData {
+    definitions: HashMap<String, HashMap<String, Definition>>,
+}
+
+/// Every change to the cache is appended to the log so that it is not lost if Kalba crashes. The
+/// log is replayed and merged into the snapshot when the cache is opened.
+#[derive(Serialize, Deserialize)]
+enum CacheChange {
+    Insert {
+        language: String,
+        dictionary: String,
+        lemma: String,
+        definition: CachedDefinition,
+    },
+    RemoveDictionary {
+        language: String,
+        dictionary: String,
+    },
+    RemoveLanguage {
+        language: String,
+    },
+    RenameLanguage {
+        from: String,
+        to: String,
+    },
+}
+
+#[derive(Default)]
+pub struct DefinitionCache {
+    languages: CachedLanguages,
+    settings: CacheSettings,
+    directory: Option<PathBuf>,
+    log: Option<File>,
+}
+
+#[derive(Serialize)]
+pub struct DictionaryCacheInfo {
+    language: String,
+    dictionary: String,
+    entries: usize,
+    oldest: Option<DateTime<Utc>>,
+}
+
+impl DefinitionCache {
+    /// Loads the cache saved in `directory`, replaying any changes that were not compacted
+    pub fn open(directory: PathBuf, settings: CacheSettings) -> Result<Self, KalbaError> {
+        let mut cache = Self {
+            settings,
+            ..Default::default()
+        };
+        match fs::read(directory.join(SNAPSHOT_FILE)) {
+            Ok(snapshot) => match rmp_serde::from_slice(&snapshot) {
+                Ok(languages) => cache.languages = languages,
+                Err(e) => log::warn!("Unable to read definition cache, starting over: {e}"),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => cache.read_legacy(&directory),
+            Err(e) => return Err(e.into()),
+        }
+        match File::open(directory.join(LOG_FILE)) {
+            Ok(log) => cache.replay(BufReader::new(log)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+        cache.directory = Some(directory);
+        cache.compact()?;
+        Ok(cache)
+    }
+
+    fn read_legacy(&mut self, directory: &std::path::Path) {
+        let Ok(contents) = fs::read(directory.join(LEGACY_FILE)) else {
+            return;
+        };
+        match rmp_serde::from_slice::<HashMap<String, LegacyCachedData>>(&contents) {
+            Ok(legacy) => {
+                let fetched = Utc::now();
+                for (language, data) in legacy {
+                    let dictionaries = self.languages.entry(language).or_default();
+                    for (lemma, definitions) in data.definitions {
+                        for (dictionary, definition) in definitions {
+                            dictionaries.entry(dictionary).or_default().insert(
+                                lemma.clone(),
+                                CachedDefinition {
+                                    definition,
+                                    fetched,
+                                },
+                            );
+                        }
+                    }
+                }
+            }
+            Err(e) => log::warn!("Unable to read old definition cache: {e}"),
+        }
+        if let Err(e) = fs::remove_file(directory.join(LEGACY_FILE)) {
+            log::warn!("Unable to remove old definition cache: {e}");
+        }
+    }
+
+    fn replay(&mut self, mut log: impl Read) {
+        let mut length = [0; 4];
+        while log.read_exact(&mut length).is_ok() {
+            let length = u32::from_le_bytes(length) as usize;
+            // The length isn't trusted for allocating, as it may come from a corrupted log
+            let mut record = Vec::new();
+            if (&mut log)
+                .take(length as u64)
+                .read_to_end(&mut record)
+                .is_err()
+                || record.len() != length
+            {
+                log::warn!("Definition cache log ends with an incomplete change");
+                break;
+            }
+            match rmp_serde::from_slice(&record) {
+                Ok(change) => self.apply(change),
+                Err(e) => {
+                    log::warn!("Unable to read definition cache log: {e}");
+                    break;
+                }
+            }
+        }
+    }
+
+    fn apply(&mut self, change: CacheChange) {
+        match change {
+            CacheChange::Insert {
+                language,
+                dictionary,
+                lemma,
+                definition,
+            } => {
+                self.languages
+                    .entry(language)
+                    .or_default()
+                    .entry(dictionary)
+                    .or_default()
+                    .insert(lemma, definition);
+            }
+            CacheChange::RemoveDictionary {
+                language,
+                dictionary,
+            } => {
+                if let Some(dictionaries) = self.languages.get_mut(&language) {
+                    dictionaries.remove(&dictionary);
+                }
+            }
+            CacheChange::RemoveLanguage { language } => {
+                self.languages.remove(&language);
+            }
+            CacheChange::RenameLanguage { from, to } => {
+                if let Some(dictionaries) = self.languages.remove(&from) {
+                    self.languages.insert(to, dictionaries);
+                }
+            }
+        }
+    }
+
+    /// Applies the change and appends it to the log. The cache can always be refetched, so
+    /// failing to write the log is not treated as an error.
+    fn record(&mut self, change: CacheChange) {
+        if let Some(log) = &mut self.log {
+            let record = rmp_serde::to_vec_named(&change).expect("cache change to be serializable");
+            let mut bytes = (record.len() as u32).to_le_bytes().to_vec();
+            bytes.extend(record);
+            if let Err(e) = log.write_all(&bytes) {
+                log::warn!("Unable to write to definition cache log: {e}");
+            }
+        }
+        self.apply(change);
+    }
+
+    fn is_expired(&self, definition: &CachedDefinition) -> bool {
+        self.settings.max_age_days != 0
+            && Utc::now() - definition.fetched
+                > TimeDelta::days(i64::from(self.settings.max_age_days))
+    }
+
+    pub fn get(&self, language: &str, dictionary: &str, lemma: &str) -> Option<&Definition> {
+        self.languages
+            .get(language)?
+            .get(dictionary)?
+            .get(lemma)
+            .filter(|cached| !self.is_expired(cached))
+            .map(|cached| &cached.definition)
+    }
+
+    /// Caches a definition that was fetched from a dictionary. Errors and definitions that are
+    /// only fetched on demand are not cached.
+    pub fn insert(
+        &mut self,
+        language: &str,
+        dictionary: &str,
+        lemma: &str,
+        definition: Definition,
+    ) {
+        if matches!(definition, Definition::OnDemand(_) | Definition::Error(_)) {
+            return;
+        }
+        self.record(CacheChange::Insert {
+            language: language.to_owned(),
+            dictionary: dictionary.to_owned(),
+            lemma: lemma.to_owned(),
+            definition: CachedDefinition {
+                definition,
+                fetched: Utc::now(),
+            },
+        });
+        // Pruning is done in batches so that it doesn't happen on every insert
+        if self.len() > self.settings.max_entries + self.settings.max_entries / 10 + 1 {
+            if let Err(e) = self.compact() {
+                log::warn!("Unable to compact definition cache: {e}");
+            }
+        }
+    }
+
+    pub fn remove_dictionary(&mut self, language: &str, dictionary: &str) {
+        self.record(CacheChange::RemoveDictionary {
+            language: language.to_owned(),
+            dictionary: dictionary.to_owned(),
+        });
+    }
+
+    pub fn remove_language(&mut self, language: &str) {
+        self.record(CacheChange::RemoveLanguage {
+            language: language.to_owned(),
+        });
+    }
+
+    pub fn rename_language(&mut self, from: &str, to: &str) {
+        self.record(CacheChange::RenameLanguage {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        });
+    }
+
+    pub fn languages(&self) -> impl Iterator<Item = &String> {
+        self.languages.keys()
+    }
+
+    pub fn dictionaries(&self, language: &str) -> impl Iterator<Item = &String> {
+        self.languages
+            .get(language)
+            .into_iter()
+            .flat_map(HashMap::keys)
+    }
+
+    pub fn set_settings(&mut self, settings: CacheSettings) {
+        self.settings = settings;
+    }
+
+    fn len(&self) -> usize {
+        self.languages
+            .values()
+            .flat_map(HashMap::values)
+            .map(HashMap::len)
+            .sum()
+    }
+
+    /// Removes expired definitions, then the oldest definitions until the cache is small enough
+    fn prune(&mut self) {
+        let mut languages = std::mem::take(&mut self.languages);
+        for dictionaries in languages.values_mut() {
+            for definitions in dictionaries.values_mut() {
+                definitions.retain(|_, cached| !self.is_expired(cached));
+            }
+            dictionaries.retain(|_, definitions| !definitions.is_empty());
+        }
+        languages.retain(|_, dictionaries| !dictionaries.is_empty());
+        self.languages = languages;
+
+        let length = self.len();
+        if length <= self.settings.max_entries {
+            return;
+        }
+        let mut by_age: Vec<(DateTime<Utc>, String, String, String)> = self
+            .languages
+            .iter()
+            .flat_map(|(language, dictionaries)| {
+                dictionaries
+                    .iter()
+                    .flat_map(move |(dictionary, definitions)| {
+                        definitions.iter().map(move |(lemma, cached)| {
+                            (
+                                cached.fetched,
+                                language.clone(),
+                                dictionary.clone(),
+                                lemma.clone(),
+                            )
+                        })
+                    })
+            })
+            .collect();
+        by_age.sort_unstable();
+        for (_, language, dictionary, lemma) in
+            by_age.into_iter().take(length - self.settings.max_entries)
+        {
+            if let Some(definitions) = self
+                .languages
+                .get_mut(&language)
+                .and_then(|dictionaries| dictionaries.get_mut(&dictionary))
+            {
+                definitions.remove(&lemma);
+            }
+        }
+    }
+
+    /// Writes the whole cache to disk and empties the log
+    pub fn compact(&mut self) -> Result<(), KalbaError> {
+        self.prune();
+        let Some(directory) = &self.directory else {
+            return Ok(());
+        };
+        fs::create_dir_all(directory)?;
+        let temporary = directory.join(format!("{SNAPSHOT_FILE}.tmp"));
+        fs::write(
+            &temporary,
+            rmp_serde::to_vec_named(&self.languages).expect("error serializing cache"),
+        )?;
+        fs::rename(temporary, directory.join(SNAPSHOT_FILE))?;
+        self.log = Some(
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(directory.join(LOG_FILE))?,
+        );
+        Ok(())
+    }
+
+    pub fn info(&self) -> Vec<DictionaryCacheInfo> {
+        let mut info: Vec<DictionaryCacheInfo> = self
+            .languages
+            .iter()
+            .flat_map(|(language, dictionaries)| {
+                dictionaries
+                    .iter()
+                    .map(move |(dictionary, definitions)| DictionaryCacheInfo {
+                        language: language.clone(),
+                        dictionary: dictionary.clone(),
+                        entries: definitions.len(),
+                        oldest: definitions.values().map(|cached| cached.fetched).min(),
+                    })
+            })
+            .collect();
+        info.sort_unstable_by(|a, b| {
+            (&a.language, &a.dictionary).cmp(&(&b.language, &b.dictionary))
+        });
+        info
+    }
+}
+
+#[tauri::command]
+pub async fn get_cache_info(
+    state: State<'_, KalbaState>,
+) -> Result<Vec<DictionaryCacheInfo>, KalbaError> {
+    Ok(state.0.lock().await.definition_cache.info())
+}
+
+/// Clears the cached definitions of a dictionary, a language, or everything if neither is given
+#[tauri::command]
+pub async fn clear_cache(
+    state: State<'_, KalbaState>,
+    language: Option<String>,
+    dictionary: Option<String>,
+) -> Result<(), KalbaError> {
+    let mut state = state.0.lock().await;
+    let cache = &mut state.definition_cache;
+    match (language, dictionary) {
+        (Some(language), Some(dictionary)) => cache.remove_dictionary(&language, &dictionary),
+        (Some(language), None) => cache.remove_language(&language),
+        _ => {
+            let languages: Vec<String> = cache.languages().cloned().collect();
+            for language in languages {
+                cache.remove_language(&language);
+            }
+        }
+    }
+    cache.compact()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("kalba_cache_test_{name}"));
+        _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn text(definition: &str) -> Definition {
+        Definition::Text(definition.to_owned())
+    }
+
+    fn insert_fetched(cache: &mut DefinitionCache, lemma: &str, days_ago: i64) {
+        cache.apply(CacheChange::Insert {
+            language: String::from("lt"),
+            dictionary: String::from("wiktionary"),
+            lemma: lemma.to_owned(),
+            definition: CachedDefinition {
+                definition: text(lemma),
+                fetched: Utc::now() - TimeDelta::days(days_ago),
+            },
+        });
+    }
+
+    #[test]
+    fn changes_are_kept_without_compacting() {
+        let directory = test_directory("log");
+        let mut cache = DefinitionCache::open(directory.clone(), CacheSettings::default()).unwrap();
+        cache.insert("lt", "wiktionary", "geras", text("good"));
+        cache.insert("lt", "wiktionary", "blogas", text("bad"));
+        cache.insert("lt", "other", "geras", Definition::Empty);
+        cache.insert(
+            "lt",
+            "other",
+            "labas",
+            Definition::Error(String::from("timeout")),
+        );
+        cache.remove_dictionary("lt", "other");
+        // the cache is dropped without compacting, as it would be if Kalba crashed
+        drop(cache);
+
+        let cache = DefinitionCache::open(directory, CacheSettings::default()).unwrap();
+        assert_eq!(cache.get("lt", "wiktionary", "geras"), Some(&text("good")));
+        assert_eq!(cache.get("lt", "wiktionary", "blogas"), Some(&text("bad")));
+        assert_eq!(cache.get("lt", "other", "geras"), None);
+        assert_eq!(cache.get("lt", "other", "labas"), None);
+    }
+
+    #[test]
+    fn incomplete_log_is_ignored() {
+        let directory = test_directory("incomplete");
+        let mut cache = DefinitionCache::open(directory.clone(), CacheSettings::default()).unwrap();
+        cache.insert("lt", "wiktionary", "geras", text("good"));
+        drop(cache);
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(directory.join(LOG_FILE))
+            .unwrap();
+        log.write_all(&[200, 0, 0, 0, 1, 2]).unwrap();
+        drop(log);
+
+        let cache = DefinitionCache::open(directory, CacheSettings::default()).unwrap();
+        assert_eq!(cache.get("lt", "wiktionary", "geras"), Some(&text("good")));
+
+        // A corrupted length isn't allocated up front
+        let mut cache = DefinitionCache::default();
+        cache.replay(&[0xff, 0xff, 0xff, 0xff, 1][..]);
+        assert_eq!(cache.get("lt", "wiktionary", "geras"), None);
+    }
+
+    #[test]
+    fn oldest_definitions_are_removed_first() {
+        let mut cache = DefinitionCache {
+            settings: CacheSettings {
+                max_entries: 2,
+                max_age_days: 0,
+            },
+            ..Default::default()
+        };
+        insert_fetched(&mut cache, "geras", 3);
+        insert_fetched(&mut cache, "blogas", 1);
+        insert_fetched(&mut cache, "labas", 2);
+        cache.compact().unwrap();
+        assert_eq!(cache.get("lt", "wiktionary", "geras"), None);
+        assert_eq!(
+            cache.get("lt", "wiktionary", "blogas"),
+            Some(&text("blogas"))
+        );
+        assert_eq!(cache.get("lt", "wiktionary", "labas"), Some(&text("labas")));
+    }
+
+    #[test]
+    fn expired_definitions_are_not_returned() {
+        let mut cache = DefinitionCache::default();
+        insert_fetched(&mut cache, "geras", 400);
+        assert_eq!(cache.get("lt", "wiktionary", "geras"), None);
+        cache.compact().unwrap();
+        assert!(cache.info().is_empty());
+    }
+}
@@ -1,4 +1,10 @@
-use std::process::{Command, Output};
+use std::{
+    process::{Command, Output, Stdio},
+    time::Duration,
+};
+
+use shared::StartupCommand;
+use tokio::io::AsyncWriteExt;
 
 use crate::KalbaError;
 
@@ -10,6 +16,68 @@ pub fn run_command(real_command: &str) -> Result<Output, KalbaError> {
     }
 }
 
+pub fn run_startup_command(command: &StartupCommand) -> Result<Output, KalbaError> {
+    match command {
+        StartupCommand::Shell(command) => run_command(command),
+        StartupCommand::Args(args) => {
+            let (program, args) = args.split_first().ok_or(KalbaError::EmptyCommand)?;
+            Ok(new_command(program).args(args).output()?)
+        }
+    }
+}
+
+/// Runs a program with the word in its arguments without going through a shell. `{word}` is
+/// replaced in each argument, and the word is also put in the `KALBA_WORD` environment variable
+/// and, if `stdin` is set, written to the program's standard input. Empty arguments are skipped.
+/// Returns what the program printed.
+pub async fn run_with_word(
+    args: &[String],
+    word: &str,
+    stdin: bool,
+    timeout: Duration,
+) -> Result<String, KalbaError> {
+    Ok(String::from_utf8(
+        run_with_word_bytes(args, word, stdin, timeout).await?,
+    )?)
+}
+
+/// Like `run_with_word`, but returns the output as it is, for programs that print audio or other
+/// binary data
+pub async fn run_with_word_bytes(
+    args: &[String],
+    word: &str,
+    stdin: bool,
+    timeout: Duration,
+) -> Result<Vec<u8>, KalbaError> {
+    let args: Vec<&String> = args.iter().filter(|arg| !arg.is_empty()).collect();
+    let (program, args) = args.split_first().ok_or(KalbaError::EmptyCommand)?;
+    let mut command = tokio::process::Command::from(new_command(program));
+    command
+        .args(args.iter().map(|arg| arg.replace("{word}", word)))
+        .env("KALBA_WORD", word)
+        .stdin(if stdin { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    let mut child = command.spawn()?;
+    if let Some(mut input) = child.stdin.take() {
+        input.write_all(word.as_bytes()).await?;
+    }
+
+    let output = tokio::time::timeout(timeout, child.wait_with_output())
+        .await
+        .map_err(|_| KalbaError::Timeout(program.to_string(), timeout.as_secs()))??;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let reason = match stderr.trim() {
+            "" => output.status.to_string(),
+            stderr => stderr.to_owned(),
+        };
+        return Err(KalbaError::CommandFailed(program.to_string(), reason));
+    }
+    Ok(output.stdout)
+}
+
 #[cfg(target_os = "windows")]
 pub fn new_command<S>(executable: S) -> Command
 where
@@ -1,15 +1,43 @@
+use futures::{
+    future::{join_all, BoxFuture, Shared},
+    FutureExt,
+};
 use lol_html::{element, html_content::ContentType, rewrite_str, text, RewriteStrSettings};
 use reqwest::{header::USER_AGENT, Client};
 use select::{
     document::Document,
-    predicate::{self, Attr},
+    node::Node,
+    predicate::{self, Attr, Predicate},
 };
 use serde::{Deserialize, Serialize};
-use shared::{Definition, DefinitionStyling, DictFileType, DictionarySpecificSettings};
-use std::{collections::HashMap, fs, sync::Arc};
-use tauri::State;
+use serde_json_path::JsonPath;
+use shared::{
+    CommandOutput, CommandSettings, Definition, DefinitionEntry, DefinitionStyling, DictFileType,
+    Dictionary, DictionaryRule, DictionarySpecificSettings, Example, HttpMethod,
+    ResponseExtraction, Sense, StructuredDefinition, UrlSettings,
+};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::Arc,
+    time::Duration,
+};
+use tauri::{Emitter, State, Window};
+use tokio::sync::OnceCell;
 
-use crate::{commands::run_command, KalbaError, KalbaState};
+use crate::{
+    commands::run_with_word,
+    kaikki::{get_kaikki, KaikkiIndex},
+    mdict::MDict,
+    personal_dictionary::{personal_definition, PERSONAL_DICTIONARY},
+    prefetch::RateLimiter,
+    pronunciation::RecordingIndex,
+    structured::escape_html,
+    KalbaError, KalbaState, SharedInfo,
+};
 
 #[derive(Default, Clone)]
 pub struct DictionaryInfo {
@@ -18,6 +46,9 @@ pub struct DictionaryInfo {
     dabartines_file: Option<String>,
     ekalba_bendrines: Option<HashMap<String, String>>,
     ekalba_dabartines: Option<HashMap<String, String>>,
+    mdicts: HashMap<String, Arc<OnceCell<Arc<MDict>>>>,
+    pub kaikki_indexes: HashMap<String, Arc<OnceCell<Arc<KaikkiIndex>>>>,
+    pub recording_indexes: HashMap<String, Arc<OnceCell<Arc<RecordingIndex>>>>,
 }
 
 pub enum EkalbaDictionary {
@@ -25,21 +56,32 @@ pub enum EkalbaDictionary {
     Dabartines,
 }
 
+async fn send_request(client: &Client, url: &str) -> Result<reqwest::Response, KalbaError> {
+    Ok(client
+        .get(url)
+        .header(
+            USER_AGENT,
+            "Mozilla/5.0 (X11; Linux x86_64; rv:129.0) Gecko/20100101 Firefox/129.0",
+        )
+        .send()
+        .await?)
+}
+
 impl DictionaryInfo {
-    async fn send_request(&mut self, url: &str) -> reqwest::Response {
-        self.client
-            .get_or_insert_with(Client::new)
-            .get(url)
-            .header(
-                USER_AGENT,
-                "Mozilla/5.0 (X11; Linux x86_64; rv:129.0) Gecko/20100101 Firefox/129.0",
-            )
-            .send()
-            .await
-            .unwrap()
+    /// The client is cheap to clone, so requests can be sent without holding the lock
+    pub fn client(&mut self) -> Client {
+        self.client.get_or_insert_with(Client::new).clone()
+    }
+
+    async fn send_request(&mut self, url: &str) -> Result<reqwest::Response, KalbaError> {
+        send_request(&self.client(), url).await
     }
 
-    async fn get_bendrines(&mut self, dict: EkalbaDictionary, word: &str) -> Option<String> {
+    async fn get_bendrines(
+        &mut self,
+        dict: EkalbaDictionary,
+        word: &str,
+    ) -> Result<Option<String>, KalbaError> {
         let (dict_file, uuid_file) = match dict {
             EkalbaDictionary::Bendrines => (&mut self.bendrines_file, "bendrines_uuids"),
             EkalbaDictionary::Dabartines => (&mut self.dabartines_file, "dabartines_uuids"),
@@ -48,13 +90,13 @@ impl DictionaryInfo {
             f
         } else {
             let path = dirs::data_dir()
-                .unwrap()
+                .ok_or_else(|| KalbaError::MissingDir(String::from("data")))?
                 .join("kalba")
                 .join("language_data")
                 .join(uuid_file);
             if !path.exists() {
-                let contents = self.send_request(&format!("https://raw.githubusercontent.com/BrewingWeasel/kalba/main/data/{uuid_file}")).await.text_with_charset("utf-8").await.unwrap();
-                fs::write(path.clone(), contents).unwrap();
+                let contents = self.send_request(&format!("https://raw.githubusercontent.com/BrewingWeasel/kalba/main/data/{uuid_file}")).await?.text_with_charset("utf-8").await?;
+                fs::write(path.clone(), contents)?;
             };
             let mut_dict_file = match dict {
                 EkalbaDictionary::Bendrines => &mut self.bendrines_file,
@@ -67,17 +109,16 @@ impl DictionaryInfo {
             EkalbaDictionary::Bendrines => &mut self.ekalba_bendrines,
             EkalbaDictionary::Dabartines => &mut self.ekalba_dabartines,
         };
-        dict_map
+        Ok(dict_map
             .get_or_insert_with(|| {
                 let mut words = HashMap::new();
-                for i in file.lines() {
-                    let (cur_word, uuid) = i.split_once('\t').unwrap();
+                for (cur_word, uuid) in file.lines().filter_map(|line| line.split_once('\t')) {
                     words.insert(cur_word.to_owned(), uuid.to_owned());
                 }
                 words
             })
             .get(word)
-            .cloned()
+            .cloned())
     }
 }
 
@@ -117,111 +158,602 @@ fn get_def_from_file(
             }
             Definition::Empty
         })?),
+        DictFileType::MDict => MDict::open(Path::new(file))?.definition(lemma),
+    }
+}
+
+async fn get_mdict(
+    dict_info: Arc<tauri::async_runtime::Mutex<DictionaryInfo>>,
+    lemma: &str,
+    file: &str,
+) -> Result<Definition, KalbaError> {
+    // Reading the headword index is slow for large dictionaries, so it is only done once, and
+    // without holding the lock so that other dictionaries can be used in the meantime
+    let cell = Arc::clone(
+        dict_info
+            .lock()
+            .await
+            .mdicts
+            .entry(file.to_owned())
+            .or_default(),
+    );
+    let mdict = cell
+        .get_or_try_init(|| async {
+            let path = PathBuf::from(file);
+            tauri::async_runtime::spawn_blocking(move || MDict::open(&path))
+                .await
+                .expect("opening task to finish")
+                .map(Arc::new)
+        })
+        .await?;
+    mdict.definition(lemma)
+}
+
+/// Encodes the word so that it can be put anywhere in a url
+fn encode_url_word(word: &str) -> String {
+    url::form_urlencoded::byte_serialize(word.as_bytes())
+        .collect::<String>()
+        .replace('+', "%20")
+}
+
+/// Puts the word in a request body. JSON bodies get the word as the contents of a JSON string and
+/// anything else gets it url encoded.
+fn fill_body(body: &str, word: &str) -> String {
+    let trimmed = body.trim_start();
+    if trimmed.starts_with('{') || trimmed.starts_with('[') {
+        let quoted = serde_json::Value::String(word.to_owned()).to_string();
+        body.replace("{word}", &quoted[1..quoted.len() - 1])
+    } else {
+        body.replace("{word}", &encode_url_word(word))
+    }
+}
+
+fn json_value_to_html(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => escape_html(s),
+        serde_json::Value::Null => String::new(),
+        value => escape_html(&value.to_string()),
+    }
+}
+
+/// Turns one match from the response into html using the template. The template is filled in a
+/// single pass, so braces in the response are kept as they are.
+fn fill_template(template: &str, contents: &str, fields: Option<&serde_json::Value>) -> String {
+    if template.is_empty() {
+        return contents.to_owned();
+    }
+    let field = |name: &str| {
+        if name == "match" {
+            return Some(contents.to_owned());
+        }
+        match fields {
+            Some(serde_json::Value::Object(fields)) => fields.get(name).map(json_value_to_html),
+            _ => None,
+        }
+    };
+    let mut html = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        html.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match rest
+            .find('}')
+            .and_then(|end| Some((field(&rest[1..end])?, end)))
+        {
+            Some((value, end)) => {
+                html.push_str(&value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                html.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    html.push_str(rest);
+    html
+}
+
+fn extract_css(response: &str, selector: &str) -> Result<Vec<String>, KalbaError> {
+    let matches: Rc<RefCell<Vec<String>>> = Rc::default();
+    let (new_matches, match_text) = (Rc::clone(&matches), Rc::clone(&matches));
+    let element_content_handlers = vec![
+        element!(selector, move |_| {
+            new_matches.borrow_mut().push(String::new());
+            Ok(())
+        }),
+        text!(selector, move |t| {
+            if let Some(text) = match_text.borrow_mut().last_mut() {
+                text.push_str(t.as_str());
+            }
+            Ok(())
+        }),
+    ];
+    rewrite_str(
+        response,
+        RewriteStrSettings {
+            element_content_handlers,
+            ..RewriteStrSettings::default()
+        },
+    )?;
+    Ok(matches.take())
+}
+
+fn extract_response(settings: &UrlSettings, response: &str) -> Result<Definition, KalbaError> {
+    let parts: Vec<String> = match &settings.extraction {
+        ResponseExtraction::Whole => vec![fill_template(&settings.template, response, None)],
+        ResponseExtraction::Css(selector) => extract_css(response, selector)?
+            .iter()
+            .map(|text| fill_template(&settings.template, text, None))
+            .collect(),
+        ResponseExtraction::JsonPath(path) => {
+            let path = JsonPath::parse(path)
+                .map_err(|e| KalbaError::InvalidJsonPath(path.clone(), e.to_string()))?;
+            let response: serde_json::Value = serde_json::from_str(response)
+                .map_err(|e| KalbaError::InvalidResponse(settings.url.clone(), e.to_string()))?;
+            path.query(&response)
+                .all()
+                .into_iter()
+                .map(|value| {
+                    fill_template(&settings.template, &json_value_to_html(value), Some(value))
+                })
+                .collect()
+        }
+    };
+    let definition = parts.join("\n");
+    if definition.trim().is_empty() {
+        Ok(Definition::Empty)
+    } else {
+        Ok(Definition::Text(definition))
     }
 }
 
 async fn get_def_url(
+    dict_info: Arc<tauri::async_runtime::Mutex<DictionaryInfo>>,
     lemma: &str,
-    url: &str,
-    embed: bool,
-    selector: &str,
+    settings: &UrlSettings,
 ) -> Result<Definition, KalbaError> {
-    let new_url = url.replacen("{word}", lemma, 1);
-    if embed {
-        Ok(Definition::Text(format!(
+    let new_url = settings.url.replace("{word}", &encode_url_word(lemma));
+    if settings.embed {
+        return Ok(Definition::Text(format!(
             "<iframe class=\"w-full\" src=\"{}\"></iframe>",
             new_url
-        )))
-    } else {
-        let client = reqwest::Client::new();
-        let response = client.get(new_url).send().await?.text().await?;
-        if selector.is_empty() {
-            Ok(Definition::Text(response))
-        } else {
-            let mut text = String::new();
-            let element_content_handlers = vec![text!(&selector, |t| {
-                text.push_str(t.as_str());
-                text.push('\n');
-                Ok(())
-            })];
-            rewrite_str(
-                &response,
-                RewriteStrSettings {
-                    element_content_handlers,
-                    ..RewriteStrSettings::default()
-                },
-            )?;
-            if text.is_empty() {
-                Ok(Definition::Empty)
-            } else {
-                Ok(Definition::Text(text))
+        )));
+    }
+
+    let client = dict_info.lock().await.client();
+    let mut request = match settings.method {
+        HttpMethod::Get => client.get(new_url),
+        HttpMethod::Post => client.post(new_url),
+    };
+    for (name, value) in &settings.headers {
+        request = request.header(name, value);
+    }
+    if !settings.body.is_empty() {
+        request = request.body(fill_body(&settings.body, lemma));
+    }
+    let response = request.send().await?.error_for_status()?.text().await?;
+    extract_response(settings, &response)
+}
+
+async fn get_def_command(
+    lemma: &str,
+    settings: &CommandSettings,
+    timeout: Duration,
+) -> Result<Definition, KalbaError> {
+    let output = run_with_word(&settings.args, lemma, settings.stdin, timeout).await?;
+    if output.trim().is_empty() {
+        return Ok(Definition::Empty);
+    }
+    match settings.output {
+        CommandOutput::Html => Ok(Definition::Text(output)),
+        CommandOutput::Json => {
+            let mut definition: StructuredDefinition =
+                serde_json::from_str(&output).map_err(KalbaError::InvalidCommandOutput)?;
+            if definition.headword.is_empty() {
+                lemma.clone_into(&mut definition.headword);
             }
+            Ok(Definition::Structured(definition))
         }
     }
 }
 
-async fn get_def_command(lemma: &str, cmd: &str) -> Result<Definition, KalbaError> {
-    let real_command = cmd.replacen("{word}", lemma, 1);
-    let output = run_command(&real_command)?;
-    Ok(Definition::Text(String::from_utf8(output.stdout)?))
+#[derive(Clone, Serialize)]
+struct DefinitionFetched<'a> {
+    lemma: &'a str,
+    dictionary: &'a str,
+    definition: &'a Definition,
+}
+
+#[derive(Clone)]
+enum FetchedDefinition {
+    Skipped,
+    Found(Definition),
+}
+
+impl FetchedDefinition {
+    /// Whether the dictionary had something for the word, so dictionaries replacing it are skipped
+    fn found(&self) -> bool {
+        matches!(self, Self::Found(def) if !matches!(def, Definition::Empty | Definition::Error(_)))
+    }
 }
 
+/// What is known about the word being looked up, which dictionary rules can depend on
+#[derive(Default, Clone, Debug)]
+pub struct WordDetails {
+    pub pos: Option<String>,
+    pub morph: HashMap<String, String>,
+}
+
+/// Checks a dictionary rule. `found` has whether each dictionary the rule depends on found a
+/// definition, and dictionaries that are missing from it count as having failed.
+fn rule_applies(
+    rule: &DictionaryRule,
+    lemma: &str,
+    details: &WordDetails,
+    found: &HashMap<&str, bool>,
+) -> bool {
+    match rule {
+        DictionaryRule::PartOfSpeech(tags) => details
+            .pos
+            .as_ref()
+            .is_some_and(|pos| tags.iter().any(|tag| tag.eq_ignore_ascii_case(pos))),
+        DictionaryRule::Feature(name, value) => details.morph.get(name) == Some(value),
+        DictionaryRule::Capitalized => lemma.chars().next().is_some_and(char::is_uppercase),
+        DictionaryRule::AllFailed(dictionaries) => dictionaries
+            .iter()
+            .all(|dictionary| found.get(dictionary.as_str()) != Some(&true)),
+        DictionaryRule::Not(rule) => !rule_applies(rule, lemma, details, found),
+    }
+}
+
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Everything needed to fetch definitions without holding the state lock
+pub struct FetchOptions {
+    pub dict_info: Arc<tauri::async_runtime::Mutex<DictionaryInfo>>,
+    pub definition_styling: DefinitionStyling,
+    pub offline_mode: bool,
+    /// Spaces out requests to each website when fetching many definitions in the background
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+impl FetchOptions {
+    pub fn from_state(state: &SharedInfo) -> Self {
+        Self {
+            dict_info: Arc::clone(&state.dict_info),
+            definition_styling: state.settings.definition_styling.clone(),
+            offline_mode: state.settings.offline_mode,
+            rate_limiter: None,
+        }
+    }
+}
+
+/// The website a network dictionary gets its definitions from
+fn network_provider(dict: &DictionarySpecificSettings) -> Option<String> {
+    match dict {
+        DictionarySpecificSettings::Url(settings) => Some(
+            url::Url::parse(&settings.url)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_owned))
+                .unwrap_or_else(|| settings.url.clone()),
+        ),
+        DictionarySpecificSettings::EkalbaBendrines
+        | DictionarySpecificSettings::EkalbaDabartines => Some(String::from("ekalba.lt")),
+        DictionarySpecificSettings::Wiktionary(definition_lang, _) => {
+            Some(format!("{definition_lang}.wiktionary.org"))
+        }
+        DictionarySpecificSettings::WordReference(..) => Some(String::from("wordreference.com")),
+        _ => None,
+    }
+}
+
+/// Gets the definition from a single dictionary. Network dictionaries are given up on after their
+/// timeout and retried with an increasing delay.
+async fn fetch_definition(
+    options: &FetchOptions,
+    dict: &Dictionary,
+    lemma: &str,
+) -> Result<Definition, KalbaError> {
+    if !dict.specific_settings.is_network() {
+        return get_def(
+            Arc::clone(&options.dict_info),
+            dict,
+            lemma,
+            &options.definition_styling,
+        )
+        .await;
+    }
+    if options.offline_mode {
+        return Err(KalbaError::OfflineMode(dict.name.clone()));
+    }
+
+    let mut attempt = 0;
+    loop {
+        if let (Some(rate_limiter), Some(provider)) = (
+            &options.rate_limiter,
+            network_provider(&dict.specific_settings),
+        ) {
+            rate_limiter.wait(&provider).await;
+        }
+        let result = tokio::time::timeout(
+            Duration::from_secs(dict.timeout),
+            get_def(
+                Arc::clone(&options.dict_info),
+                dict,
+                lemma,
+                &options.definition_styling,
+            ),
+        )
+        .await
+        .unwrap_or_else(|_| Err(KalbaError::Timeout(dict.name.clone(), dict.timeout)));
+        match result {
+            Err(e) if attempt < dict.retries => {
+                log::warn!(
+                    "Attempt {} to get definition from {} failed: {e}",
+                    attempt + 1,
+                    dict.name
+                );
+                tokio::time::sleep(RETRY_BACKOFF * 2_u32.saturating_pow(attempt)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Fetches the definitions from every dictionary at the same time. Dictionaries with
+/// `run_when_not` set wait for the named dictionary and are skipped if it found a definition.
+/// Dictionaries whose rules are not met are skipped, and rules that depend on other dictionaries
+/// wait for them first. Only dictionaries listed earlier can be depended on.
+/// Dictionaries that fail are returned as `Definition::Error` so the rest can still be shown.
+pub async fn fetch_definitions<F>(
+    dicts: &[Dictionary],
+    cached: &HashMap<String, Definition>,
+    options: &FetchOptions,
+    lemma: &str,
+    details: &WordDetails,
+    on_fetched: F,
+) -> HashMap<String, Definition>
+where
+    F: Fn(&str, &Definition) + Sync,
+{
+    let on_fetched = &on_fetched;
+    let mut fetches: HashMap<&str, Shared<BoxFuture<'_, FetchedDefinition>>> = HashMap::new();
+    let mut ordered_fetches = Vec::new();
+
+    for dict in dicts {
+        let dependencies: Vec<_> = dict
+            .run_when_not
+            .iter()
+            .map(String::as_str)
+            .chain(dict.rules.iter().flat_map(DictionaryRule::dictionaries))
+            .filter_map(|name| Some((name, fetches.get(name)?.clone())))
+            .collect();
+        let fetch = async move {
+            let mut found = HashMap::new();
+            for (name, dependency) in dependencies {
+                found.insert(name, dependency.await.found());
+            }
+            let replaced = dict
+                .run_when_not
+                .as_ref()
+                .is_some_and(|required| found.get(required.as_str()) == Some(&true));
+            if replaced
+                || !dict
+                    .rules
+                    .iter()
+                    .all(|rule| rule_applies(rule, lemma, details, &found))
+            {
+                return FetchedDefinition::Skipped;
+            }
+            let def = if !dict.fetch_by_default {
+                Definition::OnDemand(dict.name.to_owned())
+            } else if let Some(def) = cached.get(&dict.name) {
+                def.clone()
+            } else if options.offline_mode && dict.specific_settings.is_network() {
+                // Network dictionaries are left out entirely rather than shown as errors
+                return FetchedDefinition::Skipped;
+            } else {
+                fetch_definition(options, dict, lemma)
+                    .await
+                    .unwrap_or_else(|e| Definition::Error(e.to_string()))
+            };
+            on_fetched(&dict.name, &def);
+            FetchedDefinition::Found(def)
+        }
+        .boxed()
+        .shared();
+        fetches.insert(&dict.name, fetch.clone());
+        ordered_fetches.push((&dict.name, fetch));
+    }
+
+    join_all(
+        ordered_fetches
+            .into_iter()
+            .map(|(name, fetch)| async move { (name, fetch.await) }),
+    )
+    .await
+    .into_iter()
+    .filter_map(|(name, fetched)| match fetched {
+        FetchedDefinition::Skipped => None,
+        FetchedDefinition::Found(def) => Some((name.clone(), def)),
+    })
+    .collect()
+}
+
+/// The dictionaries of the current language and the definitions already cached from them
+pub fn cached_definitions(
+    state: &SharedInfo,
+    language: &str,
+    lemma: &str,
+) -> (Vec<Dictionary>, HashMap<String, Definition>) {
+    let dicts = state
+        .settings
+        .languages
+        .get(language)
+        .expect("language should exist")
+        .dicts
+        .clone();
+    let cached = dicts
+        .iter()
+        .filter_map(|dict| {
+            state
+                .definition_cache
+                .get(language, &dict.name, lemma)
+                .map(|def| (dict.name.clone(), def.clone()))
+        })
+        .collect();
+    (dicts, cached)
+}
+
+/// Adds the definitions that were not already cached to the cache
+pub fn cache_definitions(
+    state: &mut SharedInfo,
+    language: &str,
+    lemma: &str,
+    defs: &HashMap<String, Definition>,
+    cached: &HashMap<String, Definition>,
+) {
+    for (dictionary, def) in defs {
+        if !cached.contains_key(dictionary) {
+            state
+                .definition_cache
+                .insert(language, dictionary, lemma, def.clone());
+        }
+    }
+}
+
+/// The definitions found for one of the forms a word was looked up with
+#[derive(Serialize, Debug)]
+pub struct CandidateDefinitions {
+    pub lemma: String,
+    pub definitions: HashMap<String, Definition>,
+}
+
+/// The forms to look a word up with, in order: the chosen lemma, the other suggested forms and
+/// then the word as it appears in the text
+fn lookup_candidates(lemma: &str, other_forms: &[String], text: Option<&str>) -> Vec<String> {
+    let mut candidates: Vec<String> = Vec::new();
+    let text_forms = text
+        .into_iter()
+        .flat_map(|text| [text.to_owned(), text.to_lowercase()]);
+    for candidate in std::iter::once(lemma.to_owned())
+        .chain(other_forms.iter().cloned())
+        .chain(text_forms)
+    {
+        let candidate = candidate.trim().to_owned();
+        if !candidate.is_empty() && !candidates.contains(&candidate) {
+            candidates.push(candidate);
+        }
+    }
+    candidates
+}
+
+/// Whether any dictionary actually has a definition, rather than nothing or an error
+fn has_definition(definitions: &HashMap<String, Definition>) -> bool {
+    definitions
+        .values()
+        .any(|def| matches!(def, Definition::Text(_) | Definition::Structured(_)))
+}
+
+async fn get_candidate_defs(
+    state: &State<'_, KalbaState>,
+    window: &Window,
+    lemma: &str,
+    details: &WordDetails,
+) -> HashMap<String, Definition> {
+    // The state is only locked while reading the settings so other commands can run while the
+    // definitions are being fetched
+    let (language, dicts, cached, options, personal) = {
+        let state = state.0.lock().await;
+        let language = state
+            .current_language
+            .clone()
+            .expect("current language should already be selected");
+        let (dicts, cached) = cached_definitions(&state, &language, lemma);
+        let personal = personal_definition(&state, &language, lemma);
+        (
+            language,
+            dicts,
+            cached,
+            FetchOptions::from_state(&state),
+            personal,
+        )
+    };
+
+    let send_definition = |dictionary: &str, definition: &Definition| {
+        if let Err(e) = window.emit(
+            "definition_fetched",
+            DefinitionFetched {
+                lemma,
+                dictionary,
+                definition,
+            },
+        ) {
+            log::warn!("Unable to send definition from {dictionary}: {e}");
+        }
+    };
+    if let Some(personal) = &personal {
+        send_definition(PERSONAL_DICTIONARY, personal);
+    }
+
+    let mut defs =
+        fetch_definitions(&dicts, &cached, &options, lemma, details, send_definition).await;
+
+    cache_definitions(&mut *state.0.lock().await, &language, lemma, &defs, &cached);
+    if let Some(personal) = personal {
+        defs.insert(PERSONAL_DICTIONARY.to_owned(), personal);
+    }
+    defs
+}
+
+/// Looks up the lemma, falling back to the other forms of the word and the word itself when no
+/// dictionary has a definition for it. With `show_all_candidates` enabled, every form with a
+/// definition is returned instead of only the first.
 #[tauri::command]
 pub async fn get_defs(
     state: State<'_, KalbaState>,
+    window: Window,
     lemma: String,
-) -> Result<HashMap<String, Definition>, KalbaError> {
-    let mut state = state.0.lock().await;
-    let language = state
-        .current_language
-        .clone()
-        .expect("current language should already be selected");
-    if let Some(v) = state
-        .language_cached_data
-        .get(&language)
-        .and_then(|v| v.definitions.get(&lemma))
-    {
-        Ok(v.clone())
-    } else {
-        let mut defs = HashMap::new();
-        let mut successful_defs = Vec::new();
-        for dict in &state
-            .settings
-            .languages
-            .get(&language)
-            .expect("language should exist")
-            .dicts
-        {
-            if let Some(required_dictionary) = dict.run_when_not.as_ref() {
-                if successful_defs.contains(&required_dictionary) {
-                    continue;
-                }
-            }
-            let def = if dict.fetch_by_default {
-                get_def(
-                    Arc::clone(&state.dict_info),
-                    &dict.specific_settings,
-                    &lemma,
-                    &state.settings.definition_styling,
-                )
-                .await?
-            } else {
-                Definition::OnDemand(dict.name.to_owned())
-            };
-            if def != Definition::Empty {
-                successful_defs.push(&dict.name);
-            }
+    other_forms: Option<Vec<String>>,
+    text: Option<String>,
+    pos: Option<String>,
+    morph: Option<HashMap<String, String>>,
+) -> Result<Vec<CandidateDefinitions>, KalbaError> {
+    let details = WordDetails {
+        pos,
+        morph: morph.unwrap_or_default(),
+    };
+    let show_all_candidates = state.0.lock().await.settings.show_all_candidates;
+    let candidates = lookup_candidates(
+        &lemma,
+        other_forms.as_deref().unwrap_or_default(),
+        text.as_deref(),
+    );
 
-            defs.insert(dict.name.clone(), def);
+    let mut found = Vec::new();
+    let mut fallback = None;
+    for candidate in candidates {
+        let definitions = get_candidate_defs(&state, &window, &candidate, &details).await;
+        let candidate = CandidateDefinitions {
+            lemma: candidate,
+            definitions,
+        };
+        if has_definition(&candidate.definitions) {
+            found.push(candidate);
+            if !show_all_candidates {
+                break;
+            }
+        } else if fallback.is_none() {
+            fallback = Some(candidate);
         }
-        state
-            .language_cached_data
-            .entry(language)
-            .or_default()
-            .definitions
-            .insert(lemma, defs.clone());
-        Ok(defs)
     }
+    // When nothing has a definition, the lemma's errors and on demand dictionaries are still shown
+    if found.is_empty() {
+        found.extend(fallback);
+    }
+    Ok(found)
 }
 
 #[tauri::command]
@@ -230,43 +762,52 @@ pub async fn get_definition_on_demand(
     lemma: String,
     dictionary: String,
 ) -> Result<Definition, KalbaError> {
-    let state = state.0.lock().await;
-    let language = state
-        .current_language
-        .clone()
-        .expect("current language should already be selected");
-    for dict in &state
-        .settings
-        .languages
-        .get(&language)
-        .expect("language should exist")
-        .dicts
-    {
-        if dict.name == dictionary {
-            return get_def(
-                Arc::clone(&state.dict_info),
-                &dict.specific_settings,
-                &lemma,
-                &state.settings.definition_styling,
-            )
-            .await;
+    let (language, dict, options) = {
+        let state = state.0.lock().await;
+        let language = state
+            .current_language
+            .clone()
+            .expect("current language should already be selected");
+        let dict = state
+            .settings
+            .languages
+            .get(&language)
+            .expect("language should exist")
+            .dicts
+            .iter()
+            .find(|dict| dict.name == dictionary)
+            .cloned()
+            .expect("No dictionary found");
+        if let Some(def) = state.definition_cache.get(&language, &dictionary, &lemma) {
+            return Ok(def.clone());
         }
-    }
-    panic!("No dictionary found");
+        (language, dict, FetchOptions::from_state(&state))
+    };
+    let def = fetch_definition(&options, &dict, &lemma).await?;
+    state
+        .0
+        .lock()
+        .await
+        .definition_cache
+        .insert(&language, &dictionary, &lemma, def.clone());
+    Ok(def)
 }
 
 async fn get_def(
     dict_info: Arc<tauri::async_runtime::Mutex<DictionaryInfo>>,
-    dict: &DictionarySpecificSettings,
+    dict: &Dictionary,
     lemma: &str,
     definition_styling: &DefinitionStyling,
 ) -> Result<Definition, KalbaError> {
-    match dict {
+    match &dict.specific_settings {
+        DictionarySpecificSettings::File(f, DictFileType::MDict) => {
+            get_mdict(dict_info, lemma, f).await
+        }
         DictionarySpecificSettings::File(f, dict_type) => get_def_from_file(lemma, f, dict_type),
-        DictionarySpecificSettings::Url(url, embed, selector) => {
-            get_def_url(lemma, url, *embed, selector).await
+        DictionarySpecificSettings::Url(settings) => get_def_url(dict_info, lemma, settings).await,
+        DictionarySpecificSettings::Command(cmd) => {
+            get_def_command(lemma, cmd, Duration::from_secs(dict.timeout)).await
         }
-        DictionarySpecificSettings::Command(cmd) => get_def_command(lemma, cmd).await,
         DictionarySpecificSettings::EkalbaBendrines => {
             get_ekalba_bendrines(dict_info, lemma, definition_styling).await
         }
@@ -284,16 +825,123 @@ async fn get_def(
             .await
         }
         DictionarySpecificSettings::WordReference(definition_lang, target_lang) => {
-            get_wordreference(
-                dict_info,
-                lemma,
-                definition_lang,
-                target_lang,
-                definition_styling,
-            )
-            .await
+            get_wordreference(dict_info, lemma, definition_lang, target_lang).await
+        }
+        DictionarySpecificSettings::Kaikki(file) => get_kaikki(dict_info, lemma, file).await,
+    }
+}
+
+/// Collapses the whitespace of text taken from html
+fn clean_text(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Finds the text of a wiktionary sense without its labels, examples or subsenses
+fn wiktionary_gloss(sense: Node) -> String {
+    let mut gloss = String::new();
+    for part in sense.children() {
+        let skipped = matches!(part.name(), Some("dl" | "ul" | "ol" | "sup"))
+            || predicate::Class("usage-label-sense").matches(&part);
+        if !skipped {
+            gloss.push_str(&part.text());
+        }
+    }
+    clean_text(&gloss)
+}
+
+fn wiktionary_sense(sense: Node) -> Sense {
+    let tags = sense
+        .find(predicate::Descendant(
+            predicate::Class("usage-label-sense"),
+            predicate::Class("ib-content"),
+        ))
+        .map(|tag| clean_text(&tag.text()))
+        .collect();
+    let examples = sense
+        .find(predicate::Class("h-usage-example"))
+        .map(|example| {
+            let text = example
+                .find(predicate::Class("e-example"))
+                .next()
+                .unwrap_or(example)
+                .text();
+            Example {
+                text: clean_text(&text),
+                translation: example
+                    .find(predicate::Class("e-translation"))
+                    .next()
+                    .map(|translation| clean_text(&translation.text())),
+            }
+        })
+        .collect();
+    Sense {
+        gloss: wiktionary_gloss(sense),
+        tags,
+        examples,
+    }
+}
+
+/// Splits the section of a wiktionary page for one language into entries for each part of speech.
+/// Returns `None` if no senses could be found, so that the page can be shown as it is instead.
+fn parse_wiktionary_section(section: &str, lemma: &str) -> Option<StructuredDefinition> {
+    let doc = Document::from(section);
+    let body = doc.find(predicate::Name("body")).next()?;
+    let mut definition = StructuredDefinition {
+        headword: lemma.to_owned(),
+        entries: Vec::new(),
+    };
+    let mut part_of_speech = None;
+    let mut gender = None;
+    let mut ipa: Vec<String> = Vec::new();
+
+    let is_heading = |node: &Node| matches!(node.name(), Some("h3" | "h4" | "h5"));
+
+    for node in body.children() {
+        if let Some(heading) = Some(node)
+            .filter(is_heading)
+            .or_else(|| node.descendants().find(is_heading))
+        {
+            let title = heading
+                .find(predicate::Class("mw-headline"))
+                .next()
+                .unwrap_or(heading);
+            part_of_speech = Some(clean_text(&title.text()));
+            continue;
+        }
+        for pronunciation in node.find(predicate::Class("IPA")) {
+            let pronunciation = clean_text(&pronunciation.text());
+            if !ipa.contains(&pronunciation) {
+                ipa.push(pronunciation);
+            }
+        }
+        if let Some(headword_line) = node.find(predicate::Class("headword-line")).next() {
+            if let Some(headword) = headword_line.find(predicate::Class("headword")).next() {
+                definition.headword = clean_text(&headword.text());
+            }
+            gender = headword_line
+                .find(predicate::Class("gender"))
+                .next()
+                .map(|gender| clean_text(&gender.text()));
+        }
+        if node.name() == Some("ol") {
+            let senses: Vec<Sense> = node
+                .children()
+                .filter(|sense| sense.name() == Some("li"))
+                .map(wiktionary_sense)
+                .filter(|sense| !sense.gloss.is_empty())
+                .collect();
+            if !senses.is_empty() {
+                definition.entries.push(DefinitionEntry {
+                    part_of_speech: part_of_speech.clone(),
+                    gender: gender.take(),
+                    ipa: ipa.clone(),
+                    senses,
+                    forms: Vec::new(),
+                });
+            }
         }
     }
+    (!definition.entries.is_empty()).then_some(definition)
 }
 
 async fn get_wiktionary(
@@ -303,13 +951,27 @@ async fn get_wiktionary(
     target_lang: &str,
     definition_styling: &DefinitionStyling,
 ) -> Result<Definition, KalbaError> {
-    let mut lock = dict_info.lock().await;
-    let response = lock
-        .send_request(&format!(
-            "https://{definition_lang}.wiktionary.org/wiki/{lemma}"
-        ))
-        .await;
-    let doc = Document::from_read(response.text().await?.as_bytes())?;
+    let client = dict_info.lock().await.client();
+    let response = send_request(
+        &client,
+        &format!("https://{definition_lang}.wiktionary.org/wiki/{lemma}"),
+    )
+    .await?;
+    wiktionary_definition(
+        &response.text().await?,
+        lemma,
+        target_lang,
+        definition_styling,
+    )
+}
+
+fn wiktionary_definition(
+    page: &str,
+    lemma: &str,
+    target_lang: &str,
+    definition_styling: &DefinitionStyling,
+) -> Result<Definition, KalbaError> {
+    let doc = Document::from(page);
     let mut def_html = String::new();
     for node in doc.find(Attr("id", target_lang)) {
         let mut node = node.parent().unwrap();
@@ -325,6 +987,10 @@ async fn get_wiktionary(
         }
     }
 
+    if let Some(definition) = parse_wiktionary_section(&def_html, lemma) {
+        return Ok(Definition::Structured(definition));
+    }
+
     let element_content_handlers = vec![
         element!("ol li", |el| {
             el.set_attribute("style", &definition_styling.definition).unwrap();
@@ -372,31 +1038,35 @@ async fn get_wiktionary(
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Default)]
-struct DefinitionDetails {
-    title: String,
-    definition: String,
-    notes: String,
-    examples: String,
-}
-
 async fn get_wordreference(
     dict_info: Arc<tauri::async_runtime::Mutex<DictionaryInfo>>,
     lemma: &str,
     definition_lang: &str,
     target_lang: &str,
-    definition_styling: &DefinitionStyling,
 ) -> Result<Definition, KalbaError> {
-    let mut lock = dict_info.lock().await;
-    let response = lock
-        .send_request(&format!(
-            "https://www.wordreference.com/{target_lang}{definition_lang}/{lemma}"
-        ))
-        .await
-        .text()
-        .await?;
+    let client = dict_info.lock().await.client();
+    let response = send_request(
+        &client,
+        &format!("https://www.wordreference.com/{target_lang}{definition_lang}/{lemma}"),
+    )
+    .await?
+    .text()
+    .await?;
+    Ok(wordreference_definition(&response, lemma))
+}
+
+/// Text of a wordreference cell without the parts of speech, which are in `em` tags
+fn wordreference_text(cell: Node) -> String {
+    let text: String = cell
+        .children()
+        .filter(|part| part.name() != Some("em"))
+        .map(|part| part.text())
+        .collect();
+    clean_text(&text)
+}
 
-    let doc = Document::from_read(response.as_bytes()).unwrap();
+fn wordreference_definition(page: &str, lemma: &str) -> Definition {
+    let doc = Document::from(page);
     let Some(article_node) = doc
         .find(predicate::Descendant(
             predicate::Attr("id", "articleWRD"),
@@ -404,97 +1074,85 @@ async fn get_wordreference(
         ))
         .next()
     else {
-        return Ok(Definition::Empty);
+        return Definition::Empty;
     };
 
-    let children = article_node.children();
-
+    let mut definition = StructuredDefinition {
+        headword: lemma.to_owned(),
+        entries: Vec::new(),
+    };
     let mut is_even = true;
-    let mut definitions = vec![DefinitionDetails::default()];
+    // Every translation takes up a group of rows with the same class
+    let mut senses: Vec<(Option<String>, Sense)> = vec![Default::default()];
 
-    for row in children.skip(2) {
+    for row in article_node.children().skip(2) {
         if let Some(class) = row.attr("class") {
             let is_even_now = class == "even";
             if is_even_now != is_even {
-                definitions.push(DefinitionDetails::default());
+                senses.push(Default::default());
             }
             is_even = is_even_now;
         }
-        let last_definition = definitions.last_mut().unwrap();
+        let (part_of_speech, sense) = senses.last_mut().unwrap();
         for (i, cell) in row.children().enumerate() {
             match i {
                 0 => {
-                    for title_part in cell.children() {
-                        if title_part.html().starts_with("<em") {
-                            last_definition.title.push_str(&format!(
-                                "<span style=\"{}\">{}</span>",
-                                definition_styling.main_detail,
-                                title_part.text()
-                            ));
-                        } else {
-                            last_definition.title.push_str(&title_part.text());
-                        }
+                    if let Some(pos) = cell.find(predicate::Name("em")).next() {
+                        part_of_speech.get_or_insert_with(|| clean_text(&pos.text()));
+                    }
+                    if let Some(headword) = cell.find(predicate::Name("strong")).next() {
+                        definition.headword = clean_text(&headword.text());
                     }
-                    last_definition.title.push('\n');
                 }
                 1 => {
-                    let text = cell.text();
-                    let final_text = text.trim();
-                    let section = if final_text.starts_with('(') {
-                        &mut last_definition.notes
-                    } else {
-                        &mut last_definition.examples
-                    };
-                    section.push_str(final_text);
-                    section.push('\n');
+                    let text = clean_text(&cell.text());
+                    if text.starts_with('(') {
+                        sense
+                            .tags
+                            .push(text.trim_matches(|c| c == '(' || c == ')').to_owned());
+                    } else if predicate::Class("ToEx").matches(&cell) {
+                        if let Some(example) = sense.examples.last_mut() {
+                            example.translation = Some(text);
+                        }
+                    } else if !text.is_empty() {
+                        sense.examples.push(Example {
+                            text,
+                            translation: None,
+                        });
+                    }
                 }
                 2 => {
-                    for definition_part in cell.children() {
-                        if definition_part.html().starts_with("<em") {
-                            last_definition.definition.push_str(&format!(
-                                "<span style=\"{}\">{}</span>",
-                                definition_styling.main_detail,
-                                definition_part.text()
-                            ));
-                        } else {
-                            last_definition.definition.push_str(&definition_part.text());
+                    let translation = wordreference_text(cell);
+                    if !translation.is_empty() {
+                        if !sense.gloss.is_empty() {
+                            sense.gloss.push_str(", ");
                         }
+                        sense.gloss.push_str(&translation);
                     }
-                    last_definition.definition.push('\n');
                 }
                 _ => continue,
             }
         }
     }
-    let mut generated_html = String::new();
-    let num_definitions = definitions.len();
-    for (i, definition) in definitions.into_iter().enumerate() {
-        generated_html.push_str(&format!(
-            "<div><span style=\"{}\">{} </span>",
-            definition_styling.info,
-            definition.title.trim()
-        ));
-        if !definition.notes.is_empty() {
-            generated_html.push_str(&format!(
-                "<span style=\"{}\">{}</span></div>",
-                definition_styling.main_detail,
-                definition.notes.trim()
-            ));
-        }
-        if !definition.definition.is_empty() {
-            generated_html.push_str(&format!(
-                "<p style=\"{}\">{}</p>",
-                definition_styling.definition, definition.definition
-            ));
-        }
-        if !definition.examples.is_empty() {
-            generated_html.push_str(&format!("<p>{}</p>", definition.examples));
+
+    for (part_of_speech, sense) in senses {
+        if sense.gloss.is_empty() {
+            continue;
         }
-        if i + 1 < num_definitions {
-            generated_html.push_str("<hr>");
+        match definition.entries.last_mut() {
+            Some(entry) if entry.part_of_speech == part_of_speech => entry.senses.push(sense),
+            _ => definition.entries.push(DefinitionEntry {
+                part_of_speech,
+                senses: vec![sense],
+                ..Default::default()
+            }),
         }
     }
-    Ok(Definition::Text(generated_html))
+    if definition.entries.is_empty() {
+        Definition::Empty
+    } else {
+        Definition::Structured(definition)
+    }
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -514,20 +1172,23 @@ async fn get_ekalba_bendrines(
     word: &str,
     definition_styling: &DefinitionStyling,
 ) -> Result<Definition, KalbaError> {
-    let response = {
+    let (client, uuid) = {
         let mut lock = dict_info.lock().await;
-        let Some(uuid) = lock.get_bendrines(EkalbaDictionary::Bendrines, word).await else {
+        let Some(uuid) = lock
+            .get_bendrines(EkalbaDictionary::Bendrines, word)
+            .await?
+        else {
             return Ok(Definition::Empty);
         };
-        let uuid = uuid.to_owned();
-
-        lock.send_request(&format!(
-            "https://ekalba.lt/action/vocabulary/record/{uuid}?viewType=64"
-        ))
-        .await
-        .json::<EkalbaRoot>()
-        .await?
+        (lock.client(), uuid)
     };
+    let response = send_request(
+        &client,
+        &format!("https://ekalba.lt/action/vocabulary/record/{uuid}?viewType=64"),
+    )
+    .await?
+    .json::<EkalbaRoot>()
+    .await?;
     let element_content_handlers = vec![
         // Titles
         element!("span.bzpusjuodis", |el| {
@@ -569,20 +1230,23 @@ async fn get_ekalba_dabartines(
     word: &str,
     definition_styling: &DefinitionStyling,
 ) -> Result<Definition, KalbaError> {
-    let response = {
+    let (client, uuid) = {
         let mut lock = dict_info.lock().await;
-        let Some(uuid) = lock.get_bendrines(EkalbaDictionary::Dabartines, word).await else {
+        let Some(uuid) = lock
+            .get_bendrines(EkalbaDictionary::Dabartines, word)
+            .await?
+        else {
             return Ok(Definition::Empty);
         };
-        let uuid = uuid.to_owned();
-
-        lock.send_request(&format!(
-            "https://ekalba.lt/action/vocabulary/record/{uuid}?viewType=64"
-        ))
-        .await
-        .json::<EkalbaRoot>()
-        .await?
+        (lock.client(), uuid)
     };
+    let response = send_request(
+        &client,
+        &format!("https://ekalba.lt/action/vocabulary/record/{uuid}?viewType=64"),
+    )
+    .await?
+    .json::<EkalbaRoot>()
+    .await?;
     let element_content_handlers = vec![
         element!(".dz_homonym div", |el| {
             el.set_tag_name("span").unwrap();
@@ -634,6 +1298,7 @@ mod tests {
     use std::path::PathBuf;
 
     use super::*;
+    use crate::test_server::serve_once;
 
     fn get_delim_file() -> PathBuf {
         [
@@ -658,6 +1323,18 @@ mod tests {
         .collect()
     }
 
+    fn get_mdict_file() -> PathBuf {
+        [
+            env!("CARGO_MANIFEST_DIR"),
+            "resources",
+            "test",
+            "mdict",
+            "sample.mdx",
+        ]
+        .iter()
+        .collect()
+    }
+
     #[test]
     fn read_from_delimiter_file_key_exists() {
         let f = get_delim_file();
@@ -729,4 +1406,596 @@ mod tests {
             String::from("Dict path is not invalid") // TODO: actual error message is wrong
         );
     }
+
+    #[test]
+    fn read_from_mdict_key_exists() {
+        let f = get_mdict_file();
+        assert_eq!(
+            get_def_from_file("blogas", f.to_str().unwrap(), &DictFileType::MDict).unwrap(),
+            Definition::Text(String::from("<b>blogas</b> bad, wrong"))
+        );
+    }
+
+    #[test]
+    fn read_from_mdict_follows_links() {
+        let f = get_mdict_file();
+        assert_eq!(
+            get_def_from_file("blogai", f.to_str().unwrap(), &DictFileType::MDict).unwrap(),
+            Definition::Text(String::from("<b>blogas</b> bad, wrong"))
+        );
+    }
+
+    #[test]
+    fn read_from_mdict_prefers_exact_case() {
+        let f = get_mdict_file();
+        assert_eq!(
+            get_def_from_file("Geras", f.to_str().unwrap(), &DictFileType::MDict).unwrap(),
+            Definition::Text(String::from("<b>Geras</b> a surname"))
+        );
+    }
+
+    #[test]
+    fn read_from_mdict_embeds_resources() {
+        let f = get_mdict_file();
+        assert_eq!(
+            get_def_from_file("labas", f.to_str().unwrap(), &DictFileType::MDict).unwrap(),
+            Definition::Text(String::from("<b>labas</b> hi <img src=\"data:image/png;base64,iVBORw0K\"> <audio controls src=\"data:audio/mpeg;base64,SUQzYWJj\"></audio>"))
+        );
+        assert_eq!(
+            get_def_from_file("geras", f.to_str().unwrap(), &DictFileType::MDict).unwrap(),
+            Definition::Text(String::from(
+                "<style>b { color: red; }</style><b>geras</b> good"
+            ))
+        );
+    }
+
+    #[test]
+    fn read_from_mdict_key_doesnt_exist() {
+        let f = get_mdict_file();
+        assert_eq!(
+            get_def_from_file(
+                "this key doesnt exist",
+                f.to_str().unwrap(),
+                &DictFileType::MDict
+            )
+            .unwrap(),
+            Definition::Empty,
+        );
+    }
+
+    fn test_dictionary(name: &str, specific_settings: DictionarySpecificSettings) -> Dictionary {
+        Dictionary {
+            name: name.to_owned(),
+            run_when_not: None,
+            specific_settings,
+            fetch_by_default: true,
+            timeout: 10,
+            retries: 0,
+            rules: Vec::new(),
+        }
+    }
+
+    fn test_options(offline_mode: bool) -> FetchOptions {
+        FetchOptions {
+            dict_info: Arc::default(),
+            definition_styling: DefinitionStyling::default(),
+            offline_mode,
+            rate_limiter: None,
+        }
+    }
+
+    #[test]
+    fn offline_mode_skips_network_dictionaries() {
+        let delim = DictionarySpecificSettings::File(
+            get_delim_file().to_str().unwrap().to_owned(),
+            DictFileType::TextSplitAt(String::from(":")),
+        );
+        let mut fallback = test_dictionary("fallback", delim.clone());
+        fallback.run_when_not = Some(String::from("wiktionary"));
+        let dicts = [
+            test_dictionary(
+                "wiktionary",
+                DictionarySpecificSettings::Wiktionary(String::from("en"), String::from("lt")),
+            ),
+            test_dictionary("delim", delim),
+            fallback,
+        ];
+        let defs = tauri::async_runtime::block_on(fetch_definitions(
+            &dicts,
+            &HashMap::new(),
+            &test_options(true),
+            "geras",
+            &WordDetails::default(),
+            |_, _| (),
+        ));
+        assert_eq!(
+            defs,
+            HashMap::from([
+                (
+                    String::from("delim"),
+                    Definition::Text(String::from("good"))
+                ),
+                (
+                    String::from("fallback"),
+                    Definition::Text(String::from("good"))
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn failed_dictionaries_are_reported() {
+        let dicts = [
+            test_dictionary(
+                "missing",
+                DictionarySpecificSettings::File(
+                    String::from("/this/file/does/not/exist"),
+                    DictFileType::TextSplitAt(String::from(":")),
+                ),
+            ),
+            test_dictionary(
+                "delim",
+                DictionarySpecificSettings::File(
+                    get_delim_file().to_str().unwrap().to_owned(),
+                    DictFileType::TextSplitAt(String::from(":")),
+                ),
+            ),
+        ];
+        let defs = tauri::async_runtime::block_on(fetch_definitions(
+            &dicts,
+            &HashMap::new(),
+            &test_options(false),
+            "labas",
+            &WordDetails::default(),
+            |_, _| (),
+        ));
+        assert!(matches!(defs.get("missing"), Some(Definition::Error(_))));
+        assert_eq!(
+            defs.get("delim"),
+            Some(&Definition::Text(String::from("hi")))
+        );
+    }
+
+    #[test]
+    fn lookup_candidates_are_ordered_and_unique() {
+        assert_eq!(
+            lookup_candidates(
+                "geras",
+                &[String::from("gerti"), String::from("geras")],
+                Some("Gerą")
+            ),
+            vec!["geras", "gerti", "Gerą", "gerą"]
+        );
+        assert_eq!(
+            lookup_candidates("labas", &[], Some("labas ")),
+            vec!["labas"]
+        );
+    }
+
+    #[test]
+    fn only_real_definitions_count() {
+        let mut defs = HashMap::from([
+            (String::from("a"), Definition::Empty),
+            (
+                String::from("b"),
+                Definition::Error(String::from("timed out")),
+            ),
+            (String::from("c"), Definition::OnDemand(String::from("c"))),
+        ]);
+        assert!(!has_definition(&defs));
+        defs.insert(String::from("d"), Definition::Text(String::from("good")));
+        assert!(has_definition(&defs));
+    }
+
+    #[test]
+    fn rules_choose_dictionaries() {
+        let delim = DictionarySpecificSettings::File(
+            get_delim_file().to_str().unwrap().to_owned(),
+            DictFileType::TextSplitAt(String::from(":")),
+        );
+        let with_rules = |name: &str, rules: Vec<DictionaryRule>| Dictionary {
+            rules,
+            ..test_dictionary(name, delim.clone())
+        };
+        let dicts = [
+            with_rules(
+                "verbs",
+                vec![DictionaryRule::PartOfSpeech(vec![String::from("VERB")])],
+            ),
+            with_rules(
+                "nouns",
+                vec![DictionaryRule::PartOfSpeech(vec![String::from("noun")])],
+            ),
+            test_dictionary(
+                "missing",
+                DictionarySpecificSettings::File(
+                    String::from("/this/file/does/not/exist"),
+                    DictFileType::TextSplitAt(String::from(":")),
+                ),
+            ),
+            with_rules(
+                "last_resort",
+                vec![DictionaryRule::AllFailed(vec![
+                    String::from("missing"),
+                    String::from("nouns"),
+                ])],
+            ),
+            with_rules(
+                "lowercase",
+                vec![DictionaryRule::Not(Box::new(DictionaryRule::Capitalized))],
+            ),
+            with_rules(
+                "past",
+                vec![DictionaryRule::Feature(
+                    String::from("Tense"),
+                    String::from("Past"),
+                )],
+            ),
+        ];
+        let fetched_from = |details: WordDetails| {
+            let mut names: Vec<String> = tauri::async_runtime::block_on(fetch_definitions(
+                &dicts,
+                &HashMap::new(),
+                &test_options(false),
+                "geras",
+                &details,
+                |_, _| (),
+            ))
+            .into_keys()
+            .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(
+            fetched_from(WordDetails {
+                pos: Some(String::from("NOUN")),
+                morph: HashMap::new(),
+            }),
+            vec!["lowercase", "missing", "nouns"]
+        );
+        assert_eq!(
+            fetched_from(WordDetails {
+                pos: Some(String::from("VERB")),
+                morph: HashMap::from([(String::from("Tense"), String::from("Past"))]),
+            }),
+            vec!["last_resort", "lowercase", "missing", "past", "verbs"]
+        );
+    }
+
+    fn read_test_page(name: &str) -> String {
+        let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "resources", "test", name]
+            .iter()
+            .collect();
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn wiktionary_page_is_structured() {
+        let page = read_test_page("wiktionary_sample.html");
+        let definition =
+            wiktionary_definition(&page, "geras", "Lithuanian", &DefinitionStyling::default())
+                .unwrap();
+        let ipa = vec![String::from("[ɡʲɛˈrɐs]")];
+        assert_eq!(
+            definition,
+            Definition::Structured(StructuredDefinition {
+                headword: String::from("gẽras"),
+                entries: vec![
+                    DefinitionEntry {
+                        part_of_speech: Some(String::from("Adjective")),
+                        gender: None,
+                        ipa: ipa.clone(),
+                        senses: vec![
+                            Sense {
+                                gloss: String::from("good"),
+                                tags: Vec::new(),
+                                examples: vec![Example {
+                                    text: String::from("Geras žmogus."),
+                                    translation: Some(String::from("A good person.")),
+                                }],
+                            },
+                            Sense {
+                                gloss: String::from("kind"),
+                                tags: vec![String::from("figuratively")],
+                                examples: Vec::new(),
+                            },
+                        ],
+                        forms: Vec::new(),
+                    },
+                    DefinitionEntry {
+                        part_of_speech: Some(String::from("Noun")),
+                        gender: Some(String::from("m")),
+                        ipa,
+                        senses: vec![Sense {
+                            gloss: String::from("a good person"),
+                            ..Default::default()
+                        }],
+                        forms: Vec::new(),
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn wiktionary_page_without_language() {
+        let page = read_test_page("wiktionary_sample.html");
+        assert_eq!(
+            wiktionary_definition(&page, "geras", "English", &DefinitionStyling::default())
+                .unwrap(),
+            Definition::Empty
+        );
+    }
+
+    #[test]
+    fn wordreference_page_is_structured() {
+        let page = read_test_page("wordreference_sample.html");
+        assert_eq!(
+            wordreference_definition(&page, "geras"),
+            Definition::Structured(StructuredDefinition {
+                headword: String::from("geras"),
+                entries: vec![
+                    DefinitionEntry {
+                        part_of_speech: Some(String::from("adj")),
+                        senses: vec![
+                            Sense {
+                                gloss: String::from("good"),
+                                tags: vec![String::from("of quality")],
+                                examples: vec![Example {
+                                    text: String::from("Tai geras filmas."),
+                                    translation: Some(String::from("This is a good film.")),
+                                }],
+                            },
+                            Sense {
+                                gloss: String::from("kind, nice"),
+                                tags: vec![String::from("of people")],
+                                examples: Vec::new(),
+                            },
+                        ],
+                        ..Default::default()
+                    },
+                    DefinitionEntry {
+                        part_of_speech: Some(String::from("nm")),
+                        senses: vec![Sense {
+                            gloss: String::from("good person"),
+                            ..Default::default()
+                        }],
+                        ..Default::default()
+                    },
+                ],
+            })
+        );
+    }
+
+    fn command(args: &[&str], output: CommandOutput) -> CommandSettings {
+        CommandSettings {
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            stdin: false,
+            output,
+        }
+    }
+
+    const UNTRUSTED_WORD: &str = "labas'; echo \"$(echo injected)\"; '";
+
+    #[test]
+    fn windows_commands_are_split_into_arguments() {
+        assert_eq!(
+            shared::split_command(r#"C:\dict\lookup.exe  --word "{word}" "two words""#),
+            ["C:\\dict\\lookup.exe", "--word", "{word}", "two words"]
+        );
+        assert_eq!(
+            shared::split_command(r#"lookup "" {word}"#),
+            ["lookup", "", "{word}"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_word_is_not_interpreted_by_shell() {
+        for settings in [
+            command(&["echo", "{word}"], CommandOutput::Html),
+            CommandSettings {
+                args: shared::shell_args("echo '{word}'"),
+                ..Default::default()
+            },
+        ] {
+            let definition = tauri::async_runtime::block_on(get_def_command(
+                UNTRUSTED_WORD,
+                &settings,
+                Duration::from_secs(10),
+            ))
+            .unwrap();
+            assert_eq!(definition, Definition::Text(format!("{UNTRUSTED_WORD}\n")));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn quoted_words_in_shell_commands_are_replaced() {
+        for (command, expected) in [
+            ("echo 'lookup {word}'", format!("lookup {UNTRUSTED_WORD}\n")),
+            (
+                "echo \"lookup {word}s\"",
+                format!("lookup {UNTRUSTED_WORD}s\n"),
+            ),
+            ("echo {word} | head -c 7", String::from("labas';")),
+            (
+                r"echo \{word} '{word}'",
+                format!("{{word}} {UNTRUSTED_WORD}\n"),
+            ),
+        ] {
+            let settings = CommandSettings {
+                args: shared::shell_args(command),
+                ..Default::default()
+            };
+            let definition = tauri::async_runtime::block_on(get_def_command(
+                UNTRUSTED_WORD,
+                &settings,
+                Duration::from_secs(10),
+            ))
+            .unwrap();
+            assert_eq!(definition, Definition::Text(expected), "{command}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_word_from_stdin() {
+        let settings = CommandSettings {
+            stdin: true,
+            ..command(&["cat"], CommandOutput::Html)
+        };
+        let definition = tauri::async_runtime::block_on(get_def_command(
+            "labas",
+            &settings,
+            Duration::from_secs(10),
+        ))
+        .unwrap();
+        assert_eq!(definition, Definition::Text(String::from("labas")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_json_output() {
+        let settings = command(
+            &[
+                "echo",
+                r#"{"entries": [{"part_of_speech": "intj", "senses": [{"gloss": "hi"}]}]}"#,
+            ],
+            CommandOutput::Json,
+        );
+        let definition = tauri::async_runtime::block_on(get_def_command(
+            "labas",
+            &settings,
+            Duration::from_secs(10),
+        ))
+        .unwrap();
+        assert_eq!(
+            definition,
+            Definition::Structured(StructuredDefinition {
+                headword: String::from("labas"),
+                entries: vec![DefinitionEntry {
+                    part_of_speech: Some(String::from("intj")),
+                    senses: vec![Sense {
+                        gloss: String::from("hi"),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }],
+            })
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_errors_are_reported() {
+        let failing = command(&["sh", "-c", "echo oops >&2; exit 1"], CommandOutput::Html);
+        let result = tauri::async_runtime::block_on(get_def_command(
+            "labas",
+            &failing,
+            Duration::from_secs(10),
+        ));
+        assert!(matches!(result, Err(KalbaError::CommandFailed(_, reason)) if reason == "oops"));
+
+        let slow = command(&["sleep", "10"], CommandOutput::Html);
+        let result = tauri::async_runtime::block_on(get_def_command(
+            "labas",
+            &slow,
+            Duration::from_millis(100),
+        ));
+        assert!(matches!(result, Err(KalbaError::Timeout(..))));
+    }
+
+    fn fetch_url(settings: &UrlSettings, lemma: &str) -> Definition {
+        tauri::async_runtime::block_on(get_def_url(
+            Arc::new(tauri::async_runtime::Mutex::new(DictionaryInfo::default())),
+            lemma,
+            settings,
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn url_json_api() {
+        let (url, request) = serve_once(
+            "application/json",
+            r#"{"results": [{"headword": "labas", "definition": "hi & hello"}, {"headword": "labas", "definition": "good day"}]}"#,
+        );
+        let settings = UrlSettings {
+            url: format!("{url}/lookup"),
+            method: HttpMethod::Post,
+            headers: vec![(String::from("X-Api-Key"), String::from("secret"))],
+            body: String::from(r#"{"word": "{word}"}"#),
+            extraction: ResponseExtraction::JsonPath(String::from("$.results[*]")),
+            template: String::from("<b>{headword}</b> {definition}"),
+            ..Default::default()
+        };
+        assert_eq!(
+            fetch_url(&settings, "labas"),
+            Definition::Text(String::from(
+                "<b>labas</b> hi &amp; hello\n<b>labas</b> good day"
+            ))
+        );
+        let request = request.join().unwrap();
+        assert!(request.starts_with("POST /lookup "));
+        assert!(request.to_lowercase().contains("x-api-key: secret"));
+        assert!(request.ends_with(r#"{"word": "labas"}"#));
+    }
+
+    #[test]
+    fn url_css_selector() {
+        let (url, request) = serve_once(
+            "text/html",
+            "<div class=\"def\">good</div><p>ignored</p><div class=\"def\">kind</div>",
+        );
+        let settings = UrlSettings {
+            url: format!("{url}/{{word}}"),
+            extraction: ResponseExtraction::Css(String::from(".def")),
+            template: String::from("<li>{match}</li>"),
+            ..Default::default()
+        };
+        assert_eq!(
+            fetch_url(&settings, "ge ras&"),
+            Definition::Text(String::from("<li>good</li>\n<li>kind</li>"))
+        );
+        assert!(request.join().unwrap().starts_with("GET /ge%20ras%26 "));
+    }
+
+    #[test]
+    fn response_text_is_not_filled_in_again() {
+        let fields = serde_json::json!({
+            "headword": "{definition}",
+            "definition": "hi {match}",
+        });
+        assert_eq!(
+            fill_template(
+                "<b>{headword}</b> {definition} {missing} {match}",
+                "{headword}",
+                Some(&fields)
+            ),
+            "<b>{definition}</b> hi {match} {missing} {headword}"
+        );
+    }
+
+    #[test]
+    fn word_is_escaped_in_body() {
+        assert_eq!(fill_body(r#"{"q": "{word}"}"#, "a\"b"), r#"{"q": "a\"b"}"#);
+        assert_eq!(fill_body("q={word}", "a b&c"), "q=a%20b%26c");
+    }
+
+    #[test]
+    fn legacy_url_settings() {
+        let settings: DictionarySpecificSettings = serde_json::from_str(
+            r#"{"t": "Url", "c": ["https://example.com/{word}", false, ".def"]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            settings,
+            DictionarySpecificSettings::Url(UrlSettings {
+                url: String::from("https://example.com/{word}"),
+                extraction: ResponseExtraction::Css(String::from(".def")),
+                ..Default::default()
+            })
+        );
+    }
 }
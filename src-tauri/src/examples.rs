@@ -0,0 +1,418 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::PathBuf,
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use shared::{Example, ExampleOrder, ToasterPayload};
+use tauri::{Emitter, State, Window};
+
+use crate::{language_parsing::sentence_lemmas, KalbaError, KalbaState};
+
+/// Sentences are tokenized in batches so the state isn't locked for the whole import
+const IMPORT_BATCH_SIZE: usize = 200;
+/// Words rated at least this well count as understood when ranking examples
+const KNOWN_RATING: i8 = 3;
+
+/// Files to import example sentences from
+#[derive(Deserialize, Debug)]
+#[serde(tag = "t", content = "c")]
+pub enum ExampleSource {
+    /// Lines with a sentence and optionally its translation, separated by a tab. Tatoeba's
+    /// sentence pair exports, which also include the ids of both sentences, work as well.
+    Pairs(String),
+    /// The sentences and links files of a Tatoeba export, along with the three letter codes of
+    /// the language and the language of the translations
+    Tatoeba {
+        sentences: String,
+        links: String,
+        language: String,
+        translation_language: String,
+    },
+}
+
+impl ExampleSource {
+    fn read(&self) -> Result<Vec<(String, Option<String>)>, KalbaError> {
+        Ok(match self {
+            Self::Pairs(file) => read_pairs(&fs::read_to_string(file)?),
+            Self::Tatoeba {
+                sentences,
+                links,
+                language,
+                translation_language,
+            } => read_tatoeba(
+                &fs::read_to_string(sentences)?,
+                &fs::read_to_string(links)?,
+                language,
+                translation_language,
+            ),
+        })
+    }
+}
+
+fn non_empty(text: &str) -> Option<String> {
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_owned())
+}
+
+fn read_pairs(contents: &str) -> Vec<(String, Option<String>)> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let is_id = |field: &str| field.trim().parse::<u64>().is_ok();
+            let (text, translation) = if fields.len() >= 4 && is_id(fields[0]) && is_id(fields[2]) {
+                (fields[1], Some(fields[3]))
+            } else {
+                (fields[0], fields.get(1).copied())
+            };
+            Some((non_empty(text)?, translation.and_then(non_empty)))
+        })
+        .collect()
+}
+
+fn read_tatoeba(
+    sentences: &str,
+    links: &str,
+    language: &str,
+    translation_language: &str,
+) -> Vec<(String, Option<String>)> {
+    let mut originals = Vec::new();
+    let mut translations = HashMap::new();
+    for line in sentences.lines() {
+        let mut fields = line.splitn(3, '\t');
+        let (Some(id), Some(sentence_language), Some(text)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        if sentence_language == language {
+            originals.push((id, text));
+        } else if sentence_language == translation_language {
+            translations.insert(id, text);
+        }
+    }
+
+    let mut linked = HashMap::new();
+    for (id, translation) in links.lines().filter_map(|line| line.split_once('\t')) {
+        if let Some(translation) = translations.get(translation.trim()) {
+            linked.entry(id).or_insert(*translation);
+        }
+    }
+
+    originals
+        .into_iter()
+        .filter_map(|(id, text)| {
+            Some((
+                non_empty(text)?,
+                linked.get(id).copied().and_then(non_empty),
+            ))
+        })
+        .collect()
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredExample {
+    text: String,
+    translation: Option<String>,
+    lemmas: Vec<String>,
+}
+
+/// Example sentences of a language, indexed by the lemmas of their words
+#[derive(Serialize, Deserialize, Default)]
+pub struct ExampleStore {
+    sentences: Vec<StoredExample>,
+    lemmas: HashMap<String, Vec<u32>>,
+}
+
+fn store_location(language: &str) -> Result<PathBuf, KalbaError> {
+    Ok(dirs::data_dir()
+        .ok_or_else(|| KalbaError::MissingDir(String::from("data")))?
+        .join("kalba")
+        .join("language_data")
+        .join("examples")
+        .join(language))
+}
+
+impl ExampleStore {
+    fn add(&mut self, text: String, translation: Option<String>, lemmas: Vec<String>) {
+        let index = self.sentences.len() as u32;
+        let mut seen = HashSet::new();
+        let lemmas: Vec<String> = lemmas
+            .into_iter()
+            .filter(|lemma| seen.insert(lemma.clone()))
+            .collect();
+        for lemma in &lemmas {
+            self.lemmas.entry(lemma.clone()).or_default().push(index);
+        }
+        self.sentences.push(StoredExample {
+            text,
+            translation,
+            lemmas,
+        });
+    }
+
+    /// Loads the saved examples of the language, which are empty if none were imported
+    fn load(language: &str) -> Result<Self, KalbaError> {
+        match fs::read(store_location(language)?) {
+            Ok(saved) => Ok(rmp_serde::from_slice(&saved).unwrap_or_else(|e| {
+                log::warn!("unable to read example sentences for {language}: {e}");
+                Self::default()
+            })),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, language: &str) -> Result<(), KalbaError> {
+        let location = store_location(language)?;
+        if let Some(parent) = location.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(
+            location,
+            rmp_serde::to_vec(self).expect("example sentences to be serializable"),
+        )?;
+        Ok(())
+    }
+
+    /// The share of the other words in the example that are known. Words that are ignored, such
+    /// as names, count as known.
+    fn comprehensibility(
+        example: &StoredExample,
+        lemma: &str,
+        rating: &impl Fn(&str) -> Option<i8>,
+    ) -> f64 {
+        let others: Vec<&String> = example.lemmas.iter().filter(|l| *l != lemma).collect();
+        if others.is_empty() {
+            return 1.0;
+        }
+        let known = others
+            .iter()
+            .filter(|other| rating(other).is_some_and(|r| r == -1 || r >= KNOWN_RATING))
+            .count();
+        known as f64 / others.len() as f64
+    }
+
+    /// Finds up to `count` examples of the lemma. `rating` gives the rating of a word, if it has
+    /// one.
+    pub fn examples(
+        &self,
+        lemma: &str,
+        count: usize,
+        order: ExampleOrder,
+        rating: impl Fn(&str) -> Option<i8>,
+    ) -> Vec<Example> {
+        let Some(indexes) = self.lemmas.get(lemma) else {
+            return Vec::new();
+        };
+        let mut ranked: Vec<(f64, usize, &StoredExample)> = indexes
+            .iter()
+            .map(|&i| {
+                let example = &self.sentences[i as usize];
+                (
+                    Self::comprehensibility(example, lemma, &rating),
+                    example.text.chars().count(),
+                    example,
+                )
+            })
+            .collect();
+        ranked.sort_by(|a, b| match order {
+            ExampleOrder::MostComprehensible => b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)),
+            ExampleOrder::Shortest => a.1.cmp(&b.1).then(b.0.total_cmp(&a.0)),
+        });
+        ranked
+            .into_iter()
+            .take(count)
+            .map(|(_, _, example)| Example {
+                text: example.text.clone(),
+                translation: example.translation.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Replaces the example sentences of the current language with the ones from the files. Returns
+/// how many sentences were imported.
+#[tauri::command]
+pub async fn import_examples(
+    state: State<'_, KalbaState>,
+    window: Window,
+    source: ExampleSource,
+) -> Result<usize, KalbaError> {
+    let pairs = source.read()?;
+    let language = state
+        .0
+        .lock()
+        .await
+        .current_language
+        .clone()
+        .expect("current language should already be selected");
+    log::info!("importing {} example sentences for {language}", pairs.len());
+    window.emit(
+        "importing_dictionary",
+        Some(ToasterPayload {
+            message: Some("Indexing example sentences"),
+        }),
+    )?;
+
+    let mut store = ExampleStore::default();
+    let mut result = Ok(());
+    for batch in pairs.chunks(IMPORT_BATCH_SIZE) {
+        let texts: Vec<String> = batch.iter().map(|(text, _)| text.clone()).collect();
+        let lemmas = match sentence_lemmas(&texts, &mut state.0.lock().await) {
+            Ok(lemmas) => lemmas,
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        };
+        for ((text, translation), lemmas) in batch.iter().cloned().zip(lemmas) {
+            store.add(text, translation, lemmas);
+        }
+    }
+
+    window.emit(
+        "importing_dictionary",
+        Some(ToasterPayload { message: None }),
+    )?;
+    result?;
+    store.save(&language)?;
+    let imported = store.sentences.len();
+    state
+        .0
+        .lock()
+        .await
+        .example_stores
+        .insert(language, Arc::new(store));
+    Ok(imported)
+}
+
+/// Example sentences for the lemma from the imported sentences of the current language
+#[tauri::command]
+pub async fn get_examples(
+    state: State<'_, KalbaState>,
+    lemma: String,
+) -> Result<Vec<Example>, KalbaError> {
+    let mut state = state.0.lock().await;
+    let language = state
+        .current_language
+        .clone()
+        .expect("current language should already be selected");
+    let store = match state.example_stores.get(&language) {
+        Some(store) => Arc::clone(store),
+        None => {
+            let store = Arc::new(ExampleStore::load(&language)?);
+            state
+                .example_stores
+                .insert(language.clone(), Arc::clone(&store));
+            store
+        }
+    };
+    let settings = &state
+        .settings
+        .languages
+        .get(&language)
+        .expect("language should exist")
+        .examples;
+    let words = &state
+        .to_save
+        .language_specific
+        .get(&language)
+        .expect("language should exist")
+        .words;
+    Ok(
+        store.examples(&lemma, settings.count, settings.order, |lemma| {
+            words.get(lemma).map(|info| info.rating)
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pairs_with_and_without_ids() {
+        assert_eq!(
+            read_pairs("Labas.\tHi.\nGeras oras.\n\n1\tAčiū.\t2\tThanks.\nVienas\t\n"),
+            vec![
+                (String::from("Labas."), Some(String::from("Hi."))),
+                (String::from("Geras oras."), None),
+                (String::from("Ačiū."), Some(String::from("Thanks."))),
+                (String::from("Vienas"), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn tatoeba_sentences_are_linked() {
+        let sentences = "1\tlit\tLabas.\n2\teng\tHi.\n3\tlit\tAčiū.\n4\tdeu\tDanke.\n";
+        let links = "1\t2\n2\t1\n3\t4\n";
+        assert_eq!(
+            read_tatoeba(sentences, links, "lit", "eng"),
+            vec![
+                (String::from("Labas."), Some(String::from("Hi."))),
+                (String::from("Ačiū."), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn examples_are_ranked() {
+        let mut store = ExampleStore::default();
+        store.add(
+            String::from("Geras nežinomas žodis."),
+            None,
+            vec![
+                String::from("geras"),
+                String::from("nežinomas"),
+                String::from("žodis"),
+            ],
+        );
+        store.add(
+            String::from("Labai geras žodis ir geras oras."),
+            Some(String::from("A very good word and good weather.")),
+            vec![
+                String::from("labai"),
+                String::from("geras"),
+                String::from("žodis"),
+                String::from("ir"),
+                String::from("geras"),
+                String::from("oras"),
+            ],
+        );
+        store.add(String::from("Geras."), None, vec![String::from("geras")]);
+        store.add(String::from("Blogas."), None, vec![String::from("blogas")]);
+
+        let ratings = HashMap::from([
+            ("labai", 4),
+            ("žodis", 3),
+            ("ir", 5),
+            ("oras", -1),
+            ("nežinomas", 0),
+        ]);
+        let rating = |lemma: &str| ratings.get(lemma).copied();
+        let texts = |examples: Vec<Example>| -> Vec<String> {
+            examples.into_iter().map(|example| example.text).collect()
+        };
+
+        assert_eq!(
+            texts(store.examples("geras", 2, ExampleOrder::MostComprehensible, rating)),
+            vec!["Geras.", "Labai geras žodis ir geras oras."]
+        );
+        assert_eq!(
+            texts(store.examples("geras", 5, ExampleOrder::Shortest, rating)),
+            vec![
+                "Geras.",
+                "Geras nežinomas žodis.",
+                "Labai geras žodis ir geras oras."
+            ]
+        );
+        assert!(store
+            .examples("nėra", 5, ExampleOrder::Shortest, rating)
+            .is_empty());
+    }
+}
@@ -0,0 +1,345 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io::{BufRead, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use shared::{Definition, DefinitionEntry, Example, Sense, StructuredDefinition, ToasterPayload};
+use tauri::{Emitter, State, Window};
+
+use crate::{dictionary::DictionaryInfo, KalbaError, KalbaState};
+
+/// Forms with these tags describe the inflection table itself rather than a form of the word
+const IGNORED_FORM_TAGS: [&str; 3] = ["table-tags", "inflection-template", "class"];
+const MAX_FORMS_SHOWN: usize = 16;
+
+/// Maps every headword of a kaikki.org JSONL extract to the location of its lines in the file
+#[derive(Serialize, Deserialize)]
+pub struct KaikkiIndex {
+    source_len: u64,
+    source_modified: Option<SystemTime>,
+    entries: HashMap<String, Vec<(u64, u32)>>,
+}
+
+#[derive(Deserialize)]
+struct KaikkiHeadword {
+    word: String,
+}
+
+#[derive(Deserialize)]
+struct KaikkiEntry {
+    word: String,
+    #[serde(default)]
+    pos: String,
+    #[serde(default)]
+    senses: Vec<KaikkiSense>,
+    #[serde(default)]
+    sounds: Vec<KaikkiSound>,
+    #[serde(default)]
+    forms: Vec<KaikkiForm>,
+}
+
+#[derive(Deserialize)]
+struct KaikkiSense {
+    #[serde(default)]
+    glosses: Vec<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    examples: Vec<KaikkiExample>,
+}
+
+#[derive(Deserialize)]
+struct KaikkiExample {
+    text: String,
+    #[serde(alias = "english")]
+    translation: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct KaikkiSound {
+    ipa: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct KaikkiForm {
+    form: String,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+fn index_location(source: &Path) -> Result<PathBuf, KalbaError> {
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+    Ok(dirs::data_dir()
+        .ok_or_else(|| KalbaError::MissingDir(String::from("data")))?
+        .join("kalba")
+        .join("language_data")
+        .join("kaikki")
+        .join(format!("{:x}", hasher.finish())))
+}
+
+impl KaikkiIndex {
+    pub fn build(source: &Path) -> Result<Self, KalbaError> {
+        let metadata = fs::metadata(source)?;
+        let mut reader = BufReader::new(File::open(source)?);
+        let mut entries: HashMap<String, Vec<(u64, u32)>> = HashMap::new();
+
+        let mut offset = 0;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let length = reader.read_line(&mut line)?;
+            if length == 0 {
+                break;
+            }
+            match serde_json::from_str::<KaikkiHeadword>(&line) {
+                Ok(headword) => entries
+                    .entry(headword.word)
+                    .or_default()
+                    .push((offset, length as u32)),
+                Err(e) => log::warn!("Skipping invalid kaikki line at byte {offset}: {e}"),
+            }
+            offset += length as u64;
+        }
+
+        Ok(Self {
+            source_len: metadata.len(),
+            source_modified: metadata.modified().ok(),
+            entries,
+        })
+    }
+
+    fn is_up_to_date(&self, source: &Path) -> bool {
+        fs::metadata(source).is_ok_and(|metadata| {
+            metadata.len() == self.source_len && metadata.modified().ok() == self.source_modified
+        })
+    }
+
+    /// Loads the saved index for the extract, rebuilding it if the extract has changed
+    pub fn load_or_build(source: &Path) -> Result<Self, KalbaError> {
+        let location = index_location(source)?;
+        if let Ok(saved) = fs::read(&location) {
+            match rmp_serde::from_slice::<Self>(&saved) {
+                Ok(index) if index.is_up_to_date(source) => return Ok(index),
+                Ok(_) => log::info!("kaikki extract {source:?} changed, rebuilding index"),
+                Err(e) => log::warn!("unable to read kaikki index: {e}"),
+            }
+        }
+        log::info!("building kaikki index for {source:?}");
+        let index = Self::build(source)?;
+        if let Some(parent) = location.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(
+            location,
+            rmp_serde::to_vec(&index).expect("kaikki index to be serializable"),
+        )?;
+        Ok(index)
+    }
+
+    fn read_entries(&self, source: &Path, word: &str) -> Result<Vec<KaikkiEntry>, KalbaError> {
+        let Some(locations) = self.entries.get(word) else {
+            return Ok(Vec::new());
+        };
+        let mut file = File::open(source)?;
+        let mut entries = Vec::new();
+        for (offset, length) in locations {
+            file.seek(SeekFrom::Start(*offset))?;
+            let mut line = vec![0; *length as usize];
+            file.read_exact(&mut line)?;
+            match serde_json::from_slice::<KaikkiEntry>(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => log::warn!("Unable to read kaikki entry for {word}: {e}"),
+            }
+        }
+        Ok(entries)
+    }
+
+    pub fn definition(&self, source: &Path, lemma: &str) -> Result<Definition, KalbaError> {
+        let entries = self.read_entries(source, lemma)?;
+        if entries.is_empty() {
+            return Ok(Definition::Empty);
+        }
+        Ok(Definition::Structured(StructuredDefinition {
+            headword: lemma.to_owned(),
+            entries: entries.into_iter().map(convert_entry).collect(),
+        }))
+    }
+}
+
+fn convert_entry(entry: KaikkiEntry) -> DefinitionEntry {
+    let forms = entry
+        .forms
+        .into_iter()
+        .filter(|form| {
+            form.form != entry.word
+                && !form
+                    .tags
+                    .iter()
+                    .any(|tag| IGNORED_FORM_TAGS.contains(&tag.as_str()))
+        })
+        .take(MAX_FORMS_SHOWN)
+        .map(|form| {
+            if form.tags.is_empty() {
+                form.form
+            } else {
+                format!("{} ({})", form.form, form.tags.join(", "))
+            }
+        })
+        .collect();
+    DefinitionEntry {
+        part_of_speech: Some(entry.pos).filter(|pos| !pos.is_empty()),
+        gender: None,
+        ipa: entry
+            .sounds
+            .into_iter()
+            .filter_map(|sound| sound.ipa)
+            .collect(),
+        senses: entry
+            .senses
+            .into_iter()
+            .filter(|sense| !sense.glosses.is_empty())
+            .map(|sense| Sense {
+                gloss: sense.glosses.join("; "),
+                tags: sense.tags,
+                examples: sense
+                    .examples
+                    .into_iter()
+                    .map(|example| Example {
+                        text: example.text,
+                        translation: example.translation,
+                    })
+                    .collect(),
+            })
+            .collect(),
+        forms,
+    }
+}
+
+pub async fn get_kaikki(
+    dict_info: Arc<tauri::async_runtime::Mutex<DictionaryInfo>>,
+    lemma: &str,
+    file: &str,
+) -> Result<Definition, KalbaError> {
+    let index = load_index(dict_info, file).await?;
+    index.definition(Path::new(file), lemma)
+}
+
+async fn load_index(
+    dict_info: Arc<tauri::async_runtime::Mutex<DictionaryInfo>>,
+    file: &str,
+) -> Result<Arc<KaikkiIndex>, KalbaError> {
+    // Only hold the lock while picking the cell so that other lookups aren't blocked by indexing,
+    // and concurrent lookups of the same file wait for one build instead of starting their own
+    let cell = Arc::clone(
+        dict_info
+            .lock()
+            .await
+            .kaikki_indexes
+            .entry(file.to_owned())
+            .or_default(),
+    );
+    let index = cell
+        .get_or_try_init(|| async {
+            let source = PathBuf::from(file);
+            tauri::async_runtime::spawn_blocking(move || KaikkiIndex::load_or_build(&source))
+                .await
+                .expect("indexing task to finish")
+                .map(Arc::new)
+        })
+        .await?;
+    Ok(Arc::clone(index))
+}
+
+#[tauri::command]
+pub async fn import_kaikki(
+    state: State<'_, KalbaState>,
+    window: Window,
+    file_path: String,
+) -> Result<(), KalbaError> {
+    let dict_info = Arc::clone(&state.0.lock().await.dict_info);
+    window.emit(
+        "importing_dictionary",
+        Some(ToasterPayload {
+            message: Some("Indexing Wiktionary extract"),
+        }),
+    )?;
+    dict_info.lock().await.kaikki_indexes.remove(&file_path);
+    let result = load_index(dict_info, &file_path).await;
+    window.emit(
+        "importing_dictionary",
+        Some(ToasterPayload { message: None }),
+    )?;
+    result.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::DefinitionStyling;
+
+    use super::*;
+    use crate::structured;
+
+    fn get_kaikki_file() -> PathBuf {
+        [
+            env!("CARGO_MANIFEST_DIR"),
+            "resources",
+            "test",
+            "kaikki_sample.jsonl",
+        ]
+        .iter()
+        .collect()
+    }
+
+    #[test]
+    fn index_contains_every_headword() {
+        let index = KaikkiIndex::build(&get_kaikki_file()).unwrap();
+        assert_eq!(index.entries.len(), 2);
+        assert_eq!(index.entries.get("geras").unwrap().len(), 2);
+        assert!(index.is_up_to_date(&get_kaikki_file()));
+    }
+
+    #[test]
+    fn lookup_separates_parts_of_speech() {
+        let f = get_kaikki_file();
+        let index = KaikkiIndex::build(&f).unwrap();
+        let Definition::Structured(definition) = index.definition(&f, "geras").unwrap() else {
+            panic!("kaikki definitions should be structured");
+        };
+        assert_eq!(definition.entries.len(), 2);
+        assert_eq!(
+            definition.entries[0].forms,
+            vec!["gera (feminine)", "geresnis (comparative)"]
+        );
+        let styling = DefinitionStyling {
+            definition: String::from("d"),
+            info: String::from("i"),
+            main_detail: String::from("m"),
+        };
+        assert_eq!(
+            structured::to_html(&definition, &styling),
+            concat!(
+                "<div><span style=\"m\">geras</span> <span style=\"i\">adj</span> <span>/ɡʲɛˈrɐs/</span></div>",
+                "<ol><li><span style=\"d\">good</span><ul><li><i>Geras žmogus.</i> — A good person.</li></ul></li>",
+                "<li><span style=\"d\">kind</span> <span style=\"i\">(figuratively)</span></li></ol>",
+                "<p style=\"i\">gera (feminine), geresnis (comparative)</p>",
+                "<hr>",
+                "<div><span style=\"m\">geras</span> <span style=\"i\">noun</span></div>",
+                "<ol><li><span style=\"d\">good &amp; kind person</span></li></ol>",
+            )
+        );
+    }
+
+    #[test]
+    fn lookup_missing_word() {
+        let f = get_kaikki_file();
+        let index = KaikkiIndex::build(&f).unwrap();
+        assert_eq!(index.definition(&f, "nėra").unwrap(), Definition::Empty);
+    }
+}
@@ -39,13 +39,18 @@ enum SectionContents {
     SpecificSection(Section),
 }
 
+const URL_CONTENTS_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[tauri::command]
 pub async fn get_url_contents(url: &str) -> Result<String, KalbaError> {
-    Ok(reqwest::get(url)
+    Ok(reqwest::Client::builder()
+        .timeout(URL_CONTENTS_TIMEOUT)
+        .build()?
+        .get(url)
+        .send()
         .await?
         .text()
-        .await
-        .expect("to get valid bytes"))
+        .await?)
 }
 
 enum FileType {
@@ -590,24 +595,20 @@ fn normalize_newlines(text: &str) -> String {
     result
 }
 
-fn stanza_parser(
-    sent: &str,
-    state: &mut MutexGuard<SharedInfo>,
-    language: String,
-    interpreter: &Interpreter,
-) -> Result<(Vec<String>, Vec<Word>), KalbaError> {
-    let language_parser = state
-        .language_parser
-        .as_mut()
-        .expect("language parser to be started");
-
-    let sent_formatted = format!("{}\n", normalize_newlines(sent));
+/// Sends text that ends with an empty line to stanza and reads back its sentences
+fn run_stanza(
+    language_parser: &mut LanguageParser,
+    sent_formatted: &str,
+) -> Result<Vec<Sentence>, KalbaError> {
     let bytes_written = language_parser
         .stdin
         .write(sent_formatted.as_bytes())
         .expect("to write to stdin");
     if bytes_written != sent_formatted.len() {
-        return Err(KalbaError::IncorrectWrite(sent_formatted, bytes_written));
+        return Err(KalbaError::IncorrectWrite(
+            sent_formatted.to_owned(),
+            bytes_written,
+        ));
     }
 
     log::trace!("sentence written");
@@ -629,8 +630,22 @@ fn stanza_parser(
         }
         contents.push_str(&specific_contents);
     }
-    let details =
-        serde_json::from_str::<Vec<Sentence>>(&contents).expect("valid json from stanza parser");
+    Ok(serde_json::from_str::<Vec<Sentence>>(&contents).expect("valid json from stanza parser"))
+}
+
+fn stanza_parser(
+    sent: &str,
+    state: &mut MutexGuard<SharedInfo>,
+    language: String,
+    interpreter: &Interpreter,
+) -> Result<(Vec<String>, Vec<Word>), KalbaError> {
+    let language_parser = state
+        .language_parser
+        .as_mut()
+        .expect("language parser to be started");
+
+    let sent_formatted = format!("{}\n", normalize_newlines(sent));
+    let details = run_stanza(language_parser, &sent_formatted)?;
     log::trace!("response parsed");
 
     let mut words = Vec::new();
@@ -723,6 +738,7 @@ fn stanza_parser(
                     lemma: lemma.clone(),
                     rating,
                     morph,
+                    pos: Some(token.upos.clone()),
                     sentence_index,
                     clickable,
                     other_forms: if clickable {
@@ -745,6 +761,7 @@ fn stanza_parser(
                     last_word.text = text;
                     last_word.rating = rating;
                     last_word.morph = morph;
+                    last_word.pos = Some(token.upos.clone());
                     last_word.other_forms = if clickable {
                         get_alternate_forms(&lemma, interpreter, state)?
                     } else {
@@ -759,6 +776,49 @@ fn stanza_parser(
     Ok((sentences, words))
 }
 
+/// Whether the character is part of a word when texts are split without stanza
+fn is_word_char(c: char) -> bool {
+    c.is_alphabetic()
+}
+
+/// A word found without stanza, which is its own lemma. It is marked as seen if it is new.
+fn default_word(
+    word: String,
+    whitespace_after: bool,
+    sentence_index: usize,
+    language: &str,
+    state: &mut MutexGuard<SharedInfo>,
+    interpreter: &Interpreter,
+) -> Result<Word, KalbaError> {
+    let rating = state
+        .to_save
+        .language_specific
+        .get_mut(language)
+        .expect("language to be chosen")
+        .words
+        .entry(word.clone())
+        .or_insert(crate::WordInfo {
+            rating: 0,
+            method: crate::Method::FromSeen,
+            history: vec![(chrono::Utc::now(), crate::Method::FromSeen, 0)],
+        })
+        .rating;
+
+    Ok(Word {
+        display_text: word.clone(),
+        text: word.clone(),
+        clickable: true,
+        lemma: word.clone(),
+        rating,
+        morph: HashMap::new(),
+        pos: None,
+        other_forms: get_alternate_forms(&word, interpreter, state)?,
+        length: word.chars().count(),
+        whitespace_after,
+        sentence_index,
+    })
+}
+
 fn default_tokenizer(
     sent: &str,
     language: String,
@@ -776,40 +836,19 @@ fn default_tokenizer(
     let mut chars = sent.chars().peekable();
     while let Some(c) = chars.next() {
         current_sentence.push(c);
-        if c.is_alphabetic() {
+        if is_word_char(c) {
             currently_building.push(c);
         } else {
             if !currently_building.is_empty() {
                 let word = std::mem::take(&mut currently_building);
-                // let lemma = handle_lemma(&token.lemma, interpreter)?;
-                let rating = state
-                    .to_save
-                    .language_specific
-                    .get_mut(&language)
-                    .expect("language to be chosen")
-                    .words
-                    .entry(word.clone())
-                    .or_insert(crate::WordInfo {
-                        rating: 0,
-                        method: crate::Method::FromSeen,
-                        history: vec![(chrono::Utc::now(), crate::Method::FromSeen, 0)],
-                    })
-                    .rating;
-
-                let whitespace_after = c.is_whitespace();
-
-                words.push(Word {
-                    display_text: word.clone(),
-                    text: word.clone(),
-                    clickable: true,
-                    lemma: word.clone(),
-                    rating,
-                    morph: HashMap::new(),
-                    other_forms: get_alternate_forms(&word, interpreter, state)?,
-                    length: word.chars().count(),
-                    whitespace_after,
-                    sentence_index: sentences.len(),
-                })
+                words.push(default_word(
+                    word,
+                    c.is_whitespace(),
+                    sentences.len(),
+                    &language,
+                    state,
+                    interpreter,
+                )?);
             }
             let mut whitespace_after = false;
             while let Some(possible_whitespace) = chars.peek() {
@@ -832,6 +871,7 @@ fn default_tokenizer(
                 lemma: c.to_string(),
                 rating: -1,
                 morph: HashMap::new(),
+                pos: None,
                 other_forms: Vec::new(),
                 length: 1,
                 whitespace_after,
@@ -846,35 +886,82 @@ fn default_tokenizer(
 
     if !currently_building.is_empty() {
         let word = std::mem::take(&mut currently_building);
-        let rating = state
-            .to_save
-            .language_specific
-            .get_mut(&language)
-            .expect("language to be chosen")
-            .words
-            .entry(word.clone())
-            .or_insert(crate::WordInfo {
-                rating: 0,
-                method: crate::Method::FromSeen,
-                history: vec![(chrono::Utc::now(), crate::Method::FromSeen, 0)],
-            })
-            .rating;
-
-        words.push(Word {
-            display_text: word.clone(),
-            text: word.clone(),
-            clickable: true,
-            lemma: word.clone(),
-            rating,
-            morph: HashMap::new(),
-            other_forms: get_alternate_forms(&word, interpreter, state)?,
-            length: word.chars().count(),
-            whitespace_after: true,
-            sentence_index: sentences.len(),
-        })
+        words.push(default_word(
+            word,
+            true,
+            sentences.len(),
+            &language,
+            state,
+            interpreter,
+        )?);
     }
     if !current_sentence.is_empty() {
         sentences.push(current_sentence);
     }
     Ok((sentences, words))
 }
+
+/// Finds the lemmas of the words in each sentence with the same tokenizer and lemma handling that
+/// is used for texts, without marking any of the words as seen. The sentences can't be empty.
+pub fn sentence_lemmas(
+    sentences: &[String],
+    state: &mut MutexGuard<SharedInfo>,
+) -> Result<Vec<Vec<String>>, KalbaError> {
+    let interpreter = load_spyglys(state)?;
+    let lines: Vec<String> = sentences
+        .iter()
+        .map(|sentence| {
+            sentence
+                .nfc()
+                .collect::<String>()
+                .replace(['\n', '\r'], " ")
+        })
+        .collect();
+    let mut lemmas = vec![Vec::new(); sentences.len()];
+
+    if state.language_parser.is_some() && state.settings.stanza_enabled {
+        // Every sentence is put on its own line, so the character offsets from stanza show which
+        // sentence each word is from
+        let mut starts = Vec::with_capacity(lines.len());
+        let mut text = String::new();
+        let mut offset = 0;
+        for line in &lines {
+            starts.push(offset);
+            offset += line.chars().count() + 1;
+            text.push_str(line);
+            text.push('\n');
+        }
+        text.push('\n');
+
+        let language_parser = state
+            .language_parser
+            .as_mut()
+            .expect("language parser to be started");
+        let tokens = run_stanza(language_parser, &text)?
+            .into_iter()
+            .flat_map(|sentence| sentence.words);
+        let mut sentence_index = 0;
+        for token in tokens {
+            if let Some(start) = token.start_char {
+                sentence_index = starts
+                    .partition_point(|&line_start| line_start <= start)
+                    .saturating_sub(1);
+            }
+            if !["PUNCT", "SYM", "NUM"].contains(&token.upos.as_str()) {
+                let lemma = handle_lemma(&token.lemma, &interpreter, state)?;
+                lemmas[sentence_index].push(lemma);
+            }
+        }
+    } else {
+        // Words are split like in `default_tokenizer`
+        for (line, line_lemmas) in lines.iter().zip(&mut lemmas) {
+            for word in line
+                .split(|c: char| !is_word_char(c))
+                .filter(|word| !word.is_empty())
+            {
+                line_lemmas.push(handle_lemma(word, &interpreter, state)?);
+            }
+        }
+    }
+    Ok(lemmas)
+}
@@ -3,35 +3,72 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 use crate::{
-    add_to_anki::{add_to_anki, get_export_variables},
+    add_to_anki::{add_to_anki, get_export_variables, is_in_anki, RenderedNote},
+    anki_queue::{
+        drop_queued_note, edit_queued_note, get_anki_queue, send_anki_queue, send_queued_notes,
+        QueuedNote,
+    },
     ankiconnect::{get_all_deck_names, get_all_note_names, get_note_field_names, remove_deck},
+    apkg::{export_apkg, get_apkg_queue, queue_for_apkg, remove_from_apkg_queue},
+    cache::{clear_cache, get_cache_info, DefinitionCache},
     dictionary::{get_definition_on_demand, get_defs, DictionaryInfo},
+    examples::{get_examples, import_examples, ExampleStore},
+    kaikki::import_kaikki,
     language_parsing::{get_url_contents, parse_text, parse_url, read_file, start_stanza},
     new_language_template::new_language_from_template,
+    personal_dictionary::{
+        check_dictionary_names, delete_personal_entry, export_personal_dictionary,
+        get_personal_dictionary, get_personal_entry, import_personal_dictionary,
+        rename_clashing_dictionaries, set_personal_entry, PersonalEntry, PERSONAL_DICTIONARY,
+    },
+    prefetch::{cancel_prefetch, prefetch_definitions},
+    pronunciation::get_pronunciation,
     setup_stanza::{check_stanza_installed, setup_stanza, uninstall_stanza},
+    translation::{translate_sentence, TRANSLATION_CACHE},
 };
-use ankiconnect::get_anki_card_statuses;
+use ankiconnect::{get_anki_card_statuses, AnkiSyncProgress, DeckSync};
 use chrono::{DateTime, TimeDelta, Utc};
-use commands::run_command;
+use commands::run_startup_command;
 use new_language_template::use_language_template;
 use serde::{Deserialize, Serialize};
-use shared::{Definition, LanguageSettings, Settings, StartingSettings, ToasterPayload};
+use shared::{LanguageSettings, Settings, StartingSettings, ToasterPayload};
 use simple_logger::SimpleLogger;
 use spyglys_integration::{format_spyglys, get_spyglys_functions};
 use stats::{get_words_added, get_words_known_at_levels, time_spent};
-use std::{collections::HashMap, fs, io::BufReader, process, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    fs,
+    io::BufReader,
+    process,
+    sync::{atomic::AtomicUsize, Arc},
+    time::Duration,
+};
 use tauri::{async_runtime::block_on, Emitter, Manager, State, Window, WindowEvent};
 use tokio::sync::MutexGuard;
 
 mod add_to_anki;
+mod anki_queue;
 mod ankiconnect;
+mod apkg;
+mod cache;
 mod commands;
 mod dictionary;
+mod examples;
+mod kaikki;
 mod language_parsing;
+mod mdict;
 mod new_language_template;
+mod personal_dictionary;
+mod prefetch;
+mod pronunciation;
 mod setup_stanza;
 mod spyglys_integration;
 mod stats;
+mod structured;
+mod template;
+#[cfg(test)]
+mod test_server;
+mod translation;
 
 #[derive(Debug, thiserror::Error)]
 enum KalbaError {
@@ -73,6 +110,42 @@ enum KalbaError {
     WrongPythonVersion(String),
     #[error("File {0} is not a valid file type. See the docs for more.")]
     InvalidFileType(String),
+    #[error("Unable to read MDict dictionary {0}: {1}")]
+    MDict(String, String),
+    #[error("{0} did not respond within {1} seconds")]
+    Timeout(String, u64),
+    #[error("{0} needs an internet connection, but offline mode is enabled")]
+    OfflineMode(String),
+    #[error("No program was given for the command")]
+    EmptyCommand,
+    #[error("{0} failed: {1}")]
+    CommandFailed(String, String),
+    #[error("The command did not print a valid definition: {0}")]
+    InvalidCommandOutput(serde_json::Error),
+    #[error("Line {0} of the personal dictionary needs at least a word and a definition")]
+    InvalidPersonalDictionary(usize),
+    #[error("Invalid JSONPath {0}: {1}")]
+    InvalidJsonPath(String, String),
+    #[error("{0} did not return valid JSON: {1}")]
+    InvalidResponse(String, String),
+    #[error("{0} did not produce any audio")]
+    NoAudio(String),
+    #[error("No sentence translation is set up for this language")]
+    NoTranslationBackend,
+    #[error("Unable to read media file {0}: {1}")]
+    InvalidMedia(String, String),
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Zip(#[from] zip::result::ZipError),
+    #[error("There are no words waiting to be exported")]
+    NothingToExport,
+    #[error("The template of the {0} field is invalid: {1}")]
+    InvalidTemplate(String, String),
+    #[error("{0} is already in Anki")]
+    AlreadyInAnki(String),
+    #[error("{0} is the name of the personal dictionary, so dictionaries in {1} can't use it")]
+    ReservedDictionaryName(String, String),
 }
 
 // we must manually implement serde::Serialize
@@ -95,15 +168,14 @@ struct SharedInfo {
     dict_info: Arc<tauri::async_runtime::Mutex<DictionaryInfo>>,
     errors: Vec<KalbaError>,
     can_save: bool,
-    language_cached_data: HashMap<String, CachedData>,
+    definition_cache: DefinitionCache,
+    /// Increased whenever a prefetch is started or cancelled, which stops the previous prefetch
+    prefetch_generation: Arc<AtomicUsize>,
+    /// Example sentences of each language, loaded when they are first needed
+    example_stores: HashMap<String, Arc<ExampleStore>>,
     in_reader: bool,
 }
 
-#[derive(Debug, Deserialize, Serialize, Default)]
-struct CachedData {
-    definitions: HashMap<String, HashMap<String, Definition>>,
-}
-
 struct LanguageParser {
     stdin: process::ChildStdin,
     stdout: BufReader<process::ChildStdout>,
@@ -118,9 +190,11 @@ struct ToSave {
     installing_stanza: bool,
     last_launched: DateTime<Utc>,
     last_language: Option<String>,
-    decks_checked: Vec<String>,
     language_specific: HashMap<String, LanguageSpecificToSave>,
     sessions: Vec<(DateTime<Utc>, Duration)>,
+    /// Notes that could not be added because Anki was not available
+    #[serde(default)]
+    anki_queue: Vec<QueuedNote>,
     #[serde(default = "default_version")]
     kalba_version: String,
 }
@@ -133,6 +207,13 @@ struct LanguageSpecificToSave {
     previous_amount: usize,
     words_seen: Vec<(DateTime<Utc>, usize)>,
     added_to_anki: Vec<(DateTime<Utc>, String)>,
+    #[serde(default)]
+    personal_dictionary: HashMap<String, PersonalEntry>,
+    #[serde(default)]
+    anki_decks: HashMap<String, DeckSync>,
+    /// Words waiting to be exported to an Anki package
+    #[serde(default)]
+    apkg_queue: Vec<RenderedNote>,
 }
 
 impl Default for SharedInfo {
@@ -159,7 +240,7 @@ impl Default for SharedInfo {
             }
         };
 
-        let settings: Settings = match dirs::config_dir()
+        let mut settings: Settings = match dirs::config_dir()
             .ok_or_else(|| KalbaError::MissingDir(String::from("config")))
             .and_then(|config_file| {
                 fs::read_to_string(config_file.join("kalba.toml"))
@@ -179,34 +260,45 @@ impl Default for SharedInfo {
             }
         };
 
-        let language_cached_data = match dirs::cache_dir()
+        let mut definition_cache = match dirs::cache_dir()
             .ok_or_else(|| KalbaError::MissingDir(String::from("cache")))
             .and_then(|cache_dir| {
-                fs::read(cache_dir.join("kalba").join("dictionaries"))
-                    .map_err(KalbaError::Io)
-                    .and_then(|v| rmp_serde::from_slice(&v).map_err(KalbaError::CacheDecode))
+                DefinitionCache::open(cache_dir.join("kalba"), settings.definition_cache.clone())
             }) {
             Ok(v) => v,
             Err(e) => {
-                if !matches!(e, KalbaError::Io(_)) {
-                    errors.push(e);
-                }
-                HashMap::new()
+                errors.push(e);
+                DefinitionCache::default()
             }
         };
+        // Definitions cached for a renamed dictionary were cached under the old name
+        for language in rename_clashing_dictionaries(&mut settings) {
+            if definition_cache
+                .dictionaries(&language)
+                .any(|dictionary| dictionary == PERSONAL_DICTIONARY)
+            {
+                definition_cache.remove_dictionary(&language, PERSONAL_DICTIONARY);
+            }
+        }
 
         if let Err(e) = block_on(set_word_knowledge_from_anki(
             &mut to_save,
             &settings.languages,
             false,
             settings.anki_port,
+            &|_| (),
         )) {
             errors.push(e);
         }
+        if let Err(e) = block_on(send_queued_notes(&mut to_save, settings.anki_port)) {
+            errors.push(e);
+        }
 
         if let Some(cmds) = &settings.to_run {
             for cmd in cmds {
-                _ = run_command(cmd);
+                if let Err(e) = run_startup_command(cmd) {
+                    log::warn!("Unable to run startup command {cmd:?}: {e}");
+                }
             }
         }
 
@@ -227,7 +319,9 @@ impl Default for SharedInfo {
             current_language,
             dict_info: Default::default(),
             can_save,
-            language_cached_data,
+            definition_cache,
+            prefetch_generation: Default::default(),
+            example_stores: HashMap::new(),
             in_reader: false,
         }
     }
@@ -299,7 +393,31 @@ fn main() {
             get_url_contents,
             switch_page,
             check_version,
-            uninstall_stanza
+            uninstall_stanza,
+            import_kaikki,
+            get_cache_info,
+            clear_cache,
+            prefetch_definitions,
+            cancel_prefetch,
+            get_personal_dictionary,
+            get_personal_entry,
+            set_personal_entry,
+            delete_personal_entry,
+            import_personal_dictionary,
+            export_personal_dictionary,
+            import_examples,
+            get_examples,
+            get_pronunciation,
+            translate_sentence,
+            queue_for_apkg,
+            get_apkg_queue,
+            remove_from_apkg_queue,
+            export_apkg,
+            get_anki_queue,
+            edit_queued_note,
+            drop_queued_note,
+            send_anki_queue,
+            is_in_anki
         ])
         .on_window_event(handle_window_event)
         .run(tauri::generate_context!())
@@ -324,8 +442,29 @@ async fn refresh_anki(
     let languages = state.settings.languages.clone();
     let anki_port = state.settings.anki_port;
 
-    set_word_knowledge_from_anki(&mut state.to_save, &languages, force_all, anki_port).await?;
+    let on_progress = |progress: AnkiSyncProgress| {
+        if let Err(e) = window.emit("anki_sync_progress", progress) {
+            log::warn!("Unable to send anki sync progress: {e}");
+        }
+    };
+    set_word_knowledge_from_anki(
+        &mut state.to_save,
+        &languages,
+        force_all,
+        anki_port,
+        &on_progress,
+    )
+    .await?;
     log::trace!("Anki data loaded [forced: {force_all}]");
+    let sent = send_queued_notes(&mut state.to_save, anki_port).await?;
+    if sent > 0 {
+        window.emit(
+            "added_to_anki",
+            Some(ToasterPayload {
+                message: Some(&format!("Added {sent} queued notes to anki")),
+            }),
+        )?;
+    }
     window.emit("refresh_anki", ToasterPayload { message: None })?;
     Ok(())
 }
@@ -373,32 +512,49 @@ async fn set_word_knowledge_from_anki(
     languages: &HashMap<String, LanguageSettings>,
     force_all: bool,
     port: u16,
+    on_progress: &(dyn Fn(AnkiSyncProgress) + Sync),
 ) -> Result<(), KalbaError> {
-    let new_time = Utc::now();
-    let days_passed = new_time
-        .signed_duration_since(to_save.last_launched)
-        .num_days()
-        + 2;
-
     for (language_name, language) in languages {
         let to_save_language = to_save
             .language_specific
             .entry(language_name.to_owned())
             .or_default();
+        let mut removed = Vec::new();
         for (deck, note_parser) in &language.anki_parser {
-            get_anki_card_statuses(
-                deck,
-                &note_parser.0,
-                &mut to_save_language.words,
-                days_passed,
-                // If the deck has not been added, it means this is the first time it is being
-                // checked, so we should check every card and not just the ones recently
-                // updated
-                force_all || !to_save.decks_checked.contains(deck),
-                port,
-            )
-            .await?;
-            to_save.decks_checked.push(deck.to_owned());
+            let sync = to_save_language
+                .anki_decks
+                .entry(deck.to_owned())
+                .or_default();
+            if force_all {
+                sync.modified = 0;
+            }
+            removed.extend(
+                get_anki_card_statuses(
+                    deck,
+                    &note_parser.0,
+                    &language.anki_ratings,
+                    &mut to_save_language.words,
+                    sync,
+                    port,
+                    on_progress,
+                )
+                .await?,
+            );
+        }
+        for word in removed {
+            let still_in_anki = to_save_language
+                .anki_decks
+                .values()
+                .any(|deck| deck.words.contains_key(&word));
+            if !still_in_anki
+                && to_save_language
+                    .words
+                    .get(&word)
+                    .is_some_and(|info| info.method == Method::FromAnki)
+            {
+                log::info!("removing {word}, as no note has it anymore");
+                to_save_language.words.remove(&word);
+            }
         }
 
         if Some(&language.frequency_list) != to_save_language.previous_file.as_ref()
@@ -413,7 +569,7 @@ async fn set_word_knowledge_from_anki(
             );
         }
     }
-    to_save.last_launched = new_time;
+    to_save.last_launched = Utc::now();
     Ok(())
 }
 
@@ -449,16 +605,9 @@ fn handle_window_event(window: &Window, event: &WindowEvent) {
                 let state: State<'_, KalbaState> = window.state();
                 let mut locked_state = state.0.lock().await;
                 let _ = save_state(&mut locked_state);
-                let cache_file = dirs::cache_dir()
-                    .expect("cache dir does not exist")
-                    .join("kalba")
-                    .join("dictionaries");
-                fs::write(
-                    cache_file,
-                    rmp_serde::to_vec_named(&locked_state.language_cached_data)
-                        .expect("error serializing cache"),
-                )
-                .expect("error writing cache");
+                if let Err(e) = locked_state.definition_cache.compact() {
+                    log::warn!("Unable to save definition cache: {e}");
+                }
             }
             _ => (),
         }
@@ -559,9 +708,9 @@ async fn rename_language(
             .language_specific
             .insert(new_name.clone(), old);
     }
-    if let Some(old) = state.language_cached_data.remove(&original_name) {
-        state.language_cached_data.insert(new_name.clone(), old);
-    }
+    state
+        .definition_cache
+        .rename_language(&original_name, &new_name);
     if new_selected_language {
         state.current_language = Some(new_name);
     }
@@ -576,20 +725,69 @@ async fn write_settings(
     let config_file = dirs::config_dir()
         .ok_or(KalbaError::MissingDir("config".to_string()))?
         .join("kalba.toml");
+    check_dictionary_names(&settings)?;
     let conts = toml::to_string_pretty(&settings)?;
 
     let mut state = state.0.lock().await;
 
-    // TODO: avoid this clone (arc)
-    let cloned_languages = state.settings.languages.clone();
-
-    for (language, specific_settings) in &cloned_languages {
-        if let Some(new_specific_settings) = settings.languages.get(language) {
-            if new_specific_settings.dicts != specific_settings.dicts {
-                if let Some(language_cache) = state.language_cached_data.get_mut(language) {
-                    language_cache.definitions.clear();
+    // Only the definitions of dictionaries that were changed or removed are invalidated
+    let SharedInfo {
+        settings: old_settings,
+        definition_cache: cache,
+        ..
+    } = &mut *state;
+    let cached_languages: Vec<String> = cache.languages().cloned().collect();
+    for language in cached_languages {
+        let Some(new_language) = settings.languages.get(&language) else {
+            cache.remove_language(&language);
+            continue;
+        };
+        // Languages that were just renamed are only in the new settings
+        let Some(old_language) = old_settings.languages.get(&language) else {
+            continue;
+        };
+        let find_dictionary = |language: &LanguageSettings, name: &str| {
+            language
+                .dicts
+                .iter()
+                .find(|dict| dict.name == name)
+                .map(|dict| dict.specific_settings.clone())
+        };
+        let outdated: Vec<String> = cache
+            .dictionaries(&language)
+            .filter(|dictionary| {
+                if *dictionary == TRANSLATION_CACHE {
+                    return old_language.translation.backend != new_language.translation.backend;
                 }
-            }
+                let old = find_dictionary(old_language, dictionary);
+                old.is_none() || old != find_dictionary(new_language, dictionary)
+            })
+            .cloned()
+            .collect();
+        for dictionary in outdated {
+            cache.remove_dictionary(&language, &dictionary);
+        }
+    }
+    cache.set_settings(settings.definition_cache.clone());
+
+    // Recording folders that were changed or removed are indexed again the next time they are used
+    let outdated_recordings: Vec<String> = old_settings
+        .languages
+        .iter()
+        .filter(|(language, old_language)| {
+            settings
+                .languages
+                .get(*language)
+                .map_or(true, |new_language| {
+                    new_language.pronunciation.recordings != old_language.pronunciation.recordings
+                })
+        })
+        .map(|(_, old_language)| old_language.pronunciation.recordings.clone())
+        .collect();
+    if !outdated_recordings.is_empty() {
+        let mut dict_info = state.dict_info.lock().await;
+        for folder in outdated_recordings {
+            dict_info.recording_indexes.remove(&folder);
         }
     }
 
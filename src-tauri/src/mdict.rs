@@ -0,0 +1,709 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use encoding_rs::{Encoding, UTF_16LE, UTF_8};
+use flate2::read::ZlibDecoder;
+use lol_html::{element, html_content::ContentType, rewrite_str, RewriteStrSettings};
+use shared::Definition;
+
+use crate::KalbaError;
+
+/// How many `@@@LINK=` redirects are followed before giving up
+const MAX_LINK_DEPTH: usize = 5;
+
+struct KeyEntry {
+    key: String,
+    record_start: u64,
+    record_end: u64,
+}
+
+struct RecordBlock {
+    file_offset: u64,
+    compressed_size: u64,
+    decompressed_offset: u64,
+    decompressed_size: u64,
+}
+
+/// A single parsed .mdx or .mdd file. Only the headword index and the location of the record
+/// blocks are kept in memory, the records themselves are read from disk on lookup.
+pub struct MDictFile {
+    path: PathBuf,
+    encoding: &'static Encoding,
+    is_resource: bool,
+    keys: HashMap<String, Vec<KeyEntry>>,
+    record_blocks: Vec<RecordBlock>,
+}
+
+/// An .mdx dictionary along with any .mdd resource files found next to it
+pub struct MDict {
+    definitions: MDictFile,
+    resources: Vec<MDictFile>,
+    directory: Option<PathBuf>,
+}
+
+fn mdict_error(path: &Path, message: &str) -> KalbaError {
+    KalbaError::MDict(path.to_string_lossy().to_string(), message.to_owned())
+}
+
+fn read_number(data: &[u8], width: usize) -> u64 {
+    data[..width]
+        .iter()
+        .fold(0, |number, byte| (number << 8) | u64::from(*byte))
+}
+
+/// Reads a section of the file, checking its size against what is left of the file first so that
+/// a corrupt size can't allocate more memory than the file could need
+fn read_bytes(
+    reader: &mut (impl Read + Seek),
+    path: &Path,
+    size: u64,
+) -> Result<Vec<u8>, KalbaError> {
+    let position = reader.stream_position()?;
+    let length = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(position))?;
+    if size > length.saturating_sub(position) {
+        return Err(mdict_error(path, "a section is larger than the file"));
+    }
+    let mut bytes = vec![0; size as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// The next `amount` bytes, or `None` if there aren't that many. `amount` can come from the file,
+/// so it may be far larger than the data.
+fn take<'a>(data: &'a [u8], position: &mut usize, amount: usize) -> Option<&'a [u8]> {
+    let end = position.checked_add(amount)?;
+    let taken = data.get(*position..end)?;
+    *position = end;
+    Some(taken)
+}
+
+fn parse_header_attributes(header: &str) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+    let mut rest = header;
+    while let Some(equals) = rest.find("=\"") {
+        let name = rest[..equals]
+            .rsplit(|c: char| c.is_whitespace() || c == '<')
+            .next()
+            .unwrap_or_default();
+        let value = &rest[equals + 2..];
+        let Some(end) = value.find('"') else {
+            break;
+        };
+        attributes.insert(name.to_owned(), value[..end].to_owned());
+        rest = &value[end + 1..];
+    }
+    attributes
+}
+
+fn ripemd128(message: &[u8]) -> [u8; 16] {
+    const R: [usize; 64] = [
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 7, 4, 13, 1, 10, 6, 15, 3, 12, 0, 9,
+        5, 2, 14, 11, 8, 3, 10, 14, 4, 9, 15, 8, 1, 2, 7, 0, 6, 13, 11, 5, 12, 1, 9, 11, 10, 0, 8,
+        12, 4, 13, 3, 7, 15, 14, 5, 6, 2,
+    ];
+    const R_PRIME: [usize; 64] = [
+        5, 14, 7, 0, 9, 2, 11, 4, 13, 6, 15, 8, 1, 10, 3, 12, 6, 11, 3, 7, 0, 13, 5, 10, 14, 15, 8,
+        12, 4, 9, 1, 2, 15, 5, 1, 3, 7, 14, 6, 9, 11, 8, 12, 2, 10, 0, 4, 13, 8, 6, 4, 1, 3, 11,
+        15, 0, 5, 12, 2, 13, 9, 7, 10, 14,
+    ];
+    const S: [u32; 64] = [
+        11, 14, 15, 12, 5, 8, 7, 9, 11, 13, 14, 15, 6, 7, 9, 8, 7, 6, 8, 13, 11, 9, 7, 15, 7, 12,
+        15, 9, 11, 7, 13, 12, 11, 13, 6, 7, 14, 9, 13, 15, 14, 8, 13, 6, 5, 12, 7, 5, 11, 12, 14,
+        15, 14, 15, 9, 8, 9, 14, 5, 6, 8, 6, 5, 12,
+    ];
+    const S_PRIME: [u32; 64] = [
+        8, 9, 9, 11, 13, 15, 15, 5, 7, 7, 8, 11, 14, 14, 12, 6, 9, 13, 15, 7, 12, 8, 9, 11, 7, 7,
+        12, 7, 6, 15, 13, 11, 9, 7, 15, 11, 8, 6, 6, 14, 12, 13, 5, 14, 13, 13, 7, 5, 15, 5, 8, 11,
+        14, 14, 6, 14, 6, 9, 12, 9, 12, 5, 15, 8,
+    ];
+    const K: [u32; 4] = [0x00000000, 0x5a827999, 0x6ed9eba1, 0x8f1bbcdc];
+    const K_PRIME: [u32; 4] = [0x50a28be6, 0x5c4dd124, 0x6d703ef3, 0x00000000];
+
+    fn f(round: usize, x: u32, y: u32, z: u32) -> u32 {
+        match round {
+            0 => x ^ y ^ z,
+            1 => (x & y) | (!x & z),
+            2 => (x | !y) ^ z,
+            _ => (x & z) | (y & !z),
+        }
+    }
+
+    let mut padded = message.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&((message.len() as u64) * 8).to_le_bytes());
+
+    let mut h: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for chunk in padded.chunks(64) {
+        let x: Vec<u32> = chunk
+            .chunks(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+        let (mut a, mut b, mut c, mut d) = (h[0], h[1], h[2], h[3]);
+        let (mut a_prime, mut b_prime, mut c_prime, mut d_prime) = (h[0], h[1], h[2], h[3]);
+        for j in 0..64 {
+            let round = j / 16;
+            let t = a
+                .wrapping_add(f(round, b, c, d))
+                .wrapping_add(x[R[j]])
+                .wrapping_add(K[round])
+                .rotate_left(S[j]);
+            (a, b, c, d) = (d, t, b, c);
+            let t = a_prime
+                .wrapping_add(f(3 - round, b_prime, c_prime, d_prime))
+                .wrapping_add(x[R_PRIME[j]])
+                .wrapping_add(K_PRIME[round])
+                .rotate_left(S_PRIME[j]);
+            (a_prime, b_prime, c_prime, d_prime) = (d_prime, t, b_prime, c_prime);
+        }
+        let t = h[1].wrapping_add(c).wrapping_add(d_prime);
+        h[1] = h[2].wrapping_add(d).wrapping_add(a_prime);
+        h[2] = h[3].wrapping_add(a).wrapping_add(b_prime);
+        h[3] = h[0].wrapping_add(b).wrapping_add(c_prime);
+        h[0] = t;
+    }
+
+    let mut digest = [0; 16];
+    for (i, word) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+fn decrypt_block(path: &Path, block: &[u8]) -> Result<Vec<u8>, KalbaError> {
+    if block.len() < 8 {
+        return Err(mdict_error(path, "encrypted block is too short"));
+    }
+    let mut key_source = block[4..8].to_vec();
+    key_source.extend_from_slice(&0x3695u32.to_le_bytes());
+    let key = ripemd128(&key_source);
+
+    let mut decrypted = block[..8].to_vec();
+    let mut previous = 0x36;
+    for (i, byte) in block[8..].iter().enumerate() {
+        let swapped = byte.rotate_left(4);
+        decrypted.push(swapped ^ previous ^ (i as u8) ^ key[i % key.len()]);
+        previous = *byte;
+    }
+    Ok(decrypted)
+}
+
+fn decompress_block(path: &Path, block: &[u8]) -> Result<Vec<u8>, KalbaError> {
+    if block.len() < 8 {
+        return Err(mdict_error(path, "block is too short"));
+    }
+    match u32::from_le_bytes([block[0], block[1], block[2], block[3]]) {
+        0 => Ok(block[8..].to_vec()),
+        1 => Err(mdict_error(
+            path,
+            "LZO compressed dictionaries are not supported",
+        )),
+        2 => {
+            let mut decompressed = Vec::new();
+            ZlibDecoder::new(&block[8..]).read_to_end(&mut decompressed)?;
+            Ok(decompressed)
+        }
+        _ => Err(mdict_error(path, "unknown compression type")),
+    }
+}
+
+impl MDictFile {
+    pub fn open(path: &Path) -> Result<Self, KalbaError> {
+        let is_resource = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("mdd"));
+        let mut file = File::open(path)?;
+
+        let mut buf = [0; 4];
+        file.read_exact(&mut buf)?;
+        let header = read_bytes(&mut file, path, u64::from(u32::from_be_bytes(buf)))?;
+        // adler32 checksum of the header
+        file.seek(SeekFrom::Current(4))?;
+
+        let (header, _) = UTF_16LE.decode_without_bom_handling(&header);
+        let attributes = parse_header_attributes(header.trim_end_matches('\0'));
+
+        let version: f32 = attributes
+            .get("GeneratedByEngineVersion")
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(2.0);
+        if version >= 3.0 {
+            return Err(mdict_error(path, "MDict version 3 is not supported"));
+        }
+        let number_width = if version >= 2.0 { 8 } else { 4 };
+
+        let encoding = if is_resource {
+            UTF_16LE
+        } else {
+            match attributes.get("Encoding").map(|v| v.trim().to_uppercase()) {
+                None => UTF_8,
+                Some(e) if e.is_empty() || e == "UTF-8" => UTF_8,
+                Some(e) if e == "UTF-16" => UTF_16LE,
+                Some(e) if e == "GBK" || e == "GB2312" => encoding_rs::GB18030,
+                Some(e) => Encoding::for_label(e.as_bytes()).unwrap_or(UTF_8),
+            }
+        };
+
+        let encryption: u32 = match attributes.get("Encrypted").map(|v| v.trim()) {
+            None | Some("") | Some("No") => 0,
+            Some("Yes") => 1,
+            Some(v) => v.parse().unwrap_or(0),
+        };
+        if encryption & 1 != 0 {
+            return Err(mdict_error(
+                path,
+                "dictionaries that require a registration key are not supported",
+            ));
+        }
+
+        // Keyword section
+        let mut keyword_header = vec![0; if version >= 2.0 { 5 * 8 } else { 4 * 4 }];
+        file.read_exact(&mut keyword_header)?;
+        if version >= 2.0 {
+            file.seek(SeekFrom::Current(4))?;
+        }
+        let mut numbers = keyword_header
+            .chunks(number_width)
+            .map(|chunk| read_number(chunk, number_width));
+        let num_key_blocks = numbers.next().unwrap_or_default();
+        let _num_entries = numbers.next();
+        if version >= 2.0 {
+            let _key_block_info_decompressed_size = numbers.next();
+        }
+        let key_block_info_size = numbers.next().unwrap_or_default();
+        let key_blocks_size = numbers.next().unwrap_or_default();
+
+        let mut key_block_info = read_bytes(&mut file, path, key_block_info_size)?;
+        if version >= 2.0 {
+            if encryption & 2 != 0 {
+                key_block_info = decrypt_block(path, &key_block_info)?;
+            }
+            key_block_info = decompress_block(path, &key_block_info)?;
+        }
+
+        let block_sizes =
+            Self::parse_key_block_info(&key_block_info, number_width, version, encoding)
+                .ok_or_else(|| mdict_error(path, "invalid key block info"))?;
+        if block_sizes.len() as u64 != num_key_blocks {
+            return Err(mdict_error(path, "key block count does not match"));
+        }
+
+        let key_blocks = read_bytes(&mut file, path, key_blocks_size)?;
+
+        let mut ordered_keys = Vec::new();
+        let mut position = 0;
+        for (compressed_size, _) in block_sizes {
+            let block = usize::try_from(compressed_size)
+                .ok()
+                .and_then(|size| take(&key_blocks, &mut position, size))
+                .ok_or_else(|| mdict_error(path, "key block is truncated"))?;
+            let block = decompress_block(path, block)?;
+            Self::split_key_block(&block, number_width, encoding, &mut ordered_keys);
+        }
+
+        // Record section
+        let mut record_header = vec![0; 4 * number_width];
+        file.read_exact(&mut record_header)?;
+        let num_record_blocks = read_number(&record_header, number_width);
+        let record_block_info = read_bytes(
+            &mut file,
+            path,
+            (2 * number_width as u64).saturating_mul(num_record_blocks),
+        )?;
+
+        let mut file_offset = file.stream_position()?;
+        let mut decompressed_offset = 0;
+        let mut record_blocks = Vec::new();
+        for sizes in record_block_info.chunks(2 * number_width) {
+            let compressed_size = read_number(sizes, number_width);
+            let decompressed_size = read_number(&sizes[number_width..], number_width);
+            record_blocks.push(RecordBlock {
+                file_offset,
+                compressed_size,
+                decompressed_offset,
+                decompressed_size,
+            });
+            file_offset = file_offset.saturating_add(compressed_size);
+            decompressed_offset = decompressed_offset.saturating_add(decompressed_size);
+        }
+
+        let mut keys: HashMap<String, Vec<KeyEntry>> = HashMap::new();
+        for i in 0..ordered_keys.len() {
+            // the last record continues until the end of the final record block
+            let record_end = ordered_keys
+                .get(i + 1)
+                .map(|(_, start)| *start)
+                .unwrap_or(decompressed_offset);
+            let (key, record_start) = &ordered_keys[i];
+            keys.entry(Self::normalize_key(key, is_resource))
+                .or_default()
+                .push(KeyEntry {
+                    key: key.clone(),
+                    record_start: *record_start,
+                    record_end,
+                });
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            encoding,
+            is_resource,
+            keys,
+            record_blocks,
+        })
+    }
+
+    fn parse_key_block_info(
+        info: &[u8],
+        number_width: usize,
+        version: f32,
+        encoding: &'static Encoding,
+    ) -> Option<Vec<(u64, u64)>> {
+        let (size_width, terminator) = if version >= 2.0 { (2, 1) } else { (1, 0) };
+        let char_width = if encoding == UTF_16LE { 2 } else { 1 };
+
+        let mut sizes = Vec::new();
+        let mut position = 0;
+        while position < info.len() {
+            let _num_entries = take(info, &mut position, number_width)?;
+            for _ in ["first key", "last key"] {
+                let key_size = read_number(take(info, &mut position, size_width)?, size_width);
+                let key_length = usize::try_from(key_size)
+                    .ok()?
+                    .checked_add(terminator)?
+                    .checked_mul(char_width)?;
+                take(info, &mut position, key_length)?;
+            }
+            let compressed_size =
+                read_number(take(info, &mut position, number_width)?, number_width);
+            let decompressed_size =
+                read_number(take(info, &mut position, number_width)?, number_width);
+            sizes.push((compressed_size, decompressed_size));
+        }
+        Some(sizes)
+    }
+
+    fn split_key_block(
+        block: &[u8],
+        number_width: usize,
+        encoding: &'static Encoding,
+        keys: &mut Vec<(String, u64)>,
+    ) {
+        let char_width = if encoding == UTF_16LE { 2 } else { 1 };
+        let mut position = 0;
+        while position + number_width <= block.len() {
+            let record_start = read_number(&block[position..], number_width);
+            let text_start = position + number_width;
+            let mut text_end = text_start;
+            while text_end + char_width <= block.len()
+                && block[text_end..text_end + char_width]
+                    .iter()
+                    .any(|b| *b != 0)
+            {
+                text_end += char_width;
+            }
+            let (key, _) = encoding.decode_without_bom_handling(&block[text_start..text_end]);
+            keys.push((key.into_owned(), record_start));
+            position = text_end + char_width;
+        }
+    }
+
+    fn normalize_key(key: &str, is_resource: bool) -> String {
+        if is_resource {
+            let key = key.trim().replace('/', "\\");
+            let key = key.trim_start_matches('\\');
+            format!("\\{}", key.to_lowercase())
+        } else {
+            key.trim().to_lowercase()
+        }
+    }
+
+    fn read_record(&self, start: u64, end: u64) -> Result<Vec<u8>, KalbaError> {
+        let first_block = self
+            .record_blocks
+            .partition_point(|block| block.decompressed_offset + block.decompressed_size <= start);
+
+        let mut file = File::open(&self.path)?;
+        let mut record = Vec::new();
+        for block in &self.record_blocks[first_block..] {
+            if block.decompressed_offset >= end {
+                break;
+            }
+            file.seek(SeekFrom::Start(block.file_offset))?;
+            let compressed = read_bytes(&mut file, &self.path, block.compressed_size)?;
+            let decompressed = decompress_block(&self.path, &compressed)?;
+
+            let from = start.saturating_sub(block.decompressed_offset) as usize;
+            let to = ((end - block.decompressed_offset) as usize).min(decompressed.len());
+            record.extend_from_slice(&decompressed[from.min(to)..to]);
+        }
+        Ok(record)
+    }
+
+    /// Returns the raw records for a key, preferring entries that match the case exactly
+    pub fn lookup(&self, key: &str) -> Result<Vec<Vec<u8>>, KalbaError> {
+        let Some(entries) = self.keys.get(&Self::normalize_key(key, self.is_resource)) else {
+            return Ok(Vec::new());
+        };
+        let exact_matches: Vec<_> = entries.iter().filter(|e| e.key.trim() == key).collect();
+        let selected = if exact_matches.is_empty() || self.is_resource {
+            entries.iter().collect()
+        } else {
+            exact_matches
+        };
+        selected
+            .into_iter()
+            .map(|entry| self.read_record(entry.record_start, entry.record_end))
+            .collect()
+    }
+
+    pub fn lookup_text(&self, key: &str) -> Result<Vec<String>, KalbaError> {
+        Ok(self
+            .lookup(key)?
+            .iter()
+            .map(|record| {
+                let (text, _) = self.encoding.decode_without_bom_handling(record);
+                text.trim_end_matches(['\0', '\r', '\n']).to_owned()
+            })
+            .collect())
+    }
+}
+
+fn mime_type(resource: &str) -> &'static str {
+    let extension = resource
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "webp" => "image/webp",
+        "bmp" => "image/bmp",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "ogg" | "oga" | "spx" => "audio/ogg",
+        "css" => "text/css",
+        _ => "application/octet-stream",
+    }
+}
+
+impl MDict {
+    /// Opens an .mdx file and every .mdd file next to it (`name.mdd`, `name.1.mdd`, ...)
+    pub fn open(path: &Path) -> Result<Self, KalbaError> {
+        let definitions = MDictFile::open(path)?;
+
+        let mut resources = Vec::new();
+        let resource_path = path.with_extension("mdd");
+        if resource_path.exists() {
+            resources.push(MDictFile::open(&resource_path)?);
+        }
+        let mut resource_number = 1;
+        loop {
+            let resource_path = path.with_extension(format!("{resource_number}.mdd"));
+            if !resource_path.exists() {
+                break;
+            }
+            resources.push(MDictFile::open(&resource_path)?);
+            resource_number += 1;
+        }
+
+        Ok(Self {
+            definitions,
+            resources,
+            directory: path.parent().map(Path::to_path_buf),
+        })
+    }
+
+    fn get_resource(&self, name: &str) -> Option<Vec<u8>> {
+        let name = name
+            .trim_start_matches("file://")
+            .trim_start_matches("sound://");
+        for resource in &self.resources {
+            if let Ok(mut records) = resource.lookup(name) {
+                if !records.is_empty() {
+                    return Some(records.swap_remove(0));
+                }
+            }
+        }
+        // Some dictionaries ship their stylesheets and images as loose files instead. The name comes
+        // from the dictionary, so it can't lead outside of the dictionary's folder.
+        let relative = Path::new(name.trim_start_matches(['/', '\\']));
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return None;
+        }
+        fs::read(self.directory.as_ref()?.join(relative)).ok()
+    }
+
+    fn resource_as_data_uri(&self, name: &str) -> Option<String> {
+        self.get_resource(name)
+            .map(|data| format!("data:{};base64,{}", mime_type(name), STANDARD.encode(data)))
+    }
+
+    fn resolve_entry(&self, lemma: &str, depth: usize) -> Result<Vec<String>, KalbaError> {
+        let mut entries = Vec::new();
+        for entry in self.definitions.lookup_text(lemma)? {
+            if let Some(link) = entry.strip_prefix("@@@LINK=") {
+                if depth < MAX_LINK_DEPTH {
+                    entries.extend(self.resolve_entry(link.trim(), depth + 1)?);
+                }
+            } else {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Replaces references to .mdd resources with data uris so that they can be shown in the
+    /// definition popup and exported along with the definition
+    fn embed_resources(&self, html: &str) -> Result<String, KalbaError> {
+        let element_content_handlers = vec![
+            element!("img[src], audio[src], source[src]", |el| {
+                let src = el.get_attribute("src").unwrap_or_default();
+                if !src.starts_with("data:") && !src.starts_with("http") {
+                    if let Some(data_uri) = self.resource_as_data_uri(&src) {
+                        el.set_attribute("src", &data_uri)?;
+                    }
+                }
+                Ok(())
+            }),
+            element!("a[href^='sound://']", |el| {
+                let href = el.get_attribute("href").unwrap_or_default();
+                if let Some(data_uri) = self.resource_as_data_uri(&href) {
+                    el.replace(
+                        &format!("<audio controls src=\"{data_uri}\"></audio>"),
+                        ContentType::Html,
+                    );
+                }
+                Ok(())
+            }),
+            element!("link[rel='stylesheet'][href]", |el| {
+                let href = el.get_attribute("href").unwrap_or_default();
+                match self.get_resource(&href) {
+                    Some(css) => el.replace(
+                        &format!("<style>{}</style>", String::from_utf8_lossy(&css)),
+                        ContentType::Html,
+                    ),
+                    None => el.remove(),
+                }
+                Ok(())
+            }),
+        ];
+        Ok(rewrite_str(
+            html,
+            RewriteStrSettings {
+                element_content_handlers,
+                ..RewriteStrSettings::default()
+            },
+        )?)
+    }
+
+    pub fn definition(&self, lemma: &str) -> Result<Definition, KalbaError> {
+        let entries = self.resolve_entry(lemma, 0)?;
+        if entries.is_empty() {
+            return Ok(Definition::Empty);
+        }
+        Ok(Definition::Text(
+            self.embed_resources(&entries.join("<hr>"))?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    #[test]
+    fn ripemd128_known_values() {
+        assert_eq!(to_hex(&ripemd128(b"")), "cdf26213a150dc3ecb610f18f6b38b46");
+        assert_eq!(
+            to_hex(&ripemd128(b"abc")),
+            "c14a12199c66e4ba84636b0f69144c77"
+        );
+        assert_eq!(
+            to_hex(&ripemd128(&[b'a'; 200])),
+            "713f09e7385127fd5ff7803d3112ace2"
+        );
+    }
+
+    #[test]
+    fn header_attributes() {
+        let attributes = parse_header_attributes(
+            "<Dictionary GeneratedByEngineVersion=\"2.0\" Encrypted=\"2\" Encoding=\"UTF-8\" Title=\"\"/>",
+        );
+        assert_eq!(attributes.get("GeneratedByEngineVersion").unwrap(), "2.0");
+        assert_eq!(attributes.get("Encrypted").unwrap(), "2");
+        assert_eq!(attributes.get("Encoding").unwrap(), "UTF-8");
+        assert_eq!(attributes.get("Title").unwrap(), "");
+    }
+
+    #[test]
+    fn corrupt_sizes_are_errors() {
+        let path = Path::new("corrupt.mdx");
+        assert!(decrypt_block(path, &[2, 0, 0, 0, 1]).is_err());
+
+        let mut reader = std::io::Cursor::new(vec![0; 16]);
+        reader.seek(SeekFrom::Start(4)).unwrap();
+        assert_eq!(read_bytes(&mut reader, path, 12).unwrap().len(), 12);
+        reader.seek(SeekFrom::Start(4)).unwrap();
+        assert!(read_bytes(&mut reader, path, 13).is_err());
+        assert!(read_bytes(&mut reader, path, u64::MAX).is_err());
+
+        // A header claiming to be 4GB long
+        let file = std::env::temp_dir().join("kalba_corrupt_header.mdx");
+        fs::write(&file, [0xff, 0xff, 0xff, 0xff, 0, 0]).unwrap();
+        assert!(MDictFile::open(&file).is_err());
+        fs::remove_file(file).unwrap();
+
+        // Sizes that overflow when they are added to the position
+        let mut position = 2;
+        assert!(take(&[0; 4], &mut position, usize::MAX).is_none());
+        assert_eq!(position, 2);
+        assert_eq!(take(&[0; 4], &mut position, 2), Some(&[0, 0][..]));
+    }
+
+    #[test]
+    fn loose_files_stay_in_the_dictionary_folder() {
+        let folder = std::env::temp_dir().join("kalba_loose_files");
+        let directory = folder.join("dictionary");
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("style.css"), "b { color: red }").unwrap();
+        fs::write(folder.join("secret.txt"), "secret").unwrap();
+        let mdict = MDict {
+            definitions: MDictFile {
+                path: directory.join("dictionary.mdx"),
+                encoding: UTF_8,
+                is_resource: false,
+                keys: HashMap::new(),
+                record_blocks: Vec::new(),
+            },
+            resources: Vec::new(),
+            directory: Some(directory),
+        };
+        assert_eq!(
+            mdict.get_resource("file:///style.css").unwrap(),
+            b"b { color: red }"
+        );
+        assert!(mdict.get_resource("../secret.txt").is_none());
+        assert!(mdict
+            .get_resource("sound://images/../../secret.txt")
+            .is_none());
+        fs::remove_dir_all(folder).unwrap();
+    }
+}
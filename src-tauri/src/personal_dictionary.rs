@@ -0,0 +1,371 @@
+use std::{collections::HashMap, fs};
+
+use serde::{Deserialize, Serialize};
+use shared::{Definition, DefinitionStyling, Settings};
+use tauri::State;
+
+use crate::{structured::escape_html, KalbaError, KalbaState, SharedInfo};
+
+/// Name the personal dictionary has in definitions and exports
+pub const PERSONAL_DICTIONARY: &str = "Personal";
+
+/// The user's own gloss and notes (for example a mnemonic) for a word
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+pub struct PersonalEntry {
+    pub gloss: String,
+    #[serde(default)]
+    pub notes: String,
+}
+
+impl PersonalEntry {
+    pub fn to_definition(&self, definition_styling: &DefinitionStyling) -> Definition {
+        let mut html = format!(
+            "<p style=\"{}\">{}</p>",
+            definition_styling.definition,
+            escape_html(&self.gloss)
+        );
+        if !self.notes.is_empty() {
+            html.push_str(&format!(
+                "<p style=\"{}\">{}</p>",
+                definition_styling.info,
+                escape_html(&self.notes).replace('\n', "<br>")
+            ));
+        }
+        Definition::Text(html)
+    }
+}
+
+/// Renames the dictionaries that have the personal dictionary's name, which wasn't reserved
+/// before. Returns the languages that had one.
+pub fn rename_clashing_dictionaries(settings: &mut Settings) -> Vec<String> {
+    let mut renamed = Vec::new();
+    for (language, language_settings) in &mut settings.languages {
+        let dicts = &mut language_settings.dicts;
+        for i in 0..dicts.len() {
+            if dicts[i].name != PERSONAL_DICTIONARY {
+                continue;
+            }
+            let name = (2..)
+                .map(|n| format!("{PERSONAL_DICTIONARY} ({n})"))
+                .find(|name| !dicts.iter().any(|dict| dict.name == *name))
+                .expect("some name to be free");
+            log::warn!("Renaming the {PERSONAL_DICTIONARY} dictionary of {language} to {name}");
+            dicts[i].name = name;
+            renamed.push(language.clone());
+        }
+    }
+    renamed
+}
+
+/// The personal dictionary's definitions are cached and exported under its name, so no other
+/// dictionary can have it
+pub fn check_dictionary_names(settings: &Settings) -> Result<(), KalbaError> {
+    for (language, language_settings) in &settings.languages {
+        if language_settings
+            .dicts
+            .iter()
+            .any(|dict| dict.name == PERSONAL_DICTIONARY)
+        {
+            return Err(KalbaError::ReservedDictionaryName(
+                PERSONAL_DICTIONARY.to_owned(),
+                language.clone(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub fn personal_definition(state: &SharedInfo, language: &str, lemma: &str) -> Option<Definition> {
+    state
+        .to_save
+        .language_specific
+        .get(language)?
+        .personal_dictionary
+        .get(lemma)
+        .map(|entry| entry.to_definition(&state.settings.definition_styling))
+}
+
+fn escape_tsv(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape_tsv(field: &str) -> String {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Writes the entries as lines of `lemma<TAB>gloss<TAB>notes`, sorted by lemma
+fn to_tsv(entries: &HashMap<String, PersonalEntry>) -> String {
+    let mut lemmas: Vec<&String> = entries.keys().collect();
+    lemmas.sort();
+    let mut tsv = String::new();
+    for lemma in lemmas {
+        let entry = &entries[lemma];
+        tsv.push_str(&format!(
+            "{}\t{}\t{}\n",
+            escape_tsv(lemma),
+            escape_tsv(&entry.gloss),
+            escape_tsv(&entry.notes)
+        ));
+    }
+    tsv
+}
+
+fn from_tsv(tsv: &str) -> Result<Vec<(String, PersonalEntry)>, KalbaError> {
+    let mut entries = Vec::new();
+    for (i, line) in tsv.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut fields = line.split('\t').map(unescape_tsv);
+        let (Some(lemma), Some(gloss)) = (fields.next(), fields.next()) else {
+            return Err(KalbaError::InvalidPersonalDictionary(i + 1));
+        };
+        entries.push((
+            lemma,
+            PersonalEntry {
+                gloss,
+                notes: fields.next().unwrap_or_default(),
+            },
+        ));
+    }
+    Ok(entries)
+}
+
+/// The entries of the language, which has none if nothing was saved for it yet
+fn entries<'a>(
+    state: &'a SharedInfo,
+    language: &str,
+) -> Option<&'a HashMap<String, PersonalEntry>> {
+    state
+        .to_save
+        .language_specific
+        .get(language)
+        .map(|saved| &saved.personal_dictionary)
+}
+
+/// The entries of the language, adding the language to what is saved if it isn't yet
+fn entries_mut<'a>(
+    state: &'a mut SharedInfo,
+    language: &str,
+) -> &'a mut HashMap<String, PersonalEntry> {
+    &mut state
+        .to_save
+        .language_specific
+        .entry(language.to_owned())
+        .or_default()
+        .personal_dictionary
+}
+
+fn current_language(state: &SharedInfo) -> String {
+    state
+        .current_language
+        .clone()
+        .expect("current language should already be selected")
+}
+
+#[tauri::command]
+pub async fn get_personal_dictionary(
+    state: State<'_, KalbaState>,
+    language: String,
+) -> Result<HashMap<String, PersonalEntry>, KalbaError> {
+    let state = state.0.lock().await;
+    Ok(entries(&state, &language).cloned().unwrap_or_default())
+}
+
+#[tauri::command]
+pub async fn get_personal_entry(
+    state: State<'_, KalbaState>,
+    lemma: String,
+) -> Result<Option<PersonalEntry>, KalbaError> {
+    let state = state.0.lock().await;
+    Ok(entries(&state, &current_language(&state))
+        .and_then(|entries| entries.get(&lemma))
+        .cloned())
+}
+
+/// Adds or replaces the entry for the lemma and returns how it is shown as a definition
+#[tauri::command]
+pub async fn set_personal_entry(
+    state: State<'_, KalbaState>,
+    lemma: String,
+    entry: PersonalEntry,
+) -> Result<Definition, KalbaError> {
+    let mut state = state.0.lock().await;
+    let definition = entry.to_definition(&state.settings.definition_styling);
+    let language = current_language(&state);
+    entries_mut(&mut state, &language).insert(lemma, entry);
+    Ok(definition)
+}
+
+#[tauri::command]
+pub async fn delete_personal_entry(
+    state: State<'_, KalbaState>,
+    lemma: String,
+) -> Result<(), KalbaError> {
+    let mut state = state.0.lock().await;
+    let language = current_language(&state);
+    entries_mut(&mut state, &language).remove(&lemma);
+    Ok(())
+}
+
+/// Adds the entries from a TSV file, replacing existing entries for the same lemmas. Returns how
+/// many entries were imported.
+#[tauri::command]
+pub async fn import_personal_dictionary(
+    state: State<'_, KalbaState>,
+    language: String,
+    file_path: String,
+) -> Result<usize, KalbaError> {
+    let entries = from_tsv(&fs::read_to_string(file_path)?)?;
+    let imported = entries.len();
+    let mut state = state.0.lock().await;
+    entries_mut(&mut state, &language).extend(entries);
+    Ok(imported)
+}
+
+#[tauri::command]
+pub async fn export_personal_dictionary(
+    state: State<'_, KalbaState>,
+    language: String,
+    file_path: String,
+) -> Result<(), KalbaError> {
+    let tsv = to_tsv(entries(&*state.0.lock().await, &language).unwrap_or(&HashMap::new()));
+    fs::write(file_path, tsv)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::{Dictionary, DictionarySpecificSettings, LanguageSettings};
+
+    use super::*;
+
+    #[test]
+    fn clashing_dictionaries_are_renamed_when_loading() {
+        let dictionary = |name: &str| Dictionary {
+            name: name.to_owned(),
+            run_when_not: None,
+            specific_settings: DictionarySpecificSettings::Kaikki(String::from("words.jsonl")),
+            fetch_by_default: true,
+            timeout: 10,
+            retries: 2,
+            rules: Vec::new(),
+        };
+        let mut settings = Settings::default();
+        settings.languages.insert(
+            String::from("Lithuanian"),
+            LanguageSettings {
+                dicts: vec![dictionary("Personal"), dictionary("Personal (2)")],
+                ..Default::default()
+            },
+        );
+        let config = toml::to_string_pretty(&settings).unwrap();
+
+        let mut settings: Settings = toml::from_str(&config).unwrap();
+        assert_eq!(
+            rename_clashing_dictionaries(&mut settings),
+            vec![String::from("Lithuanian")]
+        );
+        let names: Vec<&str> = settings.languages["Lithuanian"]
+            .dicts
+            .iter()
+            .map(|dict| dict.name.as_str())
+            .collect();
+        assert_eq!(names, ["Personal (3)", "Personal (2)"]);
+        // The renamed settings can be saved, and aren't renamed again after loading them
+        assert!(check_dictionary_names(&settings).is_ok());
+        let saved_config = toml::to_string_pretty(&settings).unwrap();
+        let mut saved: Settings = toml::from_str(&saved_config).unwrap();
+        assert!(rename_clashing_dictionaries(&mut saved).is_empty());
+        assert_eq!(toml::to_string_pretty(&saved).unwrap(), saved_config);
+
+        // Dictionaries can't be given the name afterwards
+        saved.languages.get_mut("Lithuanian").unwrap().dicts[1].name = String::from("Personal");
+        assert!(matches!(
+            check_dictionary_names(&saved),
+            Err(KalbaError::ReservedDictionaryName(..))
+        ));
+    }
+
+    #[test]
+    fn tsv_round_trip() {
+        let entries = HashMap::from([
+            (
+                String::from("labas"),
+                PersonalEntry {
+                    gloss: String::from("hi"),
+                    notes: String::new(),
+                },
+            ),
+            (
+                String::from("geras"),
+                PersonalEntry {
+                    gloss: String::from("good\tkind"),
+                    notes: String::from("sounds like \"gear\"\nC:\\path"),
+                },
+            ),
+        ]);
+        let tsv = to_tsv(&entries);
+        assert_eq!(
+            tsv,
+            "geras\tgood\\tkind\tsounds like \"gear\"\\nC:\\\\path\nlabas\thi\t\n"
+        );
+        let imported: HashMap<String, PersonalEntry> =
+            from_tsv(&tsv).unwrap().into_iter().collect();
+        assert_eq!(imported, entries);
+    }
+
+    #[test]
+    fn tsv_without_notes() {
+        assert_eq!(
+            from_tsv("labas\thi\n\n").unwrap(),
+            vec![(
+                String::from("labas"),
+                PersonalEntry {
+                    gloss: String::from("hi"),
+                    notes: String::new(),
+                }
+            )]
+        );
+        assert!(matches!(
+            from_tsv("labas\thi\nmissing gloss\n"),
+            Err(KalbaError::InvalidPersonalDictionary(2))
+        ));
+    }
+
+    #[test]
+    fn entry_as_definition() {
+        let styling = DefinitionStyling {
+            definition: String::from("d"),
+            info: String::from("i"),
+            main_detail: String::from("m"),
+        };
+        let entry = PersonalEntry {
+            gloss: String::from("good & kind"),
+            notes: String::from("first line\nsecond line"),
+        };
+        assert_eq!(
+            entry.to_definition(&styling),
+            Definition::Text(String::from(
+                "<p style=\"d\">good &amp; kind</p><p style=\"i\">first line<br>second line</p>"
+            ))
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tauri::{Emitter, Manager, State, Window};
+use tokio::time::Instant;
+
+use crate::{
+    dictionary::{
+        cache_definitions, cached_definitions, fetch_definitions, FetchOptions, WordDetails,
+    },
+    KalbaError, KalbaState,
+};
+
+/// Time between requests to the same website while prefetching
+const PREFETCH_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+/// How many words are looked up at once. Requests to each website are still spaced out by the
+/// rate limiter, so this mostly lets offline dictionaries run ahead of the online ones.
+const PREFETCH_CONCURRENCY: usize = 4;
+
+/// Spaces out requests to each provider so that prefetching a long text doesn't flood a website
+pub struct RateLimiter {
+    interval: Duration,
+    next_request: tokio::sync::Mutex<HashMap<String, Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next_request: Default::default(),
+        }
+    }
+
+    /// Waits until a request can be sent to the provider
+    pub async fn wait(&self, provider: &str) {
+        let scheduled = {
+            let mut next_request = self.next_request.lock().await;
+            let now = Instant::now();
+            let scheduled = next_request
+                .get(provider)
+                .copied()
+                .filter(|scheduled| *scheduled > now)
+                .unwrap_or(now);
+            next_request.insert(provider.to_owned(), scheduled + self.interval);
+            scheduled
+        };
+        tokio::time::sleep_until(scheduled).await;
+    }
+}
+
+/// A word of the text to prefetch, with what is known about it for the dictionary rules
+#[derive(Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct PrefetchWord {
+    lemma: String,
+    pos: Option<String>,
+    #[serde(default)]
+    morph: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Serialize)]
+struct PrefetchProgress {
+    done: usize,
+    total: usize,
+}
+
+async fn prefetch_word(
+    window: &Window,
+    language: &str,
+    options: &FetchOptions,
+    word: PrefetchWord,
+) {
+    let state: State<'_, KalbaState> = window.state();
+    let lemma = &word.lemma;
+    let (dicts, cached) = cached_definitions(&*state.0.lock().await, language, lemma);
+    let details = WordDetails {
+        pos: word.pos,
+        morph: word.morph.into_iter().collect(),
+    };
+    let defs = fetch_definitions(&dicts, &cached, options, lemma, &details, |_, _| ()).await;
+    cache_definitions(&mut *state.0.lock().await, language, lemma, &defs, &cached);
+}
+
+/// Fetches and caches the definitions of the words in the background. Words with the same lemma
+/// but a different part of speech or features are looked up again, as dictionary rules can depend
+/// on those, but only the dictionaries that aren't cached yet are used. Starting a new prefetch
+/// cancels the previous one.
+#[tauri::command]
+pub async fn prefetch_definitions(
+    state: State<'_, KalbaState>,
+    window: Window,
+    words: Vec<PrefetchWord>,
+) -> Result<(), KalbaError> {
+    let (language, options, generation, prefetch_generation) = {
+        let state = state.0.lock().await;
+        let language = state
+            .current_language
+            .clone()
+            .expect("current language should already be selected");
+        let mut options = FetchOptions::from_state(&state);
+        options.rate_limiter = Some(Arc::new(RateLimiter::new(PREFETCH_REQUEST_INTERVAL)));
+        let generation = state.prefetch_generation.fetch_add(1, Ordering::SeqCst) + 1;
+        (
+            language,
+            options,
+            generation,
+            Arc::clone(&state.prefetch_generation),
+        )
+    };
+
+    let mut seen = HashSet::new();
+    let words: Vec<PrefetchWord> = words
+        .into_iter()
+        .filter(|word| seen.insert(word.clone()))
+        .collect();
+    let total = words.len();
+    log::info!("prefetching definitions for {total} words");
+
+    tauri::async_runtime::spawn(async move {
+        let is_cancelled = || prefetch_generation.load(Ordering::SeqCst) != generation;
+        let done = AtomicUsize::new(0);
+
+        futures::stream::iter(words)
+            .for_each_concurrent(PREFETCH_CONCURRENCY, |word| {
+                let (window, language, options, done) = (&window, &language, &options, &done);
+                async move {
+                    if is_cancelled() {
+                        return;
+                    }
+                    prefetch_word(window, language, options, word).await;
+
+                    let done = done.fetch_add(1, Ordering::SeqCst) + 1;
+                    if !is_cancelled() {
+                        if let Err(e) =
+                            window.emit("prefetch_progress", PrefetchProgress { done, total })
+                        {
+                            log::warn!("Unable to send prefetch progress: {e}");
+                        }
+                    }
+                }
+            })
+            .await;
+        log::info!("finished prefetching definitions");
+    });
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn cancel_prefetch(state: State<'_, KalbaState>) -> Result<(), KalbaError> {
+    state
+        .0
+        .lock()
+        .await
+        .prefetch_generation
+        .fetch_add(1, Ordering::SeqCst);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_to_the_same_provider_are_spaced_out() {
+        tauri::async_runtime::block_on(async {
+            let rate_limiter = RateLimiter::new(Duration::from_millis(50));
+            let start = Instant::now();
+            rate_limiter.wait("wiktionary").await;
+            rate_limiter.wait("wordreference").await;
+            assert!(start.elapsed() < Duration::from_millis(50));
+            rate_limiter.wait("wiktionary").await;
+            rate_limiter.wait("wiktionary").await;
+            assert!(start.elapsed() >= Duration::from_millis(100));
+        });
+    }
+}
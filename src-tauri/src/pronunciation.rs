@@ -0,0 +1,238 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use shared::PronunciationSettings;
+use tauri::{ipc::Response, State};
+
+use crate::{commands::run_with_word_bytes, dictionary::DictionaryInfo, KalbaError, KalbaState};
+
+const AUDIO_EXTENSIONS: [&str; 7] = ["wav", "mp3", "ogg", "oga", "opus", "flac", "m4a"];
+const TTS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The recordings in a folder by the lowercase word they are of
+pub type RecordingIndex = HashMap<String, PathBuf>;
+
+/// Finds the word a recording is of from its file name. Lingua Libre names recordings like
+/// `LL-Q9067 (lit)-User-labas` and Forvo like `pronunciation_lt_labas`, and anything else is
+/// expected to be named after the word.
+fn recording_word(stem: &str) -> String {
+    if let Some((_, word)) = stem
+        .strip_prefix("LL-")
+        .and_then(|rest| rest.split_once(")-"))
+        .and_then(|(_, rest)| rest.split_once('-'))
+    {
+        return word.to_owned();
+    }
+    if let Some((_, word)) = stem
+        .strip_prefix("pronunciation_")
+        .and_then(|rest| rest.split_once('_'))
+    {
+        return word.replace('_', " ");
+    }
+    stem.to_owned()
+}
+
+fn index_recordings(folder: &Path, index: &mut RecordingIndex) -> Result<(), KalbaError> {
+    for entry in fs::read_dir(folder)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            index_recordings(&path, index)?;
+            continue;
+        }
+        let is_audio = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| AUDIO_EXTENSIONS.contains(&&*extension.to_lowercase()));
+        let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        if is_audio {
+            let word = recording_word(stem).to_lowercase();
+            index.entry(word).or_insert(path);
+        }
+    }
+    Ok(())
+}
+
+async fn find_recording(
+    dict_info: Arc<tauri::async_runtime::Mutex<DictionaryInfo>>,
+    folder: &str,
+    word: &str,
+) -> Result<Option<PathBuf>, KalbaError> {
+    // The folder is walked without holding the lock, and only once for concurrent lookups
+    let cell = Arc::clone(
+        dict_info
+            .lock()
+            .await
+            .recording_indexes
+            .entry(folder.to_owned())
+            .or_default(),
+    );
+    let index = cell
+        .get_or_try_init(|| async {
+            let source = PathBuf::from(folder);
+            tauri::async_runtime::spawn_blocking(move || {
+                let mut index = RecordingIndex::new();
+                index_recordings(&source, &mut index).map(|_| Arc::new(index))
+            })
+            .await
+            .expect("indexing task to finish")
+        })
+        .await?;
+    Ok(index.get(&word.to_lowercase()).cloned())
+}
+
+fn audio_extension(audio: &[u8]) -> &'static str {
+    if audio.starts_with(b"OggS") {
+        "ogg"
+    } else if audio.starts_with(b"fLaC") {
+        "flac"
+    } else if audio.starts_with(b"ID3") || audio.starts_with(&[0xFF, 0xFB]) {
+        "mp3"
+    } else {
+        "wav"
+    }
+}
+
+/// Synthesizes the text with the text to speech command. The audio is kept, so the same text is
+/// only synthesized once.
+async fn synthesize(args: &[String], text: &str) -> Result<PathBuf, KalbaError> {
+    let mut hasher = DefaultHasher::new();
+    (args, text).hash(&mut hasher);
+    let name = format!("tts_{:x}", hasher.finish());
+    let folder = dirs::cache_dir()
+        .ok_or_else(|| KalbaError::MissingDir(String::from("cache")))?
+        .join("kalba")
+        .join("audio");
+    fs::create_dir_all(&folder)?;
+    if let Some(saved) = AUDIO_EXTENSIONS
+        .iter()
+        .map(|extension| folder.join(format!("{name}.{extension}")))
+        .find(|path| path.exists())
+    {
+        return Ok(saved);
+    }
+
+    let program = args
+        .iter()
+        .find(|arg| !arg.is_empty())
+        .cloned()
+        .unwrap_or_default();
+    if args.iter().any(|arg| arg.contains("{output}")) {
+        let output = folder.join(format!("{name}.wav"));
+        let args: Vec<String> = args
+            .iter()
+            .map(|arg| arg.replace("{output}", &output.to_string_lossy()))
+            .collect();
+        run_with_word_bytes(&args, text, false, TTS_TIMEOUT).await?;
+        if !output.exists() {
+            return Err(KalbaError::NoAudio(program));
+        }
+        Ok(output)
+    } else {
+        let audio = run_with_word_bytes(args, text, false, TTS_TIMEOUT).await?;
+        if audio.is_empty() {
+            return Err(KalbaError::NoAudio(program));
+        }
+        let output = folder.join(format!("{name}.{}", audio_extension(&audio)));
+        fs::write(&output, audio)?;
+        Ok(output)
+    }
+}
+
+/// Finds a recording of the text, or synthesizes it if there is none. Returns `None` when neither
+/// recordings nor text to speech are set up.
+pub async fn pronunciation(
+    settings: &PronunciationSettings,
+    dict_info: Arc<tauri::async_runtime::Mutex<DictionaryInfo>>,
+    text: &str,
+) -> Result<Option<PathBuf>, KalbaError> {
+    let text = text.trim();
+    if !settings.recordings.is_empty() {
+        if let Some(recording) = find_recording(dict_info, &settings.recordings, text).await? {
+            return Ok(Some(recording));
+        }
+    }
+    if settings.tts.iter().all(String::is_empty) {
+        return Ok(None);
+    }
+    synthesize(&settings.tts, text).await.map(Some)
+}
+
+/// The name the audio is stored with in Anki's media folder
+pub fn media_file_name(path: &Path) -> String {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("wav");
+    format!("kalba_audio_{:x}.{extension}", hasher.finish())
+}
+
+/// Audio of a word or sentence in the current language, which is empty if there is none
+#[tauri::command]
+pub async fn get_pronunciation(
+    state: State<'_, KalbaState>,
+    text: String,
+) -> Result<Response, KalbaError> {
+    let (settings, dict_info) = {
+        let state = state.0.lock().await;
+        let language = state
+            .current_language
+            .as_ref()
+            .expect("current language should already be selected");
+        let settings = state
+            .settings
+            .languages
+            .get(language)
+            .expect("language should exist")
+            .pronunciation
+            .clone();
+        (settings, Arc::clone(&state.dict_info))
+    };
+    let audio = match pronunciation(&settings, dict_info, &text).await? {
+        Some(path) => fs::read(path)?,
+        None => Vec::new(),
+    };
+    Ok(Response::new(audio))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_from_recording_names() {
+        assert_eq!(recording_word("LL-Q9067 (lit)-Eitvys200-labas"), "labas");
+        assert_eq!(
+            recording_word("pronunciation_lt_labas_rytas"),
+            "labas rytas"
+        );
+        assert_eq!(recording_word("geras"), "geras");
+    }
+
+    #[test]
+    fn recordings_are_indexed() {
+        let folder: PathBuf = [
+            env!("CARGO_MANIFEST_DIR"),
+            "resources",
+            "test",
+            "recordings",
+        ]
+        .iter()
+        .collect();
+        let mut index = RecordingIndex::new();
+        index_recordings(&folder, &mut index).unwrap();
+        let mut words: Vec<&String> = index.keys().collect();
+        words.sort();
+        assert_eq!(words, ["ačiū", "geras", "labas"]);
+        assert!(index["labas"].ends_with("LL-Q9067 (lit)-Eitvys200-Labas.wav"));
+    }
+}
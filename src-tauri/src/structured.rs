@@ -0,0 +1,286 @@
+use shared::{Definition, DefinitionEntry, DefinitionStyling, StructuredDefinition};
+
+/// The parts of a structured definition that can be exported on their own with `{def:NAME:PART}`
+pub const EXPORT_PARTS: [&str; 6] = [
+    "first_sense",
+    "examples",
+    "pos",
+    "gender",
+    "ipa",
+    "headword",
+];
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Turns character references back into the characters they stand for, so that text taken out of
+/// HTML isn't escaped twice
+pub fn unescape_html(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let reference = rest.find(';').and_then(|end| {
+            let c = match &rest[1..end] {
+                "amp" => '&',
+                "lt" => '<',
+                "gt" => '>',
+                "quot" => '"',
+                "apos" => '\'',
+                "nbsp" => ' ',
+                reference => {
+                    let number = reference.strip_prefix('#')?;
+                    let code = match number.strip_prefix(['x', 'X']) {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                        None => number.parse().ok()?,
+                    };
+                    char::from_u32(code)?
+                }
+            };
+            Some((c, end))
+        });
+        match reference {
+            Some((c, end)) => {
+                unescaped.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+fn entry_to_html(
+    headword: &str,
+    entry: &DefinitionEntry,
+    definition_styling: &DefinitionStyling,
+) -> String {
+    let mut html = format!(
+        "<div><span style=\"{}\">{}</span>",
+        definition_styling.main_detail,
+        escape_html(headword),
+    );
+    for detail in [&entry.part_of_speech, &entry.gender].into_iter().flatten() {
+        html.push_str(&format!(
+            " <span style=\"{}\">{}</span>",
+            definition_styling.info,
+            escape_html(detail)
+        ));
+    }
+    if !entry.ipa.is_empty() {
+        html.push_str(&format!(
+            " <span>{}</span>",
+            escape_html(&entry.ipa.join(", "))
+        ));
+    }
+    html.push_str("</div><ol>");
+
+    for sense in &entry.senses {
+        html.push_str(&format!(
+            "<li><span style=\"{}\">{}</span>",
+            definition_styling.definition,
+            escape_html(&sense.gloss)
+        ));
+        if !sense.tags.is_empty() {
+            html.push_str(&format!(
+                " <span style=\"{}\">({})</span>",
+                definition_styling.info,
+                escape_html(&sense.tags.join(", "))
+            ));
+        }
+        if !sense.examples.is_empty() {
+            html.push_str("<ul>");
+            for example in &sense.examples {
+                html.push_str(&format!("<li><i>{}</i>", escape_html(&example.text)));
+                if let Some(translation) = &example.translation {
+                    html.push_str(&format!(" — {}", escape_html(translation)));
+                }
+                html.push_str("</li>");
+            }
+            html.push_str("</ul>");
+        }
+        html.push_str("</li>");
+    }
+    html.push_str("</ol>");
+
+    if !entry.forms.is_empty() {
+        html.push_str(&format!(
+            "<p style=\"{}\">{}</p>",
+            definition_styling.info,
+            escape_html(&entry.forms.join(", "))
+        ));
+    }
+    html
+}
+
+pub fn to_html(
+    definition: &StructuredDefinition,
+    definition_styling: &DefinitionStyling,
+) -> String {
+    let entries: Vec<String> = definition
+        .entries
+        .iter()
+        .map(|entry| entry_to_html(&definition.headword, entry, definition_styling))
+        .collect();
+    entries.join("<hr>")
+}
+
+/// Joins the values without repeating any
+fn join_unique<'a>(values: impl Iterator<Item = &'a String>, separator: &str) -> String {
+    let mut unique: Vec<&str> = Vec::new();
+    for value in values {
+        if !unique.contains(&value.as_str()) {
+            unique.push(value);
+        }
+    }
+    escape_html(&unique.join(separator))
+}
+
+/// Gets a single part of a definition for exporting. Definitions that aren't structured don't
+/// have any parts.
+pub fn export_part(definition: &Definition, part: &str) -> Option<String> {
+    let Definition::Structured(definition) = definition else {
+        return EXPORT_PARTS.contains(&part).then(String::new);
+    };
+    let entries = &definition.entries;
+    Some(match part {
+        "first_sense" => entries
+            .iter()
+            .flat_map(|entry| &entry.senses)
+            .next()
+            .map(|sense| escape_html(&sense.gloss))
+            .unwrap_or_default(),
+        "examples" => entries
+            .iter()
+            .flat_map(|entry| &entry.senses)
+            .flat_map(|sense| &sense.examples)
+            .map(|example| match &example.translation {
+                Some(translation) => format!(
+                    "{} — {}",
+                    escape_html(&example.text),
+                    escape_html(translation)
+                ),
+                None => escape_html(&example.text),
+            })
+            .collect::<Vec<_>>()
+            .join("<br>"),
+        "pos" => join_unique(
+            entries
+                .iter()
+                .filter_map(|entry| entry.part_of_speech.as_ref()),
+            ", ",
+        ),
+        "gender" => join_unique(
+            entries.iter().filter_map(|entry| entry.gender.as_ref()),
+            ", ",
+        ),
+        "ipa" => join_unique(entries.iter().flat_map(|entry| &entry.ipa), ", "),
+        "headword" => escape_html(&definition.headword),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::{Example, Sense};
+
+    use super::*;
+
+    fn sample_definition() -> Definition {
+        Definition::Structured(StructuredDefinition {
+            headword: String::from("geras"),
+            entries: vec![
+                DefinitionEntry {
+                    part_of_speech: Some(String::from("adj")),
+                    ipa: vec![String::from("/ɡʲɛˈrɐs/")],
+                    senses: vec![
+                        Sense {
+                            gloss: String::from("good"),
+                            tags: Vec::new(),
+                            examples: vec![Example {
+                                text: String::from("Geras žmogus."),
+                                translation: Some(String::from("A good person.")),
+                            }],
+                        },
+                        Sense {
+                            gloss: String::from("kind"),
+                            tags: vec![String::from("figuratively")],
+                            examples: Vec::new(),
+                        },
+                    ],
+                    ..Default::default()
+                },
+                DefinitionEntry {
+                    part_of_speech: Some(String::from("noun")),
+                    gender: Some(String::from("m")),
+                    ipa: vec![String::from("/ɡʲɛˈrɐs/")],
+                    senses: vec![Sense {
+                        gloss: String::from("good & kind person"),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                },
+            ],
+        })
+    }
+
+    #[test]
+    fn structured_definition_parts() {
+        let definition = sample_definition();
+        let part = |part| export_part(&definition, part).unwrap();
+        assert_eq!(part("first_sense"), "good");
+        assert_eq!(part("examples"), "Geras žmogus. — A good person.");
+        assert_eq!(part("pos"), "adj, noun");
+        assert_eq!(part("gender"), "m");
+        assert_eq!(part("ipa"), "/ɡʲɛˈrɐs/");
+        assert_eq!(part("headword"), "geras");
+        assert_eq!(export_part(&definition, "not_a_part"), None);
+    }
+
+    #[test]
+    fn text_definitions_have_empty_parts() {
+        let definition = Definition::Text(String::from("good"));
+        assert_eq!(export_part(&definition, "first_sense"), Some(String::new()));
+        assert_eq!(export_part(&definition, "not_a_part"), None);
+    }
+
+    #[test]
+    fn structured_definition_to_html() {
+        let Definition::Structured(definition) = sample_definition() else {
+            unreachable!()
+        };
+        let styling = DefinitionStyling {
+            definition: String::from("d"),
+            info: String::from("i"),
+            main_detail: String::from("m"),
+        };
+        assert_eq!(
+            to_html(&definition, &styling),
+            concat!(
+                "<div><span style=\"m\">geras</span> <span style=\"i\">adj</span> <span>/ɡʲɛˈrɐs/</span></div>",
+                "<ol><li><span style=\"d\">good</span><ul><li><i>Geras žmogus.</i> — A good person.</li></ul></li>",
+                "<li><span style=\"d\">kind</span> <span style=\"i\">(figuratively)</span></li></ol>",
+                "<hr>",
+                "<div><span style=\"m\">geras</span> <span style=\"i\">noun</span> <span style=\"i\">m</span> <span>/ɡʲɛˈrɐs/</span></div>",
+                "<ol><li><span style=\"d\">good &amp; kind person</span></li></ol>",
+            )
+        );
+    }
+}
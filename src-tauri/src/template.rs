@@ -0,0 +1,381 @@
+//! Field templates for exported notes. Variables are written as `{name}`, optionally followed by
+//! filters such as `{def:Wiktionary|strip_html|truncate:100}`. Parts of a template can be shown
+//! only when a variable has text with `{{#name}}...{{/}}`, or only when it is empty with
+//! `{{^name}}...{{/}}`. Anything else in double braces, such as Anki's `{{c1::{word}}}` cloze
+//! deletions, is kept as it is. Braces can be written with `\{` and `\}`.
+
+use std::collections::{BTreeSet, HashMap};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Filter {
+    /// The first line with text
+    FirstLine,
+    StripHtml,
+    /// At most this many characters, followed by an ellipsis if anything was cut off
+    Truncate(usize),
+    /// Turns `漢字[かんじ]` into ruby text, like Anki's furigana filter
+    Furigana,
+}
+
+impl Filter {
+    fn parse(filter: &str) -> Result<Self, String> {
+        let (name, argument) = match filter.split_once(':') {
+            Some((name, argument)) => (name.trim(), Some(argument.trim())),
+            None => (filter.trim(), None),
+        };
+        match (name, argument) {
+            ("first_line", None) => Ok(Filter::FirstLine),
+            ("strip_html", None) => Ok(Filter::StripHtml),
+            ("furigana", None) => Ok(Filter::Furigana),
+            ("truncate", Some(length)) => length
+                .parse()
+                .map(Filter::Truncate)
+                .map_err(|_| format!("truncate needs a number of characters, not `{length}`")),
+            ("truncate", None) => Err(String::from(
+                "truncate needs a number of characters, such as truncate:100",
+            )),
+            _ => Err(format!("unknown filter `{filter}`")),
+        }
+    }
+
+    fn apply(&self, text: String) -> String {
+        match self {
+            Filter::FirstLine => first_line(&text).to_owned(),
+            Filter::StripHtml => strip_html(&text),
+            Filter::Truncate(length) => truncate(text, *length),
+            Filter::Furigana => furigana(&text),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    Variable {
+        name: String,
+        filters: Vec<Filter>,
+    },
+    Section {
+        name: String,
+        /// Whether the section is shown when the variable is empty instead
+        inverted: bool,
+        children: Vec<Node>,
+    },
+}
+
+/// A parsed field template
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template(Vec<Node>);
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self, String> {
+        // The sections that are still open, along with the nodes before them
+        let mut open: Vec<(String, bool, Vec<Node>)> = Vec::new();
+        let mut nodes = Vec::new();
+        let mut text = String::new();
+        let mut rest = template;
+        while let Some(c) = rest.chars().next() {
+            match c {
+                '\\' if rest[1..].starts_with(['{', '}']) => {
+                    text.push_str(&rest[1..2]);
+                    rest = &rest[2..];
+                }
+                '{' if rest.starts_with("{{")
+                    && !rest[2..].trim_start().starts_with(['#', '^', '/']) =>
+                {
+                    text.push_str("{{");
+                    rest = &rest[2..];
+                }
+                '{' if rest.starts_with("{{") => {
+                    let end = rest
+                        .find("}}")
+                        .ok_or_else(|| format!("`{}` is never closed", first_line(rest)))?;
+                    let tag = rest[2..end].trim();
+                    rest = &rest[end + 2..];
+                    if !text.is_empty() {
+                        nodes.push(Node::Text(std::mem::take(&mut text)));
+                    }
+                    if let Some(name) = tag.strip_prefix('/') {
+                        let (opened, inverted, before) = open
+                            .pop()
+                            .ok_or_else(|| format!("`{{{{{tag}}}}}` does not close anything"))?;
+                        let name = name.trim();
+                        if !name.is_empty() && name != opened {
+                            return Err(format!("`{{{{{tag}}}}}` closes `{opened}`"));
+                        }
+                        let children = std::mem::replace(&mut nodes, before);
+                        nodes.push(Node::Section {
+                            name: opened,
+                            inverted,
+                            children,
+                        });
+                    } else {
+                        let (name, inverted) = match tag.strip_prefix('#') {
+                            Some(name) => (name.trim(), false),
+                            None => (tag[1..].trim(), true),
+                        };
+                        if name.is_empty() {
+                            return Err(format!("`{{{{{tag}}}}}` needs a variable"));
+                        }
+                        open.push((name.to_owned(), inverted, std::mem::take(&mut nodes)));
+                    }
+                }
+                '{' => {
+                    let end = rest
+                        .find('}')
+                        .ok_or_else(|| format!("`{}` is never closed", first_line(rest)))?;
+                    let mut parts = rest[1..end].split('|');
+                    let name = parts.next().unwrap_or_default().trim();
+                    if name.is_empty() {
+                        return Err(format!("`{}` needs a variable", &rest[..=end]));
+                    }
+                    let filters = parts.map(Filter::parse).collect::<Result<_, _>>()?;
+                    if !text.is_empty() {
+                        nodes.push(Node::Text(std::mem::take(&mut text)));
+                    }
+                    nodes.push(Node::Variable {
+                        name: name.to_owned(),
+                        filters,
+                    });
+                    rest = &rest[end + 1..];
+                }
+                c => {
+                    text.push(c);
+                    rest = &rest[c.len_utf8()..];
+                }
+            }
+        }
+        if let Some((name, _, _)) = open.last() {
+            return Err(format!("`{name}` needs to be closed with {{{{/}}}}"));
+        }
+        if !text.is_empty() {
+            nodes.push(Node::Text(text));
+        }
+        Ok(Self(nodes))
+    }
+
+    /// Whether the template uses the variable, including in sections
+    pub fn uses(&self, variable: impl Fn(&str) -> bool) -> bool {
+        fn uses(nodes: &[Node], variable: &dyn Fn(&str) -> bool) -> bool {
+            nodes.iter().any(|node| match node {
+                Node::Text(_) => false,
+                Node::Variable { name, .. } => variable(name),
+                Node::Section { name, children, .. } => variable(name) || uses(children, variable),
+            })
+        }
+        uses(&self.0, &variable)
+    }
+
+    /// Fills in the variables, failing with every variable that is unknown
+    pub fn render(&self, variables: &HashMap<String, String>) -> Result<String, String> {
+        let mut rendered = String::new();
+        let mut unknown = BTreeSet::new();
+        render(&self.0, variables, &mut rendered, &mut unknown);
+        if unknown.is_empty() {
+            Ok(rendered)
+        } else {
+            let unknown: Vec<String> = unknown
+                .into_iter()
+                .map(|name| format!("{{{name}}}"))
+                .collect();
+            Err(format!("unknown variables {}", unknown.join(", ")))
+        }
+    }
+}
+
+fn render<'a>(
+    nodes: &'a [Node],
+    variables: &HashMap<String, String>,
+    rendered: &mut String,
+    unknown: &mut BTreeSet<&'a str>,
+) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => rendered.push_str(text),
+            Node::Variable { name, filters } => match variables.get(name) {
+                Some(value) => {
+                    let value = filters
+                        .iter()
+                        .fold(value.clone(), |value, filter| filter.apply(value));
+                    rendered.push_str(&value);
+                }
+                None => {
+                    unknown.insert(name);
+                }
+            },
+            Node::Section {
+                name,
+                inverted,
+                children,
+            } => {
+                let has_text = match variables.get(name) {
+                    Some(value) => !strip_html(value).is_empty(),
+                    None => {
+                        unknown.insert(name);
+                        false
+                    }
+                };
+                if has_text != *inverted {
+                    render(children, variables, rendered, unknown);
+                }
+            }
+        }
+    }
+}
+
+pub fn strip_html(text: &str) -> String {
+    let mut stripped = String::new();
+    let mut in_tag = false;
+    for c in text.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            c if !in_tag => stripped.push(c),
+            _ => (),
+        }
+    }
+    stripped.replace("&nbsp;", " ").trim().to_owned()
+}
+
+/// At most `length` characters, followed by an ellipsis if anything was cut off
+pub fn truncate(text: String, length: usize) -> String {
+    if text.chars().count() <= length {
+        text
+    } else {
+        let mut truncated: String = text.chars().take(length).collect();
+        truncated.push('…');
+        truncated
+    }
+}
+
+/// Lines are split by newlines and `<br>` tags
+pub fn first_line(text: &str) -> &str {
+    text.split('\n')
+        .flat_map(|line| line.split("<br>"))
+        .flat_map(|line| line.split("<br/>"))
+        .flat_map(|line| line.split("<br />"))
+        .find(|line| !strip_html(line).is_empty())
+        .unwrap_or_default()
+        .trim()
+}
+
+/// The text before the brackets, up to a space or the end of a tag, is what the reading is for.
+/// A space before it is removed so that readings can be separated.
+fn furigana(text: &str) -> String {
+    let mut converted = String::new();
+    // How much of the text is already in the converted text
+    let mut copied = 0;
+    // Where the text that the next reading could be for starts
+    let mut base_start = 0;
+    let mut index = 0;
+    while let Some(c) = text[index..].chars().next() {
+        match c {
+            ' ' | '>' => {
+                index += 1;
+                base_start = index;
+            }
+            '[' if index > base_start => {
+                let Some(length) = text[index + 1..].find(']').filter(|length| *length > 0) else {
+                    index += 1;
+                    continue;
+                };
+                let before = &text[copied..base_start];
+                converted.push_str(before.strip_suffix(' ').unwrap_or(before));
+                converted.push_str(&format!(
+                    "<ruby><rb>{}</rb><rt>{}</rt></ruby>",
+                    &text[base_start..index],
+                    &text[index + 1..index + 1 + length]
+                ));
+                index += length + 2;
+                base_start = index;
+                copied = index;
+            }
+            c => index += c.len_utf8(),
+        }
+    }
+    converted.push_str(&text[copied..]);
+    converted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str, variables: &[(&str, &str)]) -> Result<String, String> {
+        let variables = variables
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Template::parse(template)?.render(&variables)
+    }
+
+    #[test]
+    fn sections_are_shown_depending_on_variables() {
+        let variables = [
+            ("word", "labas"),
+            ("def:Wiktionary", "<p>hello</p>"),
+            ("def:Personal", "<p> </p>"),
+        ];
+        assert_eq!(
+            render(
+                "{word}{{#def:Wiktionary}}: {def:Wiktionary}{{/}}{{#def:Personal}} ({def:Personal}){{/def:Personal}}",
+                &variables
+            ),
+            Ok(String::from("labas: <p>hello</p>"))
+        );
+        assert_eq!(
+            render(
+                "{{^def:Personal}}{{#word}}no notes for {word}{{/}}{{/}}",
+                &variables
+            ),
+            Ok(String::from("no notes for labas"))
+        );
+        assert_eq!(
+            render(r"\{word\} {word}", &variables),
+            Ok(String::from("{word} labas"))
+        );
+        assert_eq!(
+            render(
+                "{{c1::{word}}} {{ c2::{word}::{{#word}}hint{{/}} }}",
+                &variables
+            ),
+            Ok(String::from("{{c1::labas}} {{ c2::labas::hint }}"))
+        );
+    }
+
+    #[test]
+    fn filters_are_applied_in_order() {
+        let variables = [
+            ("def", "\n<ol><li>hello</li><br><li>good day</li></ol>"),
+            ("sentence", "今日[きょう]は 良[よ]い 天気[てんき]"),
+        ];
+        assert_eq!(
+            render("{def|first_line|strip_html|truncate:3}", &variables),
+            Ok(String::from("hel…"))
+        );
+        assert_eq!(
+            render("{def | strip_html | truncate: 100}", &variables),
+            Ok(String::from("hellogood day"))
+        );
+        assert_eq!(
+            render("{sentence|furigana}", &variables),
+            Ok(String::from(
+                "<ruby><rb>今日</rb><rt>きょう</rt></ruby>は<ruby><rb>良</rb><rt>よ</rt></ruby>い<ruby><rb>天気</rb><rt>てんき</rt></ruby>"
+            ))
+        );
+    }
+
+    #[test]
+    fn mistakes_are_reported() {
+        assert_eq!(
+            render("{wrod} {{#def:Wiktionray}}{def}{{/}}", &[("def", "")]),
+            Err(String::from("unknown variables {def:Wiktionray}, {wrod}"))
+        );
+        assert!(render("{word", &[]).is_err());
+        assert!(render("{word|upper}", &[]).is_err());
+        assert!(render("{word|truncate:some}", &[]).is_err());
+        assert!(render("{{#word}}", &[]).is_err());
+        assert!(render("{{/}}", &[]).is_err());
+        assert!(render("{{#word}}{{/def}}", &[]).is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use std::time::Duration;
+
+use reqwest::Client;
+use serde_json::{json, Value};
+use shared::{Definition, TranslationBackend, TranslationSettings};
+use tauri::State;
+
+use crate::{commands::run_with_word, KalbaError, KalbaState};
+
+/// Translations are kept in the definition cache as if they came from a dictionary with this name
+pub const TRANSLATION_CACHE: &str = "Sentence translations";
+
+fn response_field(response: &str, field: &str, backend: &str) -> Result<String, KalbaError> {
+    let response: Value = serde_json::from_str(response)
+        .map_err(|e| KalbaError::InvalidResponse(backend.to_owned(), e.to_string()))?;
+    response
+        .get(field)
+        .and_then(Value::as_str)
+        .map(|translation| translation.trim().to_owned())
+        .ok_or_else(|| {
+            KalbaError::InvalidResponse(backend.to_owned(), format!("missing `{field}`"))
+        })
+}
+
+fn endpoint(url: &str, path: &str) -> String {
+    format!("{}/{path}", url.trim_end_matches('/'))
+}
+
+/// Translates the sentence with the backend, without using the cache
+async fn request_translation(
+    client: &Client,
+    backend: &TranslationBackend,
+    sentence: &str,
+    timeout: Duration,
+) -> Result<String, KalbaError> {
+    match backend {
+        TranslationBackend::LibreTranslate {
+            url,
+            source,
+            target,
+            api_key,
+        } => {
+            let mut body = json!({
+                "q": sentence,
+                "source": source,
+                "target": target,
+                "format": "text",
+            });
+            if !api_key.is_empty() {
+                body["api_key"] = Value::String(api_key.clone());
+            }
+            let response = client
+                .post(endpoint(url, "translate"))
+                .json(&body)
+                .timeout(timeout)
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            response_field(&response, "translatedText", "LibreTranslate")
+        }
+        TranslationBackend::Ollama { url, model, prompt } => {
+            let response = client
+                .post(endpoint(url, "api/generate"))
+                .json(&json!({
+                    "model": model,
+                    "prompt": prompt.replace("{sentence}", sentence),
+                    "stream": false,
+                }))
+                .timeout(timeout)
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            response_field(&response, "response", "Ollama")
+        }
+        TranslationBackend::Command { args, stdin } => {
+            let args: Vec<String> = args
+                .iter()
+                .map(|arg| arg.replace("{sentence}", "{word}"))
+                .collect();
+            Ok(run_with_word(&args, sentence, *stdin, timeout)
+                .await?
+                .trim()
+                .to_owned())
+        }
+    }
+}
+
+/// Translates the sentence with the backend of the current language, using the cached translation
+/// if there is one. The state is only locked to read the settings and the cache, not while waiting
+/// for the backend. Returns `None` if the language has no backend.
+pub async fn translate(state: &KalbaState, sentence: &str) -> Result<Option<String>, KalbaError> {
+    let (language, settings, client) = {
+        let state = state.0.lock().await;
+        let language = state
+            .current_language
+            .clone()
+            .expect("current language should already be selected");
+        if let Some(Definition::Text(translation)) =
+            state
+                .definition_cache
+                .get(&language, TRANSLATION_CACHE, sentence)
+        {
+            return Ok(Some(translation.clone()));
+        }
+        let settings: TranslationSettings = state
+            .settings
+            .languages
+            .get(&language)
+            .expect("language should exist")
+            .translation
+            .clone();
+        let client = state.dict_info.lock().await.client();
+        (language, settings, client)
+    };
+    let Some(backend) = settings.backend else {
+        return Ok(None);
+    };
+    let translation = request_translation(
+        &client,
+        &backend,
+        sentence,
+        Duration::from_secs(settings.timeout),
+    )
+    .await?;
+    state.0.lock().await.definition_cache.insert(
+        &language,
+        TRANSLATION_CACHE,
+        sentence,
+        Definition::Text(translation.clone()),
+    );
+    Ok(Some(translation))
+}
+
+/// Translates a sentence of the current language, using the cached translation if there is one
+#[tauri::command]
+pub async fn translate_sentence(
+    state: State<'_, KalbaState>,
+    sentence: String,
+) -> Result<String, KalbaError> {
+    translate(&state, sentence.trim())
+        .await?
+        .ok_or(KalbaError::NoTranslationBackend)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::serve_once;
+
+    fn translate(backend: &TranslationBackend) -> Result<String, KalbaError> {
+        tauri::async_runtime::block_on(request_translation(
+            &Client::new(),
+            backend,
+            "Labas rytas.",
+            Duration::from_secs(5),
+        ))
+    }
+
+    #[test]
+    fn libretranslate() {
+        let (url, request) =
+            serve_once("application/json", r#"{"translatedText": "Good morning."}"#);
+        let backend = TranslationBackend::LibreTranslate {
+            url: format!("{url}/"),
+            source: String::from("lt"),
+            target: String::from("en"),
+            api_key: String::new(),
+        };
+        assert_eq!(translate(&backend).unwrap(), "Good morning.");
+        let request = request.join().unwrap();
+        assert!(request.starts_with("POST /translate "));
+        let (_, body) = request.split_once("\r\n\r\n").unwrap();
+        let body: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["q"], "Labas rytas.");
+        assert_eq!(body["target"], "en");
+        assert!(body.get("api_key").is_none());
+    }
+
+    #[test]
+    fn ollama() {
+        let (url, request) = serve_once(
+            "application/json",
+            r#"{"model": "llama3", "response": " Good morning.\n", "done": true}"#,
+        );
+        let backend = TranslationBackend::Ollama {
+            url,
+            model: String::from("llama3"),
+            prompt: String::from("Translate to English: {sentence}"),
+        };
+        assert_eq!(translate(&backend).unwrap(), "Good morning.");
+        let request = request.join().unwrap();
+        assert!(request.starts_with("POST /api/generate "));
+        let (_, body) = request.split_once("\r\n\r\n").unwrap();
+        let body: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["prompt"], "Translate to English: Labas rytas.");
+        assert_eq!(body["stream"], false);
+    }
+
+    #[test]
+    fn invalid_response() {
+        let (url, _) = serve_once("application/json", r#"{"error": "unknown language"}"#);
+        let backend = TranslationBackend::LibreTranslate {
+            url,
+            source: String::from("xx"),
+            target: String::from("en"),
+            api_key: String::new(),
+        };
+        assert!(matches!(
+            translate(&backend),
+            Err(KalbaError::InvalidResponse(..))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command() {
+        let backend = TranslationBackend::Command {
+            args: vec![String::from("echo"), String::from("[{sentence}]")],
+            stdin: false,
+        };
+        assert_eq!(translate(&backend).unwrap(), "[Labas rytas.]");
+    }
+}
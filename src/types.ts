@@ -7,9 +7,43 @@ export interface StartupState {
   can_save: boolean;
 }
 
-export interface Definition {
-  t: "Empty" | "Text" | "OnDemand";
-  c: undefined | string;
+export type Definition =
+  | { t: "Empty"; c: undefined }
+  | { t: "Text" | "OnDemand" | "Error"; c: string }
+  | { t: "Structured"; c: StructuredDefinition };
+
+export interface CandidateDefinitions {
+  lemma: string;
+  definitions: { [key: string]: Definition };
+}
+
+export interface StructuredDefinition {
+  headword: string;
+  entries: DefinitionEntry[];
+}
+
+export interface DefinitionEntry {
+  part_of_speech?: string;
+  gender?: string;
+  ipa: string[];
+  senses: Sense[];
+  forms: string[];
+}
+
+export interface Sense {
+  gloss: string;
+  tags: string[];
+  examples: Example[];
+}
+
+export interface Example {
+  text: string;
+  translation?: string;
+}
+
+export interface PersonalEntry {
+  gloss: string;
+  notes: string;
 }
 
 export interface DefinitionStyling {
@@ -31,6 +65,21 @@ export interface Settings {
   stanza_enabled: boolean;
   anki_port: number;
   anki_enabled: boolean;
+  offline_mode: boolean;
+  show_all_candidates: boolean;
+  definition_cache: CacheSettings;
+}
+
+export interface CacheSettings {
+  max_entries: number;
+  max_age_days: number;
+}
+
+export interface DictionaryCacheInfo {
+  language: string;
+  dictionary: string;
+  entries: number;
+  oldest?: string;
 }
 
 export interface SiteConfiguration {
@@ -51,13 +100,103 @@ export interface LanguageSettings {
   grammar_parser: string;
   run_on_lemmas: string[];
   suggest_on_lemmas: string[];
+  examples: ExampleSettings;
+  pronunciation: PronunciationSettings;
+  translation: TranslationSettings;
+  anki_ratings: AnkiRatingRules;
+  apkg: ApkgSettings;
+}
+
+export interface ApkgSettings {
+  deck: string;
+  note_type: string;
+  fields: string[];
+  front: string;
+  back: string;
+  css: string;
+}
+
+export interface RenderedNote {
+  word: string;
+  deck: string;
+  model: string;
+  fields: { [key: string]: string };
+  created: string;
 }
 
+export interface QueuedNote {
+  note: RenderedNote;
+  error?: string;
+}
+
+export interface AnkiRatingRules {
+  rules: AnkiRatingRule[];
+  otherwise: number;
+  combine: "Highest" | "Lowest";
+  cards: number[];
+}
+
+export interface AnkiRatingRule {
+  conditions: CardCondition[];
+  rating: number;
+}
+
+export type CardProperty =
+  | "Interval"
+  | "Ease"
+  | "Lapses"
+  | "Reviews"
+  | "CardNumber";
+
+export type CardState = "New" | "Learning" | "Review" | "Suspended" | "Buried";
+
+export type CardCondition =
+  | { t: "AtMost"; c: [CardProperty, number] }
+  | { t: "AtLeast"; c: [CardProperty, number] }
+  | { t: "State"; c: CardState[] }
+  | { t: "Not"; c: CardCondition };
+
+export interface ExampleSettings {
+  count: number;
+  order: "MostComprehensible" | "Shortest";
+}
+
+export interface PronunciationSettings {
+  recordings: string;
+  tts: string[];
+}
+
+export interface TranslationSettings {
+  backend?: TranslationBackend;
+  timeout: number;
+}
+
+export type TranslationBackend =
+  | {
+      t: "LibreTranslate";
+      c: { url: string; source: string; target: string; api_key: string };
+    }
+  | { t: "Ollama"; c: { url: string; model: string; prompt: string } }
+  | { t: "Command"; c: { args: string[]; stdin: boolean } };
+
+export type ExampleSource =
+  | { t: "Pairs"; c: string }
+  | {
+      t: "Tatoeba";
+      c: {
+        sentences: string;
+        links: string;
+        language: string;
+        translation_language: string;
+      };
+    };
+
 export interface Word {
   display_text: string;
   text: string;
   lemma: string;
   morph: { [key: string]: string };
+  pos?: string;
   clickable: boolean;
   rating: number;
   other_forms: string[];
@@ -72,7 +211,7 @@ export interface ParsedWords {
 }
 
 export interface FileType {
-  t: "TextSplitAt" | "StarDict";
+  t: "TextSplitAt" | "StarDict" | "MDict";
   c: string | null;
 }
 
@@ -89,6 +228,7 @@ export enum DictionaryType {
   EkalbaBendrines = "EkalbaBendrines",
   EkalbaDabartines = "EkalbaDabartines",
   WordReference = "WordReference",
+  Kaikki = "Kaikki",
 }
 
 export interface Dictionary {
@@ -96,18 +236,50 @@ export interface Dictionary {
   fetch_by_default: boolean;
   specific_settings: DictionarySpecificSettings;
   run_when_not?: string;
+  timeout: number;
+  retries: number;
+  rules: DictionaryRule[];
 }
 
+export type DictionaryRule =
+  | { t: "PartOfSpeech"; c: string[] }
+  | { t: "Feature"; c: [string, string] }
+  | { t: "Capitalized"; c?: undefined }
+  | { t: "AllFailed"; c: string[] }
+  | { t: "Not"; c: DictionaryRule };
+
 export interface DictionarySpecificSettings {
   t: DictionaryType;
   c:
     | [string, FileType]
     | [string, string]
-    | [string, boolean, string]
+    | UrlSettings
+    | CommandSettings
     | string
     | undefined;
 }
 
+export interface UrlSettings {
+  url: string;
+  embed: boolean;
+  method: "Get" | "Post";
+  headers: [string, string][];
+  body: string;
+  extraction: ResponseExtraction;
+  template: string;
+}
+
+export interface ResponseExtraction {
+  t: "Whole" | "Css" | "JsonPath";
+  c?: string;
+}
+
+export interface CommandSettings {
+  args: string[];
+  stdin: boolean;
+  output: "Html" | "Json";
+}
+
 export interface Deck {
   name: string;
   notes: Note[];